@@ -2,7 +2,11 @@ use std::num;
 
 use serde::{Serialize, Deserialize};
 use chrono::prelude::*;
-use openssl::{sha::sha256, base64};
+use openssl::base64;
+use libp2p::{identity, PeerId};
+
+use crate::blockchain::{encoding, merkle, difficulty::ChainParams, hash::BlockHash, target::Target};
+use crate::blockchain::pow_algorithm::PowAlgorithm;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Record {
@@ -12,30 +16,96 @@ pub struct Record {
     pub timestamp: u64,
     // Content of the record
     pub data: String,
+    // Peer id derived from public_key
     pub author_peer_id: String,
+    // Base64 encoded protobuf encoding of the author's public key. Records of version 0
+    // blocks were not signed, so they have neither the key nor the signature.
+    #[serde(default)]
+    pub public_key: String,
+    // Base64 encoded signature of the signing payload made with the author's key
+    #[serde(default)]
+    pub signature: String,
 }
 
 impl Record {
-    pub fn new(data: String, author_peer_id: String) -> Record {
-        Record {
+    // Creates a record signed with the given key, which can be the node's identity or any
+    // other key of the user
+    pub fn new(data: String, author_key: &identity::Keypair) -> Record {
+        let public_key = author_key.public();
+        let mut record = Record {
             idx: (0, 0),
             timestamp: Utc::now().timestamp() as u64,
             data,
-            author_peer_id,
+            author_peer_id: PeerId::from(public_key.clone()).to_string(),
+            public_key: base64::encode_block(&public_key.encode_protobuf()),
+            signature: String::new(),
+        };
+        let signature = author_key.sign(&record.signing_payload())
+            .expect("can sign the record");
+        record.signature = base64::encode_block(&signature);
+
+        record
+    }
+
+    // Canonical content of the record covered by the signature. The index is not a part of
+    // it since it is assigned by the miner who puts the record in a block.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&self.timestamp.to_be_bytes());
+        for field in [&self.data, &self.author_peer_id, &self.public_key] {
+            payload.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            payload.extend_from_slice(field.as_bytes());
+        }
+
+        payload
+    }
+
+    // Checks that the signature was made by the key stored in the record and that the key
+    // belongs to the declared author
+    pub fn has_valid_signature(&self) -> bool {
+        let public_key = match base64::decode_block(&self.public_key)
+            .ok()
+            .and_then(|bytes| identity::PublicKey::try_decode_protobuf(&bytes).ok())
+        {
+            Some(public_key) => public_key,
+            None => return false,
+        };
+        if PeerId::from(public_key.clone()).to_string() != self.author_peer_id {
+            return false;
+        }
+
+        if let Ok(signature) = base64::decode_block(&self.signature) {
+            public_key.verify(&self.signing_payload(), &signature)
+        } else {
+            false
         }
     }
 }
 
+/*
+    Version of the schema blocks are serialized with:
+        0: blocks without the version field and num_sidelinks, with unsigned records and base64
+           hashes,
+        1: blocks hashed over the canonical encoding (see encoding).
+    Blocks of version 0 can still be read (e.g. to look at an old chain file), but they are
+    never valid: they have neither genesis parameters nor signed records. Blocks of a newer
+    version are rejected.
+ */
+pub const BLOCK_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "AnyVersionBlock")]
 pub struct Block {
+    // Schema version the block was read with, BLOCK_VERSION for new blocks
+    pub version: u32,
     pub idx: u64,
-    pub previous_block_hash: String,
+    pub previous_block_hash: BlockHash,
     // List of n hashes of previous blocks chosen at random.
     // The number of hashes is defined by the network.
     // If the idx of the block is less than the number of hashes defined by the network,
     // the block contains all the hashes of the previous blocks and the rest of the
     // hashes are empty.
-    pub validation_sidelinks: Vec<String>,
+    pub validation_sidelinks: Vec<BlockHash>,
     pub num_sidelinks: usize,
     // Proof of Work
     pub pow: String,
@@ -43,36 +113,146 @@ pub struct Block {
     pub timestamp: u64,
     // List of data records added to the block
     pub records: Vec<Record>,
-    // Abstract difficulty value of mining a block. Proof of work is used to find a nonce
-    // such that the hash of (data||nonce) is less than 2^hash_output_length/difficulty.
-    pub difficulty: Vec<u8>,
+    // Target of the proof of work. Proof of work is used to find a nonce such that the hash
+    // of (data||nonce) is less than the target.
+    pub difficulty: Target,
+    // Consensus parameters of the chain, present only in the genesis block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genesis_params: Option<ChainParams>,
+    // Peer id of the node which mined the block. It is declared by the miner itself and only
+    // used for statistics, but it is covered by the proof of work, so nobody else can change it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub miner_peer_id: Option<String>,
+}
+
+// Block as serialized by any schema version
+#[derive(Deserialize)]
+struct AnyVersionBlock {
+    #[serde(default)]
+    version: Option<u32>,
+    idx: u64,
+    previous_block_hash: BlockHash,
+    validation_sidelinks: Vec<BlockHash>,
+    #[serde(default)]
+    num_sidelinks: Option<usize>,
+    pow: String,
+    timestamp: u64,
+    records: Vec<Record>,
+    difficulty: Target,
+    #[serde(default)]
+    genesis_params: Option<ChainParams>,
+    #[serde(default)]
+    miner_peer_id: Option<String>,
+}
+
+impl TryFrom<AnyVersionBlock> for Block {
+    type Error = String;
+
+    fn try_from(block: AnyVersionBlock) -> Result<Block, String> {
+        let version = block.version.unwrap_or(0);
+        if version > BLOCK_VERSION {
+            return Err(format!("block {} has version {}, only versions up to {} are supported",
+                block.idx, version, BLOCK_VERSION));
+        }
+        if version >= 1 && block.num_sidelinks.is_none() {
+            return Err(format!("block {} of version {} has no num_sidelinks", block.idx, version));
+        }
+        // Sidelinks were not counted separately before version 1
+        let num_sidelinks = block.num_sidelinks.unwrap_or(block.validation_sidelinks.len());
+
+        Ok(Block {
+            version,
+            idx: block.idx,
+            previous_block_hash: block.previous_block_hash,
+            validation_sidelinks: block.validation_sidelinks,
+            num_sidelinks,
+            pow: block.pow,
+            timestamp: block.timestamp,
+            records: block.records,
+            difficulty: block.difficulty,
+            genesis_params: block.genesis_params,
+            miner_peer_id: block.miner_peer_id,
+        })
+    }
+}
+
+// Part of the block covered by the proof of work. Records are committed to through the
+// Merkle root, so a header is enough to prove that a record belongs to a mined block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub idx: u64,
+    pub previous_block_hash: BlockHash,
+    pub validation_sidelinks: Vec<BlockHash>,
+    pub num_sidelinks: usize,
+    // Merkle root over the records of the block
+    pub merkle_root: BlockHash,
+    pub timestamp: u64,
+    pub difficulty: Target,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genesis_params: Option<ChainParams>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub miner_peer_id: Option<String>,
+    pub pow: String,
+}
+
+impl BlockHeader {
+    // Encoded header without the nonce (see encoding for the format). The token is
+    // H(prefix||nonce), so the prefix stays the same for the whole nonce search of a block
+    // template.
+    pub fn pow_prefix(&self) -> Vec<u8> {
+        let mut prefix = Vec::new();
+        self.encode_without_pow(&mut prefix);
+        prefix
+    }
+
+    // Returns the hash of the encoded header made with the hash function of the chain, which
+    // is also the hash of the whole block, since the header commits to the records through
+    // the Merkle root
+    pub fn hash(&self, algorithm: &dyn PowAlgorithm) -> BlockHash {
+        BlockHash::from_bytes(algorithm.hash(&encoding::to_bytes(self)))
+    }
 }
 
 // Genesis block
 impl Block {
-    pub fn genesis() -> Block {
+    pub fn genesis(params: ChainParams) -> Block {
         Block {
+            version: BLOCK_VERSION,
             idx: 1,
-            previous_block_hash: "0".repeat(32),
+            previous_block_hash: BlockHash::zero(),
             num_sidelinks: 0,
             validation_sidelinks: Vec::new(),
             pow: "".to_string(),
             timestamp: 0,
             records: Vec::new(),
-            difficulty: vec![0; 32],
+            difficulty: Target::zero(),
+            genesis_params: Some(params),
+            miner_peer_id: None,
+        }
+    }
+
+    // Genesis block can differ between chains only by the chain parameters it carries (and
+    // the schema version it was stored with), which have to be within their bounds
+    pub fn is_genesis(&self) -> bool {
+        match &self.genesis_params {
+            Some(params) => params.check_bounds().is_ok()
+                && *self == Block { version: self.version, ..Block::genesis(params.clone()) },
+            None => false,
         }
     }
 
     pub fn new(idx: u64,
-        previous_block_hash: String,
+        previous_block_hash: BlockHash,
         num_sidelinks: usize,
-        validation_sidelinks: Vec<String>,
+        validation_sidelinks: Vec<BlockHash>,
         pow: String,
         records: Vec<Record>,
-        difficulty: Vec<u8>,
+        difficulty: Target,
     ) -> Block
     {
         Block {
+            version: BLOCK_VERSION,
             idx,
             previous_block_hash,
             num_sidelinks,
@@ -81,33 +261,46 @@ impl Block {
             timestamp: Utc::now().timestamp() as u64,
             records,
             difficulty,
+            genesis_params: None,
+            miner_peer_id: None,
         }
     }
 
-    // Returns the base64 encoded SHA-256 hash of the block
-    pub fn hash(&self) -> String {
-        let data = serde_json::json!(self);
-        let hash_bytes = sha256(&data.to_string().as_bytes());
-        base64::encode_block(hash_bytes.as_ref())
+    // Header of the block with the Merkle root computed from the records currently stored
+    // in the block. Every hash of a chain is made with the hash function of its proof of
+    // work (see ChainParams::pow_algorithm).
+    pub fn header(&self, algorithm: &dyn PowAlgorithm) -> BlockHeader {
+        BlockHeader {
+            version: self.version,
+            idx: self.idx,
+            previous_block_hash: self.previous_block_hash,
+            validation_sidelinks: self.validation_sidelinks.clone(),
+            num_sidelinks: self.num_sidelinks,
+            merkle_root: BlockHash::from_bytes(merkle::merkle_root(&self.records, algorithm)),
+            timestamp: self.timestamp,
+            difficulty: self.difficulty.clone(),
+            genesis_params: self.genesis_params.clone(),
+            miner_peer_id: self.miner_peer_id.clone(),
+            pow: self.pow.clone(),
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn add_record_by_data(&mut self, data: String, author_peer_id: String) {
-        let last_record = self.records.last();
-        let idx = if let Some(last_record) = last_record {
-            (self.idx, last_record.idx.1 + 1)
-        } else {
-            (self.idx, 1)
-        };
+    // Returns the hash of the block. Only the header is hashed, so that a chain of headers
+    // can be verified without the records of the blocks.
+    pub fn hash(&self, algorithm: &dyn PowAlgorithm) -> BlockHash {
+        self.header(algorithm).hash(algorithm)
+    }
 
-        let new_rec = Record {
-            idx,
-            timestamp: Utc::now().timestamp() as u64,
-            data,
-            author_peer_id,
-        };
+    // Inclusion proof of the record with the given number within the block (the second
+    // element of Record::idx)
+    pub fn merkle_proof(&self, record_idx: u64, algorithm: &dyn PowAlgorithm) -> Option<merkle::MerkleProof> {
+        let position = self.records.iter().position(|record| record.idx.1 == record_idx)?;
+        merkle::merkle_proof(&self.records, position, algorithm)
+    }
 
-        self.records.push(new_rec);
+    #[allow(dead_code)]
+    pub fn add_record_by_data(&mut self, data: String, author_key: &identity::Keypair) {
+        self.add_record(Record::new(data, author_key));
     }
 
     pub fn add_record(&mut self, mut record: Record) {
@@ -121,22 +314,28 @@ impl Block {
         self.records.push(record);
     }
 
-    pub fn add_sidelink(&mut self, hash: String) {
+    // Whether the block has a record with the same content (Record::signing_payload), at any
+    // place. Records are renumbered whenever they move to another block.
+    pub fn includes_record(&self, record: &Record) -> bool {
+        let content = record.signing_payload();
+        self.records.iter().any(|included| included.signing_payload() == content)
+    }
+
+    pub fn add_sidelink(&mut self, hash: BlockHash) {
         self.validation_sidelinks.push(hash);
     }
 
     #[allow(dead_code)]
     // This function is generally wrong but stays in code as a concept to get fixed some day
-    fn derive_sidelink_indices_bad(&self) -> Vec<usize> {
+    fn derive_sidelink_indices_bad(&self, algorithm: &dyn PowAlgorithm) -> Vec<usize> {
         let mut indices = Vec::new();
         let num_sidelinks = self.num_sidelinks;
         // Derive num_sidlink indices from the previous block hash, this is deterministic
         // and will always return the same set of unique indices for the same block hash.
-        let hash = self.previous_block_hash.clone();
         if num_sidelinks < (self.idx - 1) as usize {
             for i in 0..num_sidelinks {
                 // Concatenate the previous block hash with the index of the sidelink
-                let hash_bytes = sha256(&format!("{}{}", hash, i).as_bytes());
+                let hash_bytes = algorithm.hash(&self.sidelink_seed(&[i as u64]));
                 // If there is a collision (i.e. we
                 // derive an index which is already present in the block) and, for example,
                 // sidelink a is equal to sidelink b, where a was calculated earlier than b,
@@ -161,7 +360,7 @@ impl Block {
         indices
     }
 
-    pub fn derive_sidelink_indices(&self) -> Vec<u64> {
+    pub fn derive_sidelink_indices(&self, algorithm: &dyn PowAlgorithm) -> Vec<u64> {
         let num_sidelinks = self.num_sidelinks;
         let last_possible_sl_idx = self.idx - 2;
         // println!("num_sidelinks: {}", num_sidelinks);
@@ -169,7 +368,6 @@ impl Block {
 
         // println!("candidates: {:?}", candidates);
         if num_sidelinks < (self.idx - 1) as usize {
-            let hash = self.previous_block_hash.clone();
 
             // Perform deterministic swaps based on the previous block hash
             // The number of swaps is arbitrary
@@ -178,8 +376,8 @@ impl Block {
             let number_of_swaps = num_sidelinks * 2;
 
             for i in 0..number_of_swaps {
-                let hash_bytes1 = sha256(&format!("{}{}", hash, i).as_bytes());
-                let hash_bytes2 = sha256(&format!("{}{}{}", hash, i, i).as_bytes());
+                let hash_bytes1 = algorithm.hash(&self.sidelink_seed(&[i as u64]));
+                let hash_bytes2 = algorithm.hash(&self.sidelink_seed(&[i as u64, i as u64]));
 
                 let idx1 = u64::from_be_bytes(hash_bytes1[24..].try_into().unwrap()) % (last_possible_sl_idx as u64) as u64;
                 let idx2 = u64::from_be_bytes(hash_bytes2[24..].try_into().unwrap()) % (last_possible_sl_idx as u64) as u64;
@@ -195,6 +393,16 @@ impl Block {
         }
     }
 
+    // Previous block hash followed by the given numbers, the sidelinks are derived from its
+    // hashes (see encoding)
+    fn sidelink_seed(&self, numbers: &[u64]) -> Vec<u8> {
+        let mut seed = self.previous_block_hash.as_bytes().to_vec();
+        for number in numbers {
+            seed.extend_from_slice(&number.to_be_bytes());
+        }
+        seed
+    }
+
 }
 
 
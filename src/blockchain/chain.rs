@@ -1,18 +1,25 @@
-use crate::blockchain::block::Block;
+use crate::blockchain::block::{Block, Record, BLOCK_VERSION};
 use crate::blockchain::pow;
-use openssl::base64;
+use crate::blockchain::difficulty::{self, ChainParams};
+use crate::blockchain::hash::BlockHash;
+use crate::blockchain::merkle::MerkleProof;
+use crate::blockchain::metadata::{ChainMetadata, DEFAULT_NETWORK_ID};
+use crate::blockchain::receipt::RecordReceipt;
+use crate::blockchain::store::{BlockReader, BlockStore, FileBlockStore};
+use crate::blockchain::target::Target;
+use rug::Integer;
 use rand::Rng;
 use serde::{Serialize, Deserialize};
-use std::fs::{File, OpenOptions};
+use std::collections::HashSet;
 use std::hash::Hash;
-use std::io::{self, Write, BufRead};
-use log::{info, warn, error};
-use std::collections::HashMap;
 
-pub static mut DIFFICULTY_VALUE: Vec<u8> = Vec::new();
 pub static mut NUM_SIDELINKS: usize = 5;
 pub const DEFAULT_DIFFICULTY_IN_SECONDS: f64 = 30.0;
 pub const DEFAULT_NUM_OF_SIDELINKS: usize = 5;
+pub const DEFAULT_RETARGET_INTERVAL: u64 = 10;
+// How far into the future (in seconds) the timestamp of a block can be with respect to the
+// local clock. Timestamps drive the difficulty adjustment so they cannot be arbitrary.
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Chain {
@@ -36,6 +43,9 @@ pub enum ChainType {
 pub struct ChainChoice {
     pub chosen_chain_type: ChainType,
     pub chosen_chain: Option<Chain>,
+    // Cumulative work of the remote chain minus cumulative work of the local chain (an
+    // invalid or missing chain has no work)
+    pub work_difference: Integer,
 }
 
 #[derive(Debug, PartialEq)]
@@ -45,28 +55,34 @@ pub enum ChainValidationResult {
     ChainOk,
 }
 
-#[derive(Debug)]
-enum BlockValidationSource {
-    File,
-    Chain,
-}
-
-// Mechanism for choosing the longest chain
-pub fn find_longest_chain(local_chain: &Chain, remote_chain: &Chain) -> ChainChoice {
+// Mechanism for choosing the chain with the most work put into it. The number of blocks is
+// not enough once the difficulty changes, since many easy blocks can be mined with less work
+// than a few hard ones.
+pub fn find_heaviest_chain(local_chain: &Chain, remote_chain: &Chain) -> ChainChoice {
     let local_chain_validation = local_chain.validate_chain();
     let remote_chain_validation = remote_chain.validate_chain();
+    let local_work = if local_chain_validation {
+        local_chain.cumulative_work()
+    } else {
+        Integer::new()
+    };
+    let remote_work = if remote_chain_validation {
+        remote_chain.cumulative_work()
+    } else {
+        Integer::new()
+    };
+    let work_difference = Integer::from(&remote_work - &local_work);
+
     let winner_chain_type = if local_chain_validation && remote_chain_validation {
-        if local_chain.blocks.len() > remote_chain.blocks.len() {
+        if local_work > remote_work {
             ChainType::Local
-        } else if local_chain.blocks.len() < remote_chain.blocks.len() {
+        } else if local_work < remote_work {
             ChainType::Remote
         } else {
             // Return the chain with the lowest hash value of the last block if chains have
-            // equal length
-            let local_last_block_hash = local_chain.blocks.last().unwrap().hash();
-            let local_last_block_hash = base64::decode_block(&local_last_block_hash).unwrap();
-            let remote_last_block_hash = remote_chain.blocks.last().unwrap().hash();
-            let remote_last_block_hash = base64::decode_block(&remote_last_block_hash).unwrap();
+            // equal work
+            let local_last_block_hash = local_chain.blocks.last().unwrap().hash(local_chain.pow_algorithm());
+            let remote_last_block_hash = remote_chain.blocks.last().unwrap().hash(remote_chain.pow_algorithm());
             
             if local_last_block_hash < remote_last_block_hash {
                 ChainType::Local
@@ -89,7 +105,8 @@ pub fn find_longest_chain(local_chain: &Chain, remote_chain: &Chain) -> ChainCho
         ChainType::NoChain
     };
 
-    println!("Choosing the longest chain...");
+    println!("Choosing the heaviest chain... Local chain work: {}, remote chain work: {}, \
+        difference: {}", local_work, remote_work, work_difference);
     let winner_chain = match winner_chain_type {
         ChainType::Local => {
             println!("Choosing the local chain.");
@@ -111,7 +128,8 @@ pub fn find_longest_chain(local_chain: &Chain, remote_chain: &Chain) -> ChainCho
 
     return ChainChoice {
         chosen_chain_type: winner_chain_type,
-        chosen_chain: winner_chain.cloned()
+        chosen_chain: winner_chain.cloned(),
+        work_difference,
     };
 }
 
@@ -123,319 +141,114 @@ impl Chain {
         }
     }
 
+    #[allow(dead_code)]
+    // Chain of a block file in any format, e.g. one written by another node
     pub fn load_from_file(file_name: &str) -> Result<Chain, Box<dyn std::error::Error>> {
-        let file = std::fs::File::open(file_name)?;
-        let reader = std::io::BufReader::new(file);
-        let mut blocks = Vec::new();
-        for line in reader.lines() {
-            let block = serde_json::from_str(&line?)?;
-            blocks.push(block);
-        }
+        let format = FileBlockStore::detect_format(file_name)?.unwrap_or_default();
+        let store = FileBlockStore::with_format(file_name, format);
         Ok(Chain {
-            blocks,
-            // TODO: determine how to store difficulty and num_side_links in the file
-            num_sidelinks: unsafe { NUM_SIDELINKS },
+            blocks: store.read_all()?,
+            num_sidelinks: store.num_sidelinks(),
         })
     }
 
-    pub fn save_blockchain_to_file(&self, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let mut file = File::create(file_name)?;
-        // Each block should be serialized on a separate line
-        let mut blockchian_string = Vec::new();
-        for block in &self.blocks {
-            let block_string = serde_json::to_string(block)?;
-            // Push a string with a newline character
-            blockchian_string.push(format!("{}\n", block_string));
-        }
-        file.write_all(blockchian_string.join("").as_bytes())?;
-
-        Ok(())
-        
-        // Alternative way of serializing the blockchain - everything in one line
-        // let mut serializer = serde_json::Serializer::new(&file);
-        // let mut seq = serializer
-        //     .serialize_seq(Some(self.blocks.len())).expect("can serialize sequence");
-        // for block in &self.blocks {
-        //     seq.serialize_element(block).expect("can serialize element");
-        // }
-        // seq.end().expect("can end serialization");
-        // file.flush().expect("can flush writer");
-    }
-
-    pub fn get_blockchain_length(file_name: &str) -> Result<usize, Box<dyn std::error::Error>> {
-        // TODO: we assume that the file is not corrupted and that, for simplicity, every
-        // block is on separate line. So to get ith block we simply read the ith line.
-        let file_res = File::open(file_name);
-        if let Ok(file) = file_res {
-            let length_reader = io::BufReader::new(file);
-            Ok(length_reader.lines().count())
-        } else {
-            warn!("Error while opening the file: {}", file_res.err().unwrap());
-            Err("Error while opening the file".into())
+    // Whole chain of the store, None if there is no chain (or it cannot be read). Use a
+    // snapshot (storage::ChainReader::snapshot) of a running node's chain, so that the blocks
+    // are not changed in the middle of loading them.
+    pub fn load_from_store(store: &dyn BlockReader) -> Option<Chain> {
+        let len = store.len();
+        if len == 0 {
+            return None;
         }
+        Some(Chain {
+            blocks: store.range(1, len)?,
+            num_sidelinks: store.num_sidelinks(),
+        })
     }
 
-    pub fn append_block_to_file(block: &Block,
-        file_name: &str,
-    ) -> Result<(), Box<dyn std::error::Error>>
-    {
-        let mut file = if let Ok(file) = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(file_name)
-        {
-            file
-        } else {
-            return Err("Error while opening the file to append the block".into());
-        };
-
-        let block_string = serde_json::to_string(block)?;
-        file.write_all(format!("{}\n", block_string).as_bytes())?;
-
-        Ok(())
-    }
-    
-    pub fn init_first_block(&mut self) {
-        self.blocks.push(Block::genesis());
+    // Sidelinks of the block with the given index in a chain requiring num_sidelinks of them:
+    // blocks at the start of the chain link all previous blocks but the genesis block
+    pub fn num_sidelinks_of_block(block_idx: u64, num_sidelinks: usize) -> usize {
+        num_sidelinks.min(block_idx.saturating_sub(2) as usize)
     }
 
-    pub fn add_block(&mut self, block: Block) {
-        if !self.validate_block(&block) {
-            println!("Invalid block: {:?}", block);
-            return;
+    // Overwrites the file (and its index and metadata) with the chain of the default network.
+    // A running node changes its chain through the storage thread instead.
+    #[allow(dead_code)]
+    pub fn save_blockchain_to_file(&self, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut store = FileBlockStore::new(file_name);
+        store.replace(&self.blocks)?;
+        match ChainMetadata::new(&self.blocks, self.num_sidelinks, DEFAULT_NETWORK_ID) {
+            Some(metadata) => store.set_metadata(&metadata),
+            None => Ok(()),
         }
-        self.blocks.push(block);
     }
 
-    // TODO: second parameter should be a flag whether we want to get blocks in the order specified
-    pub fn get_blocks_by_indices_from_file_in_given_order(indices: &Vec<u64>,
-        order: Option<Vec<u64>>,
-        file_name: &str
-    ) -> Option<Vec<Block>> {
-        let file = if let Ok(file) = File::open(file_name) {
-            file
-        } else {
-            println!("[LOAD BLOCKS FROM FILE] Error while opening the file");
-            return None;
-        };
-        let reader = io::BufReader::new(file);
-
-        // Create a map where the key is the index from indices array and the value is the
-        // value from order array
-        let order_map = if let Some(order) = order {
-            let mut map = HashMap::new();
-            for (i, &block_idx) in order.iter().enumerate() {
-                map.insert(i, block_idx);
-            }
-            Some(map)
-        } else {
-            None
-        };
-        let order_map = order_map.as_ref();
-        
-        let mut initial_order_map = HashMap::new();
-        // Vector with indices.len() elements to store the blocks in the correct order
-        let mut blocks = Vec::with_capacity(indices.len());
-        let mut block_position = 0;
-        for (i, line) in reader.lines().enumerate() {
-            if indices.contains(&((i + 1) as u64)) {
-                if let Ok(line) = line {
-                    if let Ok(block) = serde_json::from_str::<Block>(&line) {
-                        initial_order_map.insert(block.idx, block_position);
-                        blocks.push(block);
-                        block_position += 1;
-                    } else if let Err(e) = serde_json::from_str::<Block>(&line) {
-                        println!("[LOAD BLOCKS FROM FILE] Error while parsing the block");
-                        return None;
-                    }
-                } else {
-                    println!("[LOAD BLOCKS FROM FILE] Error while reading the file");
-                    return None;
-                }
-            }
-        }
-
-        // Reorder the blocks if necessary
-        if let Some(order_map) = order_map {
-            // info!("Reordering the blocks");
-            // println!("Order of the blocsk: {:?}", order_map);
-            let mut ordered_blocks = Vec::with_capacity(indices.len());
-            for i in 0..indices.len() {
-                if let Some(&block_idx) = order_map.get(&i) {
-                    let old_block_idx = initial_order_map.get(&block_idx).unwrap();
-                    // println!("Inserting block with ID {} from position {} to position {}", block_idx, *old_block_idx, i);
-                    ordered_blocks.push(blocks[*old_block_idx].clone());
-                }
-            }
-            blocks = ordered_blocks;
-        }
-
-        Some(blocks)
+    pub fn init_first_block(&mut self, params: ChainParams) {
+        self.blocks.push(Block::genesis(params));
     }
 
-    pub fn get_last_n_blocks_from_file(n: usize, file_name: &str) -> Option<Vec<Block>> {
-        let blockchain_length =
-            if let Err(e) = Chain::get_blockchain_length(file_name) {
-                println!("Error while getting last block from file: {}", e);
-                0
-            } else {
-                Chain::get_blockchain_length(file_name).unwrap()
-            };
-        let mut last_n_blocks = None;
-
-        if blockchain_length > 0 {
-            let indices = if blockchain_length <= n {
-                // Collect all indices into the vector
-                (1..(blockchain_length as u64 + 1)).collect()
-            } else {
-                // Collect the range of indices into the vector
-                ((blockchain_length as u64 - n as u64 + 1)..(blockchain_length as u64 + 1)).collect()
-            };
-            last_n_blocks = Chain::get_blocks_by_indices_from_file_in_given_order(&indices, 
-                None,
-                file_name);
-        }
-
-        last_n_blocks
+    // Sum of the expected number of hashes needed to mine every block of the chain
+    pub fn cumulative_work(&self) -> Integer {
+        self.blocks
+            .iter()
+            .filter(|block| block.idx > 1)
+            .map(|block| block.difficulty.work())
+            .sum()
     }
 
-    pub fn get_range_of_blocks_from_file(start_idx: u64, end_idx: u64, file_name: &str) -> Option<Vec<Block>> {
-        let blockchain_length =
-            if let Err(e) = Chain::get_blockchain_length(file_name) {
-                println!("Error while getting last block from file: {}", e);
-                0
-            } else {
-                Chain::get_blockchain_length(file_name).unwrap()
-            };
-            
-            if start_idx < 1 {
-            println!("Start index must be greater than 0");
-            return None;
-        }
-        if end_idx > blockchain_length as u64 + 1 {
-            println!("End index must be less than or equal to the length of the blockchain");
-            return None;
-        }
-        if end_idx < start_idx {
-            println!("End index must be greater than or equal to start index");
-            return None;
-        }
-        
-        let mut blocks = None;
-        if blockchain_length > 0 {
-            let indices = if end_idx > blockchain_length as u64 {
-                // Collect all indices into the vector
-                (start_idx..(blockchain_length as u64 + 1)).collect()
-            } else {
-                // Collect the range of indices into the vector
-                (start_idx..(end_idx + 1)).collect()
-            };
-            blocks = Chain::get_blocks_by_indices_from_file_in_given_order(&indices, 
-                None,
-                file_name);
-        }
+    // Difficulty the block with the given index has to declare to be valid in this chain
+    #[allow(dead_code)]
+    pub fn expected_difficulty(&self, block_idx: u64) -> Option<Target> {
+        difficulty::expected_difficulty(block_idx,
+            |idx| self.blocks.get(idx as usize - 1).cloned())
+    }
 
-        blocks
+    // Consensus parameters stored in the genesis block
+    pub fn chain_params(&self) -> Option<ChainParams> {
+        self.blocks.first()?.genesis_params.clone()
     }
 
-    pub fn load_block_from_file(block_idx: u64, file_name: &str) -> Option<Block> {
-        // TODO: we assume that the file is not corrupted and that, for simplicity, every
-        // block is on separate line. So to get ith block we simply read the ith line.
-        let file = if let Ok(file) = File::open(file_name) {
-            file
-        } else {
-            println!("[LOAD BLOCK FROM FILE] Error while opening the file");
-            return None;
-        };
-        let reader = io::BufReader::new(file);
-
-        // Read the file until reaching the desired element index
-        for (i, line) in reader.lines().enumerate() {
-            if i == (block_idx - 1) as usize {
-                if let Ok(line) = line {
-                    if let Ok(block) = serde_json::from_str(&line) {
-                        return Some(block);
-                    } else {
-                        println!("[LOAD BLOCK FROM FILE] Error while parsing the block");
-                        return None;
-                    }
-                } else {
-                    println!("[LOAD BLOCK FROM FILE] Error while reading the file");
-                    return None;
-                }
-            }
+    pub fn add_block(&mut self, block: Block) {
+        if !Chain::validate_block_in_store(&block, self) {
+            println!("Invalid block: {:?}", block);
+            return;
         }
-
-        println!("[LOAD BLOCK FROM FILE] Unable to find the block with ID {}", block_idx);
-        None
+        self.blocks.push(block);
     }
 
     pub fn get_last_block(&self) -> Option<&Block> {
         self.blocks.last()
     }
 
-    pub fn get_last_block_from_file(file_name: &str) -> Option<Block> {
-        let blockchain_length =
-            if let Err(e) = Chain::get_blockchain_length(file_name) {
-                warn!("Error while getting last block from file: {}", e);
-                0
-            } else {
-                Chain::get_blockchain_length(file_name).unwrap()
-            };
-        let mut last_block = None;
-
-        if blockchain_length > 0 {
-            last_block = if let Some(block) = Chain::load_block_from_file(
-                blockchain_length as u64,
-                file_name)
-            {
-                Some(block)
-            } else {
-                None
-            }
-        }
+    // Records are addressed the same way as in Record::idx, i.e. by (block_idx, record_idx)
+    pub fn record_proof(&self, block_idx: u64, record_idx: u64) -> Option<MerkleProof> {
+        self.blocks.get(block_idx.checked_sub(1)? as usize)?.merkle_proof(record_idx, self.pow_algorithm())
+    }
 
-        last_block
+    // Receipt for the record containing headers of all blocks up to the current tip
+    pub fn record_receipt(&self, block_idx: u64, record_idx: u64) -> Option<RecordReceipt> {
+        let block = self.blocks.get(block_idx.checked_sub(1)? as usize)?;
+        let record = block.records.iter().find(|record| record.idx.1 == record_idx)?;
+        let algorithm = self.pow_algorithm();
+
+        Some(RecordReceipt {
+            pow_algorithm: self.chain_params()?.pow_algorithm,
+            record: record.clone(),
+            proof: self.record_proof(block_idx, record_idx)?,
+            block_header: block.header(algorithm),
+            header_chain: self.blocks[block_idx as usize..]
+                .iter()
+                .map(|block| block.header(algorithm))
+                .collect(),
+        })
     }
 
     pub fn remove_last_block(&mut self) {
         self.blocks.pop();
     }
 
-    pub fn remove_last_block_from_file(file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        if let Ok(file) = File::open(file_name){
-            if file.metadata()?.len() == 0 {
-                println!("The file is empty.");
-                return Ok(());
-            }
-            let reader = io::BufReader::new(&file);
-            let mut last_non_empty_line_pos: Option<u64> = None;
-            let mut last_line_pos = 0;
-            for line in reader.lines() {
-                let line = line?;
-                last_line_pos += line.len() as u64 + 1; // Add 1 for the line break
-
-                if !line.is_empty() {
-                    last_non_empty_line_pos = Some(last_line_pos);
-                }
-            }
-
-            if let Some(pos) = last_non_empty_line_pos {
-                // Truncate the file to remove the last non-empty line
-                let file = OpenOptions::new().write(true).open(file_name)?;
-                file.set_len(pos)?;
-                println!("Last non-empty line removed successfully.");
-            } else {
-                println!("No non-empty line found.");
-            }
-        } else {
-            println!("Error while removing last block from file");
-        };
-
-        Ok(())
-    }
-
-    pub fn choose_random_block_hashes(&self) -> Vec<String> {
+    pub fn choose_random_block_hashes(&self) -> Vec<BlockHash> {
         let hashes_to_choose = if self.blocks.len() < self.num_sidelinks {
             self.blocks.len()
         } else {
@@ -446,22 +259,13 @@ impl Chain {
         let mut i = 0;
         while i < hashes_to_choose {
             let idx = rng.gen_range(0..self.blocks.len());
-            let hash = self.blocks[idx].hash();
+            let hash = self.blocks[idx].hash(self.pow_algorithm());
             hashes.push(hash);
             i += 1;
         }
         hashes
     }
 
-    pub fn validate_chain_from_file(blockchain_filepath: &str) -> bool {
-        if let Ok(chain) = Chain::load_from_file(blockchain_filepath) {
-            chain.validate_chain()
-        } else {
-            println!("Error while loading the chain from file");
-            false
-        }
-    }
-
     pub fn validate_chain(&self) -> bool {
         // Check if the chain is empty
         if self.blocks.is_empty() {
@@ -471,15 +275,16 @@ impl Chain {
         }
 
         // Check if the genesis block is correct
-        if self.blocks[0] != Block::genesis() {
+        if !self.blocks[0].is_genesis() {
             println!("Verification of the chain failed. \
                 The genesis block is incorrect.");
             return false;
         }
 
         // Check if the chain is continuous
+        let mut included_records = HashSet::new();
         for i in 1..self.blocks.len() {
-            if !self.validate_block(&self.blocks[i]) {
+            if !Chain::validate_block_with_records(&self.blocks[i], self, &mut included_records) {
                 println!("Verification of the chain failed. \
                     Block with ID {} is invalid.", i + 1);
                 return false;
@@ -489,40 +294,60 @@ impl Chain {
         true
     }
 
-    fn validate_block_core(block: &Block,
-        blockchain_filepath: Option<&str>,
-        chain: Option<&Chain>,
-        source: BlockValidationSource,
-    ) -> bool
-    {
+    // Checks the block against the blocks preceding it in the store, which has to contain at
+    // least the blocks up to the parent of the block. Only the blocks the block refers to are
+    // read, the records of the other blocks are looked up in the index of the store.
+    pub fn validate_block_in_store(block: &Block, store: &dyn BlockReader) -> bool {
+        let mut included_records = block.records.iter()
+            .filter(|record| store.record_height(record).is_some_and(|height| height < block.idx))
+            .map(Record::signing_payload)
+            .collect();
+
+        Chain::validate_block_with_records(block, store, &mut included_records)
+    }
+
+    // Whether a record with the same content is in the store already
+    pub fn store_contains_record(store: &dyn BlockReader, record: &Record) -> bool {
+        store.record_height(record).is_some()
+    }
+
+    // included_records holds the contents (Record::signing_payload) of the records of the
+    // blocks preceding the block (at least of those which the block includes again), the
+    // records of the block are added to them
+    fn validate_block_with_records(block: &Block,
+        store: &dyn BlockReader,
+        included_records: &mut HashSet<Vec<u8>>,
+    ) -> bool {
         // println!("Validating block: {:?}", block);
+        // Blocks of older versions are only read, they cannot be part of a valid chain
+        if block.version != BLOCK_VERSION {
+            println!("Verification of block with ID {}. \
+                Block of version {} is not of version {}", block.idx, block.version, BLOCK_VERSION);
+            return false;
+        }
+
         // Check if the block is the genesis block
         if block.idx == 1 {
-            if *block != Block::genesis() {
+            if !block.is_genesis() {
                 println!("Verification of the genesis block failed. \
                     Invalid data stored in the genesis block.");
-                println!("Expected: {:?}\nActual: {:?}", Block::genesis(), block);
+                println!("Actual: {:?}", block);
                 return false;
             }
             return true;
         }
 
-        let previous_block = match source {
-            BlockValidationSource::File => {
-                let block_from_file = Chain::load_block_from_file(
-                    block.idx - 1,
-                    blockchain_filepath.unwrap());
-                block_from_file
-            }
-            BlockValidationSource::Chain => {
-                if let Some(block) = chain.unwrap().blocks.get(block.idx as usize - 2) {
-                    Some((*block).clone())
-                } else {
-                    None
-                }
-            }
+        // Every hash of the chain is made with the hash function chosen by the genesis block
+        let algorithm = if let Some(chain_params) = store.chain_params() {
+            chain_params.pow_algorithm.algorithm()
+        } else {
+            println!("Was unable to get the chain parameters from the store. \
+                Verification of block with ID {} failed.", block.idx);
+            return false;
         };
 
+        let previous_block = store.get(block.idx - 1);
+
         if let Some(previous_block) = previous_block {
             // Check the correctness of ID of the block
             if block.idx != previous_block.idx + 1 {
@@ -533,7 +358,7 @@ impl Chain {
             }
 
             // Check if the block is the next block in the chain
-            let previous_block_hash = previous_block.hash();
+            let previous_block_hash = previous_block.hash(algorithm);
             if block.previous_block_hash != previous_block_hash {
                 println!("Verification of block with ID {}. \
                     Invalid hash of the previous block: stored: {:?}, actual hash: {:?}",
@@ -541,7 +366,60 @@ impl Chain {
                 return false;
             }
 
-            let validation_sidelinks = block.derive_sidelink_indices();
+            // Check if the timestamp of the block is plausible
+            let now = chrono::Utc::now().timestamp() as u64;
+            if block.timestamp < previous_block.timestamp
+                || block.timestamp > now + MAX_FUTURE_BLOCK_TIME
+            {
+                println!("Verification of block with ID {}. \
+                    Invalid timestamp: {}, previous block's timestamp: {}, current time: {}",
+                    block.idx, block.timestamp, previous_block.timestamp, now);
+                return false;
+            }
+
+            // Check if every record is numbered by its place in the block. A record repeated
+            // at the end of a block keeps the Merkle root (see merkle::merkle_root), but not
+            // its place, so the same proof of work cannot be reused for such a block.
+            if let Some((position, record)) = block.records.iter().enumerate()
+                .find(|(position, record)| record.idx != (block.idx, *position as u64 + 1))
+            {
+                println!("Verification of block with ID {}. \
+                    Record {:?} is stored at position {} of the block",
+                    block.idx, record.idx, position + 1);
+                return false;
+            }
+
+            // Check if no record is included twice. A signature covers the content of a record
+            // but not its place, so anybody could otherwise put a signed record into another
+            // block.
+            if let Some(record) = block.records.iter()
+                .find(|record| !included_records.insert(record.signing_payload()))
+            {
+                println!("Verification of block with ID {}. \
+                    Record {:?} by {} is already in the chain",
+                    block.idx, record.idx, record.author_peer_id);
+                return false;
+            }
+
+            // Check if every record was signed by its author
+            if let Some(record) = block.records.iter().find(|record| !record.has_valid_signature()) {
+                println!("Verification of block with ID {}. \
+                    Invalid signature of record {:?} by {}",
+                    block.idx, record.idx, record.author_peer_id);
+                return false;
+            }
+
+            // Check if the block has as many sidelinks as the chain requires. The number is
+            // part of the block, so a miner could otherwise choose to link fewer blocks.
+            let required_sidelinks = Chain::num_sidelinks_of_block(block.idx, store.num_sidelinks());
+            if block.num_sidelinks != required_sidelinks {
+                println!("Verification of block with ID {}. \
+                    Invalid number of sidelinks: stored: {}, required by the chain: {}",
+                    block.idx, block.num_sidelinks, required_sidelinks);
+                return false;
+            }
+
+            let validation_sidelinks = block.derive_sidelink_indices(algorithm);
             // println!("Block index: {}, sidelinked block indices: {:?}", block.idx, validation_sidelinks);
             // Check if the number of hashes of previous blocks is correct
             if validation_sidelinks.len() != block.num_sidelinks {
@@ -552,87 +430,64 @@ impl Chain {
             }
 
             // Check if the hashes of previous blocks are correct
-            let mut block_order = Vec::new();
-            let sidelinked_blocks = match source {
-                BlockValidationSource::File => {
-                    // println!("Querying for blocks in this order: {:?}", validation_sidelinks);
-                    Chain::get_blocks_by_indices_from_file_in_given_order(
-                        &validation_sidelinks,
-                        Some(validation_sidelinks.clone()),
-                        blockchain_filepath.unwrap())
-                }
-                BlockValidationSource::Chain => {
-                    let mut blocks = Vec::new();
-                    for &idx in &validation_sidelinks {
-                        if let Some(block) = chain.unwrap().blocks.get(idx as usize - 1) {
-                            block_order.push(block.idx);
-                            blocks.push((*block).clone());
-                        } else {
-                            println!("Was unable to get the block with ID {} from the chain. \
-                                Verification of block with ID {} failed.", idx, block.idx);
-                            return false;
-                        }
-                    }
-                    Some(blocks)
-                }
-            };
+            let sidelinked_blocks = store.get_many(&validation_sidelinks);
 
-            // println!("Veryfying sidelinks:block order: {:?}", block_order);
             if let Some(sidelinked_blocks) = sidelinked_blocks {
                 if sidelinked_blocks.len() > 0 {
                     // println!("Sidelinked blocks: {:?}", sidelinked_blocks);
                     for (i, sidelinked_block) in sidelinked_blocks.iter().enumerate() {
                         // println!("Block index being verified: {}", sidelinked_block.idx);
                         // println!("Array index being verified: {}", i);
-                        if sidelinked_block.hash() != block.validation_sidelinks[i] {
+                        if sidelinked_block.hash(algorithm) != block.validation_sidelinks[i] {
                             println!("Verification of block with ID {}. \
                                 Invalid hash of the block with ID {}",
                                 block.idx, sidelinked_block.idx);
                             println!("Hash through the .hash function: {:?}\nStored: {:?}",
-                                sidelinked_block.hash(), block.validation_sidelinks[i]);
+                                sidelinked_block.hash(algorithm), block.validation_sidelinks[i]);
                             return false;
                         }
                     }
                 }
             } else {
-                println!("Was unable to get the sidelinked blocks from {:?}. \
-                    Verification of block with ID {} failed.", source, block.idx);
+                println!("Was unable to get the sidelinked blocks from the store. \
+                    Verification of block with ID {} failed.", block.idx);
+                return false;
+            }
+
+            // Check if the block was mined with the difficulty required by the chain and not
+            // with one chosen by the miner
+            let expected_difficulty = difficulty::expected_difficulty(block.idx,
+                |idx| store.get(idx));
+            if expected_difficulty.as_ref() != Some(&block.difficulty) {
+                println!("Verification of block with ID {}. \
+                    Invalid difficulty: stored: {}, expected: {:?}",
+                    block.idx, block.difficulty, expected_difficulty.map(|target| target.to_string()));
                 return false;
             }
 
             // Check the proof of work
-            let hash_result = pow::get_token_from_block(&block);
-            let token = hash_result.as_slice();
+            let token = if let Some(token) = pow::get_token_from_block(block, algorithm) {
+                token
+            } else {
+                println!("Verification of block with ID {}. \
+                    Invalid proof of work: {:?} is not a nonce", block.idx, block.pow);
+                return false;
+            };
             // println!("block.pow: {:?}", block.pow);
             // println!("block.previous_hash: {:?}", block.previous_block_hash);
             // println!("token: {:?}", token);
-            // TODO: using the static value for now since the difficulty isn't rea;;y calculated
-            if token.cmp(block.difficulty.as_slice()) != std::cmp::Ordering::Less {
+            if !block.difficulty.is_met_by(&token) {
                 println!("Verification of block with ID {}. \
                     Invalid proof of work: {:?} >= {:?}",
-                    block.idx, token, block.difficulty.as_slice());
+                    block.idx, token, block.difficulty.to_bytes());
                 return false;
             }
         } else {
-            println!("Was unable to get the last block of the chain from {:?}. \
-                Verification of block with ID {} failed.", source, block.idx);
+            println!("Was unable to get the last block of the chain from the store. \
+                Verification of block with ID {} failed.", block.idx);
             return false;
         }
 
         true
     }
-
-    pub fn validate_block_using_file(block: &Block, blockchain_filepath: &str) -> bool {
-        Chain::validate_block_core(block,
-            Some(blockchain_filepath),
-            None,
-            BlockValidationSource::File)
-    }
-    
-    fn validate_block(&self, block: &Block) -> bool {
-        Chain::validate_block_core(block,
-            None,
-            Some(self),
-            BlockValidationSource::Chain)
-    }
 }
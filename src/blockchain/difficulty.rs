@@ -0,0 +1,103 @@
+use serde::{Serialize, Deserialize};
+
+use crate::blockchain::{block::Block, pow_algorithm::PowAlgorithmKind, target::Target};
+
+// Consensus parameters of the chain. They are stored in the genesis block, so every chain
+// (and every peer that accepted it) agrees on them and they are covered by the genesis hash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    // Target which the token of the first mined block has to be smaller than
+    pub initial_difficulty: Target,
+    // Desired average time between two blocks in seconds
+    pub target_block_time: u64,
+    // Number of blocks after which the difficulty is adjusted
+    pub retarget_interval: u64,
+    // Hash function of the proof of work
+    #[serde(default, skip_serializing_if = "PowAlgorithmKind::is_default")]
+    pub pow_algorithm: PowAlgorithmKind,
+    // Hashrate (hashes per second) of the network the initial difficulty was derived from, if
+    // it was estimated by the node that created the chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial_hashrate: Option<u64>,
+}
+
+// Limits of a single adjustment (same as in Bitcoin), so that a few blocks with manipulated
+// timestamps cannot change the difficulty arbitrarily
+const MAX_ADJUSTMENT_FACTOR: u64 = 4;
+// Bounds of the chain parameters, far beyond any sensible chain. Genesis blocks come from
+// peers as well, so the difficulty arithmetic must not depend on their parameters being sane.
+pub const MAX_TARGET_BLOCK_TIME: u64 = 24 * 60 * 60;
+pub const MAX_RETARGET_INTERVAL: u64 = 1_000_000;
+
+impl ChainParams {
+    // Returns a description of the first parameter out of its bounds
+    pub fn check_bounds(&self) -> Result<(), String> {
+        if self.initial_difficulty == Target::zero() {
+            return Err("the initial difficulty is zero, no block could be mined".to_string());
+        }
+        if !(1..=MAX_TARGET_BLOCK_TIME).contains(&self.target_block_time) {
+            return Err(format!("the target block time {} s is not between 1 and {} s",
+                self.target_block_time, MAX_TARGET_BLOCK_TIME));
+        }
+        if !(1..=MAX_RETARGET_INTERVAL).contains(&self.retarget_interval) {
+            return Err(format!("the retarget interval {} is not between 1 and {}",
+                self.retarget_interval, MAX_RETARGET_INTERVAL));
+        }
+        Ok(())
+    }
+}
+
+/*
+    Difficulty (target) which the block with the given index has to declare.
+    It is derived from the genesis parameters and the blocks preceding the block, so it does
+    not depend on anything the miner of the block can choose:
+        - the first mined block uses the initial difficulty of the chain,
+        - every retarget_interval blocks the difficulty is scaled by the ratio of the time it
+          took to mine the last retarget_interval blocks to the time it should have taken,
+        - every other block keeps the difficulty of its predecessor.
+    get_block returns the block with the given index of the chain the block is validated
+    against. Returns None if some block required to determine the difficulty is missing.
+ */
+pub fn expected_difficulty(block_idx: u64, get_block: impl Fn(u64) -> Option<Block>) -> Option<Target> {
+    if block_idx <= 1 {
+        // Genesis block is not mined
+        return None;
+    }
+
+    let previous_block = get_block(block_idx - 1)?;
+    if previous_block.idx == 1 {
+        return previous_block.genesis_params.map(|params| params.initial_difficulty);
+    }
+
+    let params = get_block(1)?.genesis_params?;
+    if !is_retarget_height(block_idx, &params) {
+        return Some(previous_block.difficulty);
+    }
+
+    // Genesis has no meaningful timestamp, so the window starts at the first block of the
+    // current difficulty period and spans retarget_interval - 1 block times
+    let first_block = get_block(block_idx.checked_sub(params.retarget_interval)?)?;
+    let actual_timespan = previous_block.timestamp.saturating_sub(first_block.timestamp);
+    let expected_timespan = params.retarget_interval.saturating_sub(1).saturating_mul(params.target_block_time);
+
+    Some(retarget(&previous_block.difficulty, actual_timespan, expected_timespan))
+}
+
+// Mined blocks start at index 2, so the difficulty changes at blocks 2 + k * retarget_interval
+pub fn is_retarget_height(block_idx: u64, params: &ChainParams) -> bool {
+    // Window of a single block would span no time at all
+    params.retarget_interval >= 2
+        && block_idx > 2
+        && (block_idx - 2).is_multiple_of(params.retarget_interval)
+}
+
+// new target = old target * actual timespan / expected timespan
+pub fn retarget(difficulty: &Target, actual_timespan: u64, expected_timespan: u64) -> Target {
+    let expected_timespan = expected_timespan.max(1);
+    let actual_timespan = actual_timespan.clamp(
+        (expected_timespan / MAX_ADJUSTMENT_FACTOR).max(1),
+        expected_timespan.saturating_mul(MAX_ADJUSTMENT_FACTOR));
+
+    // Scaling saturates at the largest target
+    difficulty.scale(actual_timespan, expected_timespan)
+}
@@ -0,0 +1,363 @@
+use std::error::Error;
+
+use crate::blockchain::block::{Block, BlockHeader, Record, BLOCK_VERSION};
+use crate::blockchain::difficulty::ChainParams;
+use crate::blockchain::hash::{BlockHash, HASH_BYTES};
+use crate::blockchain::pow_algorithm::PowAlgorithmKind;
+use crate::blockchain::target::{Target, TARGET_BYTES};
+
+/*
+    Canonical binary encoding of blocks, block headers and records. Hashes, proofs of work and
+    Merkle trees are computed over it, so any implementation can reproduce them from this
+    description alone. It is also a compact alternative to JSON for storing and sending blocks
+    (see BlockFormat).
+
+    Values are encoded as:
+        - integer: 8 bytes, unsigned big-endian,
+        - string: its length as an integer, then its UTF-8 bytes,
+        - list: the number of items as an integer, then the items,
+        - optional value: 1 byte, 0 if the value is absent, 1 followed by the value if present,
+        - target: 32 bytes, big-endian,
+        - block hash: its 32 bytes,
+        - PoW algorithm: 1 byte, 0 for sha256, 1 for sha3-256.
+    Structures are the concatenation of their fields in the order below, nothing else (no field
+    names, no padding):
+        - chain parameters: initial_difficulty (target), target_block_time, retarget_interval,
+          pow_algorithm, initial_hashrate (optional integer),
+        - record: idx.0, idx.1, timestamp, data, author_peer_id, public_key, signature,
+        - block header: version, idx, previous_block_hash, validation_sidelinks (list of block
+          hashes), num_sidelinks, merkle_root (block hash), timestamp, difficulty (target),
+          genesis_params (optional chain parameters), miner_peer_id (optional string), pow,
+        - block: version, idx, previous_block_hash, validation_sidelinks, num_sidelinks, pow,
+          timestamp, records (list of records), difficulty, genesis_params, miner_peer_id.
+    Keys and signatures (public_key, signature) are encoded as the strings they are in JSON,
+    i.e. base64 text and not the bytes it decodes to.
+
+    Every hash of a chain is made with the hash function H of its proof of work (pow_algorithm
+    of the genesis parameters). The hash of a block is H(encoded header). The proof of work of
+    a block is computed over the encoded header without its last field (pow) followed by the
+    nonce as an integer, and a Merkle leaf is H(0x00 || encoded record). The sidelinks of a
+    block are derived from H(previous_block_hash || i) and H(previous_block_hash || i || i),
+    with the hash as its 32 bytes and i as an integer (see Block::derive_sidelink_indices).
+ */
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    fn decode(input: &mut Decoder) -> Result<Self, String>;
+}
+
+pub fn to_bytes(value: &impl Encode) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode(&mut out);
+    out
+}
+
+// The bytes have to hold exactly one value
+pub fn from_bytes<T: Decode>(bytes: &[u8]) -> Result<T, String> {
+    let mut input = Decoder { bytes };
+    let value = T::decode(&mut input)?;
+    if !input.bytes.is_empty() {
+        return Err(format!("{} bytes left after the encoded value", input.bytes.len()));
+    }
+    Ok(value)
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.bytes.len() {
+            return Err(format!("expected {} more bytes, only {} left", len, self.bytes.len()));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl Decode for u64 {
+    fn decode(input: &mut Decoder) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(input.take(8)?.try_into().expect("took 8 bytes")))
+    }
+}
+
+impl Encode for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+}
+
+impl Decode for usize {
+    fn decode(input: &mut Decoder) -> Result<usize, String> {
+        let value = u64::decode(input)?;
+        usize::try_from(value).map_err(|_| format!("{} does not fit into usize", value))
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_str().encode(out);
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut Decoder) -> Result<String, String> {
+        let len = usize::decode(input)?;
+        String::from_utf8(input.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut Decoder) -> Result<Vec<T>, String> {
+        // Not preallocated, the number of items comes from the input
+        let len = u64::decode(input)?;
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(T::decode(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                out.push(1);
+                value.encode(out);
+            },
+            None => out.push(0),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut Decoder) -> Result<Option<T>, String> {
+        match input.byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(input)?)),
+            tag => Err(format!("invalid tag {} of an optional value", tag)),
+        }
+    }
+}
+
+impl Encode for Target {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_bytes());
+    }
+}
+
+impl Decode for Target {
+    fn decode(input: &mut Decoder) -> Result<Target, String> {
+        Ok(Target::from_bytes(input.take(TARGET_BYTES)?))
+    }
+}
+
+impl Encode for BlockHash {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for BlockHash {
+    fn decode(input: &mut Decoder) -> Result<BlockHash, String> {
+        Ok(BlockHash::from_bytes(input.take(HASH_BYTES)?.try_into().expect("took 32 bytes")))
+    }
+}
+
+impl Encode for PowAlgorithmKind {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match self {
+            PowAlgorithmKind::Sha256 => 0,
+            PowAlgorithmKind::Sha3 => 1,
+        });
+    }
+}
+
+impl Decode for PowAlgorithmKind {
+    fn decode(input: &mut Decoder) -> Result<PowAlgorithmKind, String> {
+        match input.byte()? {
+            0 => Ok(PowAlgorithmKind::Sha256),
+            1 => Ok(PowAlgorithmKind::Sha3),
+            tag => Err(format!("unknown PoW algorithm {}", tag)),
+        }
+    }
+}
+
+impl Encode for ChainParams {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.initial_difficulty.encode(out);
+        self.target_block_time.encode(out);
+        self.retarget_interval.encode(out);
+        self.pow_algorithm.encode(out);
+        self.initial_hashrate.encode(out);
+    }
+}
+
+impl Decode for ChainParams {
+    fn decode(input: &mut Decoder) -> Result<ChainParams, String> {
+        Ok(ChainParams {
+            initial_difficulty: Target::decode(input)?,
+            target_block_time: u64::decode(input)?,
+            retarget_interval: u64::decode(input)?,
+            pow_algorithm: PowAlgorithmKind::decode(input)?,
+            initial_hashrate: Option::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Record {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.idx.0.encode(out);
+        self.idx.1.encode(out);
+        self.timestamp.encode(out);
+        self.data.encode(out);
+        self.author_peer_id.encode(out);
+        self.public_key.encode(out);
+        self.signature.encode(out);
+    }
+}
+
+impl Decode for Record {
+    fn decode(input: &mut Decoder) -> Result<Record, String> {
+        Ok(Record {
+            idx: (u64::decode(input)?, u64::decode(input)?),
+            timestamp: u64::decode(input)?,
+            data: String::decode(input)?,
+            author_peer_id: String::decode(input)?,
+            public_key: String::decode(input)?,
+            signature: String::decode(input)?,
+        })
+    }
+}
+
+impl BlockHeader {
+    // Everything but the nonce, see pow_prefix
+    pub fn encode_without_pow(&self, out: &mut Vec<u8>) {
+        (self.version as u64).encode(out);
+        self.idx.encode(out);
+        self.previous_block_hash.encode(out);
+        self.validation_sidelinks.encode(out);
+        self.num_sidelinks.encode(out);
+        self.merkle_root.encode(out);
+        self.timestamp.encode(out);
+        self.difficulty.encode(out);
+        self.genesis_params.encode(out);
+        self.miner_peer_id.encode(out);
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.encode_without_pow(out);
+        self.pow.encode(out);
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.version as u64).encode(out);
+        self.idx.encode(out);
+        self.previous_block_hash.encode(out);
+        self.validation_sidelinks.encode(out);
+        self.num_sidelinks.encode(out);
+        self.pow.encode(out);
+        self.timestamp.encode(out);
+        self.records.encode(out);
+        self.difficulty.encode(out);
+        self.genesis_params.encode(out);
+        self.miner_peer_id.encode(out);
+    }
+}
+
+impl Decode for Block {
+    fn decode(input: &mut Decoder) -> Result<Block, String> {
+        let version = u64::decode(input)?;
+        if version > BLOCK_VERSION as u64 {
+            return Err(format!("block version {} is newer than the supported version {}",
+                version, BLOCK_VERSION));
+        }
+        Ok(Block {
+            version: version as u32,
+            idx: u64::decode(input)?,
+            previous_block_hash: BlockHash::decode(input)?,
+            validation_sidelinks: Vec::decode(input)?,
+            num_sidelinks: usize::decode(input)?,
+            pow: String::decode(input)?,
+            timestamp: u64::decode(input)?,
+            records: Vec::decode(input)?,
+            difficulty: Target::decode(input)?,
+            genesis_params: Option::decode(input)?,
+            miner_peer_id: Option::decode(input)?,
+        })
+    }
+}
+
+// Format blocks are stored and sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockFormat {
+    // One JSON object per block, readable and the format of every older version
+    #[default]
+    Json,
+    // Canonical binary encoding, about half the size
+    Binary,
+}
+
+impl BlockFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockFormat::Json => "json",
+            BlockFormat::Binary => "binary",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BlockFormat> {
+        [BlockFormat::Json, BlockFormat::Binary]
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+
+    pub fn encode_block(&self, block: &Block) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            BlockFormat::Json => Ok(serde_json::to_vec(block)?),
+            BlockFormat::Binary => Ok(to_bytes(block)),
+        }
+    }
+
+    pub fn decode_block(&self, bytes: &[u8]) -> Result<Block, Box<dyn Error>> {
+        match self {
+            BlockFormat::Json => Ok(serde_json::from_slice(bytes)?),
+            BlockFormat::Binary => Ok(from_bytes(bytes)?),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use openssl::base64;
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+
+pub const HASH_BYTES: usize = 32;
+
+/*
+    Hash of a block, made with the hash function of its chain (see encoding for what is
+    hashed). It is shown, parsed and stored in JSON as 64 lowercase hex digits. Hashes are
+    ordered as big-endian numbers, which breaks ties between chains with the same work.
+    The genesis block points to the zero hash, since it has no parent.
+ */
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockHash([u8; HASH_BYTES]);
+
+impl BlockHash {
+    pub const fn zero() -> BlockHash {
+        BlockHash([0; HASH_BYTES])
+    }
+
+    pub fn from_bytes(bytes: [u8; HASH_BYTES]) -> BlockHash {
+        BlockHash(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; HASH_BYTES] {
+        &self.0
+    }
+
+    /*
+        Blocks of version 0 (see block::BLOCK_VERSION) stored hashes as base64 strings and
+        pointed the genesis block to a string of 32 zeros. Only used when reading stored blocks,
+        parsing accepts hex alone.
+     */
+    fn from_legacy_str(string: &str) -> Option<BlockHash> {
+        if string == "0".repeat(HASH_BYTES) {
+            return Some(BlockHash::zero());
+        }
+        let bytes = base64::decode_block(string).ok()?;
+        Some(BlockHash(bytes.try_into().ok()?))
+    }
+}
+
+impl fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BlockHash({})", self)
+    }
+}
+
+impl FromStr for BlockHash {
+    type Err = String;
+
+    fn from_str(hex: &str) -> Result<BlockHash, String> {
+        if hex.len() != 2 * HASH_BYTES || !hex.is_ascii() {
+            return Err(format!("\"{}\" is not a hash of {} hex digits", hex, 2 * HASH_BYTES));
+        }
+        let mut bytes = [0; HASH_BYTES];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).expect("hex is ASCII");
+            *byte = u8::from_str_radix(digits, 16)
+                .map_err(|_| format!("\"{}\" is not a hex number", hex))?;
+        }
+        Ok(BlockHash(bytes))
+    }
+}
+
+impl Serialize for BlockHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BlockHash, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        string.parse()
+            .or_else(|e| BlockHash::from_legacy_str(&string).ok_or(e))
+            .map_err(serde::de::Error::custom)
+    }
+}
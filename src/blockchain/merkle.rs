@@ -0,0 +1,107 @@
+use serde::{Serialize, Deserialize};
+
+use crate::blockchain::block::Record;
+use crate::blockchain::encoding;
+use crate::blockchain::hash::BlockHash;
+use crate::blockchain::pow_algorithm::PowAlgorithm;
+
+// Domain separation prefixes so that a leaf can never be reinterpreted as an inner node
+// (and the other way around), which would allow forging inclusion of made up data.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+// Root of a tree with no leaves, i.e. of a block without any records
+pub const EMPTY_ROOT: [u8; 32] = [0; 32];
+
+// Nodes are hashed with the hash function of the chain's proof of work
+pub fn hash_leaf(record: &Record, algorithm: &dyn PowAlgorithm) -> [u8; 32] {
+    algorithm.hash(&[&[LEAF_PREFIX], encoding::to_bytes(record).as_slice()].concat())
+}
+
+pub fn hash_nodes(left: &[u8; 32], right: &[u8; 32], algorithm: &dyn PowAlgorithm) -> [u8; 32] {
+    algorithm.hash(&[&[NODE_PREFIX], left.as_slice(), right.as_slice()].concat())
+}
+
+/*
+    Merkle root over the records of a block.
+    Leaves are hashed records in the order they are stored in the block. If a level has an odd
+    number of nodes, the last node is paired with itself (same as in Bitcoin). Because of that
+    records [a, b, c] and [a, b, c, c] have the same root, which is harmless only because the
+    index of every record is hashed with it and has to match its place in the block (checked
+    by Chain::validate_block_in_store).
+ */
+pub fn merkle_root(records: &[Record], algorithm: &dyn PowAlgorithm) -> [u8; 32] {
+    if records.is_empty() {
+        return EMPTY_ROOT;
+    }
+
+    let mut level: Vec<[u8; 32]> = records.iter().map(|record| hash_leaf(record, algorithm)).collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| hash_nodes(&pair[0], pair.get(1).unwrap_or(&pair[0]), algorithm))
+            .collect();
+    }
+
+    level[0]
+}
+
+// One step of the path from a leaf to the root: the hash of the sibling node and whether the
+// sibling is the left child of their common parent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProofStep {
+    pub sibling: BlockHash,
+    pub sibling_is_left: bool,
+}
+
+// Merkle inclusion proof of a single record, from the leaf up to (but without) the root
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    // Position of the record among the records of the block
+    pub leaf_position: usize,
+    pub path: Vec<ProofStep>,
+}
+
+pub fn merkle_proof(records: &[Record], leaf_position: usize, algorithm: &dyn PowAlgorithm) -> Option<MerkleProof> {
+    if leaf_position >= records.len() {
+        return None;
+    }
+
+    let mut path = Vec::new();
+    let mut position = leaf_position;
+    let mut level: Vec<[u8; 32]> = records.iter().map(|record| hash_leaf(record, algorithm)).collect();
+    while level.len() > 1 {
+        // Same odd node pairing as in merkle_root
+        let sibling_position = position ^ 1;
+        let sibling = level.get(sibling_position).unwrap_or(&level[position]);
+        path.push(ProofStep {
+            sibling: BlockHash::from_bytes(*sibling),
+            sibling_is_left: sibling_position < position,
+        });
+
+        level = level
+            .chunks(2)
+            .map(|pair| hash_nodes(&pair[0], pair.get(1).unwrap_or(&pair[0]), algorithm))
+            .collect();
+        position /= 2;
+    }
+
+    Some(MerkleProof { leaf_position, path })
+}
+
+impl MerkleProof {
+    // Checks that the record is included in the tree with the given root
+    pub fn verify(&self, record: &Record, merkle_root: &BlockHash, algorithm: &dyn PowAlgorithm) -> bool {
+        let mut hash = hash_leaf(record, algorithm);
+        for step in &self.path {
+            let sibling = step.sibling.as_bytes();
+            hash = if step.sibling_is_left {
+                hash_nodes(sibling, &hash, algorithm)
+            } else {
+                hash_nodes(&hash, sibling, algorithm)
+            };
+        }
+
+        hash == *merkle_root.as_bytes()
+    }
+}
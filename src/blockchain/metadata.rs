@@ -0,0 +1,101 @@
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+
+use crate::blockchain::block::Block;
+use crate::blockchain::chain::Chain;
+use crate::blockchain::hash::BlockHash;
+use crate::blockchain::pow_algorithm::PowAlgorithmKind;
+use crate::blockchain::store::BlockReader;
+
+// Version of the layout of the stored chain (block file, index and metadata)
+pub const CHAIN_FORMAT_VERSION: u32 = 1;
+// Network of the nodes which do not configure one
+pub const DEFAULT_NETWORK_ID: &str = "pliers";
+
+/*
+    Chain-level information stored next to the blocks (see FileBlockStore). Most of it can be
+    derived from the genesis block, it is kept so that a store can be checked before it is
+    used: a chain of another network, of a newer format or with a replaced genesis block is
+    rejected instead of being extended.
+    The number of sidelinks is the only part which cannot be derived: blocks at the start of
+    the chain have fewer sidelinks than the chain requires.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainMetadata {
+    pub format_version: u32,
+    pub network_id: String,
+    pub num_sidelinks: usize,
+    // Seconds, same as in the genesis parameters
+    pub target_block_time: u64,
+    pub pow_algorithm: PowAlgorithmKind,
+    pub genesis_hash: BlockHash,
+}
+
+impl ChainMetadata {
+    // None if the chain has no genesis block with parameters
+    pub fn new(blocks: &[Block], num_sidelinks: usize, network_id: &str) -> Option<ChainMetadata> {
+        let genesis = blocks.first()?;
+        let params = genesis.genesis_params.as_ref()?;
+        Some(ChainMetadata {
+            format_version: CHAIN_FORMAT_VERSION,
+            network_id: network_id.to_string(),
+            num_sidelinks,
+            target_block_time: params.target_block_time,
+            pow_algorithm: params.pow_algorithm,
+            genesis_hash: genesis.hash(params.pow_algorithm.algorithm()),
+        })
+    }
+
+    /*
+        Metadata for a chain stored without it (by an older version, or if the node stopped
+        between writing the blocks and the metadata). The number of sidelinks is known from the
+        tip once the chain is long enough, until then the given default is the best guess.
+     */
+    pub fn infer(store: &dyn BlockReader, default_num_sidelinks: usize, network_id: &str) -> Option<ChainMetadata> {
+        let genesis = store.get(1)?;
+        let tip = store.tip()?;
+        let num_sidelinks = if (tip.num_sidelinks as u64) < tip.idx.saturating_sub(2) {
+            tip.num_sidelinks
+        } else {
+            default_num_sidelinks.max(tip.num_sidelinks)
+        };
+        ChainMetadata::new(&[genesis], num_sidelinks, network_id)
+    }
+
+    // Checks that the metadata belongs to the stored chain and the chain to the given network
+    pub fn check(&self, store: &dyn BlockReader, network_id: &str) -> Result<(), Box<dyn Error>> {
+        if self.format_version > CHAIN_FORMAT_VERSION {
+            return Err(format!("the chain was stored by a newer version (format {}, supported up to {})",
+                self.format_version, CHAIN_FORMAT_VERSION).into());
+        }
+        if self.network_id != network_id {
+            return Err(format!("the chain belongs to network \"{}\", not to \"{}\"",
+                self.network_id, network_id).into());
+        }
+
+        let genesis = store.get(1).ok_or("the chain has no genesis block")?;
+        let genesis_hash = genesis.hash(store.pow_algorithm());
+        if genesis_hash != self.genesis_hash {
+            return Err(format!("the genesis block {} is not the genesis block {} of the chain",
+                genesis_hash, self.genesis_hash).into());
+        }
+        let expected = ChainMetadata::new(&[genesis], self.num_sidelinks, network_id)
+            .ok_or("the genesis block has no chain parameters")?;
+        if self.target_block_time != expected.target_block_time || self.pow_algorithm != expected.pow_algorithm {
+            return Err(format!("the parameters of the genesis block (block time {} s, {:?}) differ from \
+                the metadata (block time {} s, {:?})",
+                expected.target_block_time, expected.pow_algorithm,
+                self.target_block_time, self.pow_algorithm).into());
+        }
+
+        // Every block has as many sidelinks as the chain requires, or all previous blocks
+        // but the genesis block if there are fewer
+        let tip = store.tip().ok_or("the chain has no blocks")?;
+        if tip.idx > 1 && tip.num_sidelinks != Chain::num_sidelinks_of_block(tip.idx, self.num_sidelinks) {
+            return Err(format!("block {} has {} sidelinks, but the chain requires {}",
+                tip.idx, tip.num_sidelinks, self.num_sidelinks).into());
+        }
+
+        Ok(())
+    }
+}
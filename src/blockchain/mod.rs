@@ -1,32 +1,146 @@
 pub mod chain;
 pub mod block;
 pub mod pow;
+pub mod pow_algorithm;
+pub mod merkle;
+pub mod receipt;
+pub mod difficulty;
+pub mod target;
+pub mod stats;
+pub mod store;
+pub mod storage;
+pub mod metadata;
+pub mod encoding;
+pub mod hash;
 
 #[cfg(test)]
 mod test {
+    use super::block::Block;
+    use super::pow_algorithm::Sha256Pow;
+    use super::target::Target;
+
+    const EASY_DIFFICULTY: [u8; 32] = [0, 48, 80, 236, 231, 14, 175, 100, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    fn easy_target() -> Target {
+        Target::from_bytes(&EASY_DIFFICULTY)
+    }
+
+    fn easy_params() -> super::difficulty::ChainParams {
+        super::difficulty::ChainParams {
+            initial_difficulty: easy_target(),
+            target_block_time: 10,
+            retarget_interval: 3,
+            pow_algorithm: Default::default(),
+            initial_hashrate: None,
+        }
+    }
+
+    fn mine_with(block: &mut Block, algorithm: &dyn super::pow_algorithm::PowAlgorithm) {
+        use super::pow::get_new_token;
+
+        let nonce = (0..u64::MAX)
+            .find(|&nonce| {
+                block.difficulty.is_met_by(&get_new_token(block, nonce, algorithm))
+            })
+            .unwrap();
+        block.pow = nonce.to_string();
+    }
+
+    fn mine(block: &mut Block) {
+        mine_with(block, &super::pow_algorithm::Sha256Pow);
+    }
 
     #[test]
     fn test_compare_token_getters() {
         use super::pow::{get_new_token, get_token_from_block};
+        use super::pow_algorithm::Sha256Pow;
 
         let nonce = 6339200808718768504;
         let block = crate::blockchain::block::Block::new(
             1,
-            "mDgKLzrjHxk/fpBrKby9puNvbVMVunf44ns3uj3d9UY=".to_string(),
+            "98380a2f3ae31f193f7e906b29bcbda6e36f6d5315ba77f8e27b37ba3dddf546".parse().unwrap(),
             0,
             Vec::new(),
             nonce.to_string(),
             Vec::new(),
-            vec![0, 0, 0, 48, 80, 236, 231, 14, 175, 100, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            Target::from_bytes(&[0, 0, 0, 48, 80, 236, 231, 14, 175, 100, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
         );
 
-        let token = get_new_token(&block, nonce);
-        let token2 = get_token_from_block(&block);
+        let token = get_new_token(&block, nonce, &Sha256Pow);
+        let token2 = get_token_from_block(&block, &Sha256Pow).unwrap();
 
         println!("token: {:?}\ntoken2: {:?}", token, token2);
         assert_eq!(token, token2);
     }
 
+    #[test]
+    fn test_midstate_tokens() {
+        use super::pow::{get_new_token, get_token_from_prefix};
+        use super::pow_algorithm::{PowAlgorithm, Sha256Pow, Sha3Pow};
+
+        let mut block = Block::genesis(easy_params());
+        block.idx = 2;
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        block.add_record_by_data("midstate".to_string(), &keypair);
+        let algorithms: [&dyn PowAlgorithm; 2] = [&Sha256Pow, &Sha3Pow];
+        for algorithm in algorithms {
+            let pow_prefix = block.header(algorithm).pow_prefix();
+            let midstate = algorithm.midstate(&pow_prefix);
+            for nonce in [0, 1, 4242, u64::MAX] {
+                let token = get_token_from_prefix(&pow_prefix, nonce, algorithm);
+                assert_eq!(midstate.token(nonce), token);
+                assert_eq!(midstate.token(nonce), get_new_token(&block, nonce, algorithm));
+            }
+        }
+        let pow_prefix = block.header(&Sha256Pow).pow_prefix();
+        assert_ne!(Sha256Pow.hash(&pow_prefix), Sha3Pow.hash(&pow_prefix));
+    }
+
+    // Run with: cargo test --release bench_pow_hashing -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_pow_hashing() {
+        use super::pow::{get_new_token, get_token_from_prefix};
+        use super::pow_algorithm::{PowAlgorithm, Sha256Pow, Sha3Pow};
+        use std::time::{Duration, Instant};
+
+        let mut block = Block::genesis(easy_params());
+        block.idx = 2;
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        for record in 0..10 {
+            block.add_record_by_data(format!("record {}", record), &keypair);
+        }
+        let pow_prefix = block.header(&Sha256Pow).pow_prefix();
+        let midstate = Sha256Pow.midstate(&pow_prefix);
+        let sha3_midstate = Sha3Pow.midstate(&pow_prefix);
+
+        let hashes_per_second = |hash: &dyn Fn(u64) -> [u8; 32]| {
+            let started = Instant::now();
+            let mut nonce = 0;
+            while started.elapsed() < Duration::from_secs(2) {
+                for _ in 0..1000 {
+                    std::hint::black_box(hash(nonce));
+                    nonce += 1;
+                }
+            }
+            nonce as f64 / started.elapsed().as_secs_f64()
+        };
+
+        let whole_header = hashes_per_second(&|nonce| get_new_token(&block, nonce, &Sha256Pow));
+        let concat = hashes_per_second(&|nonce| {
+            get_token_from_prefix(&pow_prefix, nonce, &Sha256Pow)
+        });
+        let midstate = hashes_per_second(&|nonce| midstate.token(nonce));
+        let sha3_midstate = hashes_per_second(&|nonce| sha3_midstate.token(nonce));
+        println!("PoW prefix length: {} bytes", pow_prefix.len());
+        println!("header rebuilt for every nonce: {:>12.0} H/s", whole_header);
+        println!("prefix concatenated:            {:>12.0} H/s", concat);
+        println!("midstate:                       {:>12.0} H/s ({:.2}x)",
+            midstate, midstate / concat);
+        println!("SHA3-256 midstate:              {:>12.0} H/s", sha3_midstate);
+    }
+
     #[test]
     fn test_sidelink_deriviation() {
         use super::block::Block;
@@ -34,19 +148,1321 @@ mod test {
         let num_sidelinks = 6;
         let block = Block::new(
             10,
-            "mDgKLzrjHxk/fpBrKby9puNvbVMVunf44ns3uj3d9UY=".to_string(),
+            "98380a2f3ae31f193f7e906b29bcbda6e36f6d5315ba77f8e27b37ba3dddf546".parse().unwrap(),
             num_sidelinks,
             Vec::new(),
             "6339200808718768504".to_string(),
             Vec::new(),
-            vec![0, 0, 0, 48, 80, 236, 231, 14, 175, 100, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            easy_target(),
         );
 
-        let sidelinks = block.derive_sidelink_indices();
-        let sidelinks_once_more = block.derive_sidelink_indices();
+        let sidelinks = block.derive_sidelink_indices(&Sha256Pow);
+        let sidelinks_once_more = block.derive_sidelink_indices(&Sha256Pow);
         println!("sidelinks: {:?}", sidelinks);
 
         assert_eq!(sidelinks, sidelinks_once_more);
+        // Derived from the bytes of the previous block hash, so they do not depend on how the
+        // hash is shown
+        assert_eq!(sidelinks, vec![3, 2, 5, 8, 7, 4]);
+    }
+
+    #[test]
+    fn test_merkle_root_commits_to_records() {
+        use super::block::Record;
+        use super::merkle::{merkle_root, EMPTY_ROOT};
+
+        let author_key = libp2p::identity::Keypair::generate_ed25519();
+        let mut block = Block::genesis(easy_params());
+        assert_eq!(merkle_root(&block.records, &Sha256Pow), EMPTY_ROOT);
+
+        for data in ["a", "b", "c"] {
+            block.add_record(Record::new(data.to_string(), &author_key));
+        }
+        let root = merkle_root(&block.records, &Sha256Pow);
+
+        let mut reordered = block.records.clone();
+        reordered.swap(0, 1);
+        assert_ne!(root, merkle_root(&reordered, &Sha256Pow));
+
+        let mut tampered = block.records.clone();
+        tampered[2].data = "d".to_string();
+        assert_ne!(root, merkle_root(&tampered, &Sha256Pow));
+    }
+
+    #[test]
+    fn test_malformed_pow_is_rejected() {
+        use super::chain::Chain;
+        use super::pow::get_token_from_block;
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        let mut block = Block::new(
+            2,
+            chain.get_last_block().unwrap().hash(&Sha256Pow),
+            0,
+            Vec::new(),
+            "".to_string(),
+            Vec::new(),
+            easy_target(),
+        );
+        mine(&mut block);
+        chain.blocks.push(block.clone());
+        assert!(chain.validate_chain());
+
+        // Blocks from peers can carry anything as the nonce
+        for pow in ["", "nonce", "-1", "18446744073709551616"] {
+            chain.blocks[1].pow = pow.to_string();
+            assert_eq!(get_token_from_block(&chain.blocks[1], &Sha256Pow), None);
+            assert!(!chain.validate_chain());
+        }
+    }
+
+    #[test]
+    fn test_duplicated_records_are_rejected() {
+        use super::block::Record;
+        use super::chain::Chain;
+        use super::merkle::merkle_root;
+
+        let author_key = libp2p::identity::Keypair::generate_ed25519();
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        let mut block = Block::new(
+            2,
+            chain.get_last_block().unwrap().hash(&Sha256Pow),
+            0,
+            Vec::new(),
+            "".to_string(),
+            Vec::new(),
+            easy_target(),
+        );
+        for data in ["a", "b", "c"] {
+            block.add_record(Record::new(data.to_string(), &author_key));
+        }
+        mine(&mut block);
+        chain.blocks.push(block.clone());
+        assert!(chain.validate_chain());
+
+        // [a, b, c, c] has the same Merkle root and so the same proof of work
+        let mut duplicated = block.clone();
+        duplicated.records.push(block.records[2].clone());
+        assert_eq!(merkle_root(&duplicated.records, &Sha256Pow), merkle_root(&block.records, &Sha256Pow));
+        assert_eq!(duplicated.hash(&Sha256Pow), block.hash(&Sha256Pow));
+        chain.blocks[1] = duplicated;
+        assert!(!chain.validate_chain());
+
+        // Records out of their places are rejected as well
+        let mut swapped = block.clone();
+        swapped.records.swap(0, 1);
+        mine(&mut swapped);
+        chain.blocks[1] = swapped;
+        assert!(!chain.validate_chain());
+    }
+
+    #[test]
+    fn test_tampered_records_invalidate_pow() {
+        use super::block::Record;
+        use super::pow::get_token_from_block;
+        use super::pow_algorithm::Sha256Pow;
+
+        let author_key = libp2p::identity::Keypair::generate_ed25519();
+        let mut block = Block::new(
+            2,
+            "98380a2f3ae31f193f7e906b29bcbda6e36f6d5315ba77f8e27b37ba3dddf546".parse().unwrap(),
+            0,
+            Vec::new(),
+            "".to_string(),
+            Vec::new(),
+            easy_target(),
+        );
+        block.add_record(Record::new("original".to_string(), &author_key));
+        mine(&mut block);
+        let token = get_token_from_block(&block, &Sha256Pow).unwrap();
+
+        block.records[0].data = "forged".to_string();
+        assert_ne!(Some(token), get_token_from_block(&block, &Sha256Pow));
+    }
+
+    #[test]
+    fn test_record_receipt() {
+        use super::block::Record;
+        use super::chain::Chain;
+        use super::hash::BlockHash;
+        use super::receipt::RecordReceipt;
+
+        let author_key = libp2p::identity::Keypair::generate_ed25519();
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        for block_idx in 2..=4 {
+            let mut block = Block::new(
+                block_idx,
+                chain.get_last_block().unwrap().hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                easy_target(),
+            );
+            for data in ["a", "b", "c", "d", "e"] {
+                block.add_record(Record::new(data.to_string(), &author_key));
+            }
+            mine(&mut block);
+            chain.blocks.push(block);
+        }
+
+        for record_idx in 1..=5 {
+            let receipt = chain.record_receipt(2, record_idx).unwrap();
+            assert_eq!(receipt.header_chain.len(), 2);
+            assert_eq!(receipt.verify(), Ok(chain.get_last_block().unwrap().hash(&Sha256Pow)));
+        }
+        assert!(chain.record_receipt(2, 6).is_none());
+        assert!(chain.record_receipt(5, 1).is_none());
+
+        let mut forged_record = chain.record_receipt(3, 2).unwrap();
+        forged_record.record.data = "forged".to_string();
+        assert!(forged_record.verify().is_err());
+
+        let mut broken_link = chain.record_receipt(2, 4).unwrap();
+        broken_link.header_chain.remove(0);
+        assert!(broken_link.verify().is_err());
+
+        let mut forged_proof = chain.record_receipt(2, 1).unwrap();
+        forged_proof.proof.path[0].sibling = BlockHash::zero();
+        assert!(forged_proof.verify().is_err());
+
+        // Proof hashes are written as hex, like every other hash of the receipt
+        let receipt = chain.record_receipt(2, 1).unwrap();
+        let json = serde_json::to_value(&receipt).unwrap();
+        assert_eq!(json["proof"]["path"][0]["sibling"], receipt.proof.path[0].sibling.to_string());
+        assert_eq!(json["block_header"]["merkle_root"], receipt.block_header.merkle_root.to_string());
+        assert_eq!(serde_json::from_value::<RecordReceipt>(json).unwrap(), receipt);
+    }
+
+    #[test]
+    fn test_record_signatures() {
+        use super::block::Record;
+        use super::chain::Chain;
+        use libp2p::identity::Keypair;
+
+        let author_key = Keypair::generate_ed25519();
+        let record = Record::new("data".to_string(), &author_key);
+        assert!(record.has_valid_signature());
+
+        let mut forged_data = record.clone();
+        forged_data.data = "forged".to_string();
+        assert!(!forged_data.has_valid_signature());
+
+        // Claiming somebody else's authorship with own key and signature
+        let mut impersonation = Record::new("data".to_string(), &Keypair::generate_ed25519());
+        impersonation.author_peer_id = record.author_peer_id.clone();
+        assert!(!impersonation.has_valid_signature());
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        let mut block = Block::new(
+            2,
+            chain.get_last_block().unwrap().hash(&Sha256Pow),
+            0,
+            Vec::new(),
+            "".to_string(),
+            Vec::new(),
+            easy_target(),
+        );
+        block.add_record(record);
+        mine(&mut block);
+        chain.blocks.push(block.clone());
+        assert!(chain.validate_chain());
+
+        // A signed record cannot be included again, neither in a later block nor twice in
+        // the same one
+        let mut replay = Block::new(
+            3,
+            block.hash(&Sha256Pow),
+            0,
+            Vec::new(),
+            "".to_string(),
+            Vec::new(),
+            easy_target(),
+        );
+        replay.add_record(block.records[0].clone());
+        mine(&mut replay);
+        assert!(Chain::store_contains_record(&chain, &replay.records[0]));
+        assert!(!Chain::validate_block_in_store(&replay, &chain));
+        chain.blocks.push(replay);
+        assert!(!chain.validate_chain());
+        chain.blocks.pop();
+        let mut repeated = block.clone();
+        repeated.add_record(block.records[0].clone());
+        mine(&mut repeated);
+        chain.blocks[1] = repeated;
+        assert!(!chain.validate_chain());
+
+        // Valid proof of work, but over a record with a forged author
+        block.records[0] = impersonation;
+        mine(&mut block);
+        chain.blocks[1] = block;
+        assert!(!chain.validate_chain());
+    }
+
+    #[test]
+    fn test_self_declared_difficulty_is_rejected() {
+        use super::chain::Chain;
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        assert_eq!(chain.expected_difficulty(2), Some(easy_target()));
+
+        let mut block = Block::new(
+            2,
+            chain.get_last_block().unwrap().hash(&Sha256Pow),
+            0,
+            Vec::new(),
+            "".to_string(),
+            Vec::new(),
+            Target::max(),
+        );
+        mine(&mut block);
+        chain.blocks.push(block);
+        assert!(!chain.validate_chain());
+
+        chain.blocks[1].difficulty = easy_target();
+        mine(&mut chain.blocks[1]);
+        assert!(chain.validate_chain());
+        assert_eq!(chain.expected_difficulty(3), Some(easy_target()));
+    }
+
+    #[test]
+    fn test_pow_algorithm_from_genesis() {
+        use super::block::Record;
+        use super::chain::Chain;
+        use super::merkle::merkle_root;
+        use super::pow_algorithm::{PowAlgorithm, PowAlgorithmKind, Sha3Pow};
+
+        let mut params = easy_params();
+        params.pow_algorithm = PowAlgorithmKind::Sha3;
+        let mut chain = Chain::new(0);
+        chain.init_first_block(params);
+        let genesis_hash = chain.blocks[0].hash(&Sha3Pow);
+        // Genesis blocks of chains with other algorithms differ
+        assert_ne!(genesis_hash, Block::genesis(easy_params()).hash(&Sha256Pow));
+        // Not only the proof of work, every hash of the chain is made with its algorithm
+        assert_ne!(genesis_hash, chain.blocks[0].hash(&Sha256Pow));
+
+        let mut block = Block::new(
+            2,
+            chain.blocks[0].hash(&Sha256Pow),
+            0,
+            Vec::new(),
+            "".to_string(),
+            Vec::new(),
+            easy_target(),
+        );
+        let author_key = libp2p::identity::Keypair::generate_ed25519();
+        block.add_record(Record::new("a".to_string(), &author_key));
+        assert_ne!(merkle_root(&block.records, &Sha3Pow), merkle_root(&block.records, &Sha256Pow));
+        mine_with(&mut block, &Sha3Pow);
+        chain.blocks.push(block.clone());
+        // The SHA-256 hash of the genesis block is not its hash in this chain
+        assert!(!chain.validate_chain());
+
+        block.previous_block_hash = genesis_hash;
+        mine(&mut block);
+        chain.blocks[1] = block.clone();
+        // Nonce found for SHA-256 is (almost certainly) not valid for SHA3-256
+        assert!(!chain.validate_chain());
+
+        mine_with(&mut block, &Sha3Pow);
+        chain.blocks[1] = block;
+        assert!(chain.validate_chain());
+        assert_eq!(PowAlgorithmKind::from_name(Sha3Pow.name()), Some(PowAlgorithmKind::Sha3));
+    }
+
+    #[test]
+    fn test_retarget() {
+        use super::difficulty::retarget;
+
+        let difficulty = easy_target();
+        assert_eq!(retarget(&difficulty, 100, 100), difficulty);
+
+        // Blocks mined twice as slow as expected double the target
+        let mut doubled = EASY_DIFFICULTY;
+        doubled[1..8].copy_from_slice(&[96, 161, 217, 206, 29, 94, 200]);
+        assert_eq!(retarget(&difficulty, 200, 100), Target::from_bytes(&doubled));
+        assert_eq!(retarget(&difficulty, 200, 100).work() * 2, difficulty.work());
+
+        // Adjustment is clamped to a factor of 4 in both directions
+        assert_eq!(retarget(&difficulty, 1000, 100), retarget(&difficulty, 400, 100));
+        assert_eq!(retarget(&difficulty, 0, 100), retarget(&difficulty, 25, 100));
+        assert!(retarget(&difficulty, 25, 100) < difficulty);
+
+        // Target never exceeds the largest value of the hash output
+        assert_eq!(retarget(&Target::max(), 400, 100), Target::max());
+        // Timespans at the end of the range saturate instead of overflowing
+        assert_eq!(retarget(&difficulty, u64::MAX, u64::MAX), difficulty);
+        assert!(retarget(&difficulty, 0, u64::MAX) < difficulty);
+    }
+
+    #[test]
+    fn test_genesis_params_out_of_bounds() {
+        use super::chain::Chain;
+        use super::difficulty::{expected_difficulty, ChainParams, MAX_RETARGET_INTERVAL};
+
+        assert!(easy_params().check_bounds().is_ok());
+        let out_of_bounds = [
+            ChainParams { initial_difficulty: Target::zero(), ..easy_params() },
+            ChainParams { target_block_time: 0, ..easy_params() },
+            ChainParams { target_block_time: u64::MAX, ..easy_params() },
+            ChainParams { retarget_interval: 0, ..easy_params() },
+            ChainParams { retarget_interval: MAX_RETARGET_INTERVAL + 1, ..easy_params() },
+        ];
+        for params in out_of_bounds {
+            assert!(params.check_bounds().is_err(), "{:?}", params);
+            // Such a genesis is not accepted from a peer
+            let mut chain = Chain::new(0);
+            chain.blocks.push(Block::genesis(params.clone()));
+            assert!(!chain.blocks[0].is_genesis());
+            assert!(!chain.validate_chain());
+
+            // The difficulty of the following blocks still does not panic
+            let genesis = Block::genesis(params);
+            let block = Block { idx: 2, ..genesis.clone() };
+            let get_block = |idx| match idx {
+                1 => Some(genesis.clone()),
+                _ => Some(block.clone()),
+            };
+            for block_idx in [2, 3, 4, u64::MAX] {
+                expected_difficulty(block_idx, get_block);
+            }
+        }
+    }
+
+    #[test]
+    fn test_target_encodings() {
+        use rug::Integer;
+
+        let target = easy_target();
+        assert_eq!(target.to_bytes(), EASY_DIFFICULTY);
+        assert_eq!(target.to_compact(), 0x1f30_50ec);
+        // Compact encoding keeps the three most significant bytes only
+        assert_eq!(Target::from_compact(0x1f30_50ec).to_bytes()[..4], EASY_DIFFICULTY[..4]);
+        assert!(Target::from_compact(0x1f30_50ec) <= target);
+        assert_eq!(Target::from_compact(0x1d00_ffff).to_compact(), 0x1d00_ffff);
+        // Mantissa with the highest bit set moves one byte up
+        assert_eq!(Target::from_bytes(&[0x80]).to_compact(), 0x0200_8000);
+        assert_eq!(Target::from_compact(0x0200_8000), Target::from_bytes(&[0x80]));
+
+        // Work and expected hashes are inverse of each other
+        let hashes = Integer::from(1_000_000);
+        assert_eq!(Target::from_expected_hashes(&hashes).work(), hashes);
+        assert_eq!(Target::max().work(), 1);
+        assert_eq!(Target::from_integer(Integer::from(-1)), Target::zero());
+
+        let mut token = EASY_DIFFICULTY;
+        assert!(!target.is_met_by(&token));
+        token[31] = 0xff;
+        token[8] = 0;
+        token[7] -= 1;
+        assert!(target.is_met_by(&token));
+
+        // Difficulties shorter than 32 bytes used to be compared with tokens byte by byte
+        let legacy: Target = serde_json::from_str("[0, 48, 80, 236, 231, 14, 175, 100]").unwrap();
+        assert_eq!(legacy, target);
+        assert_eq!(serde_json::to_string(&target).unwrap(),
+            serde_json::to_string(&EASY_DIFFICULTY).unwrap());
+    }
+
+    #[test]
+    fn test_difficulty_follows_block_times() {
+        use super::chain::Chain;
+
+        let params = easy_params();
+        let mut chain = Chain::new(0);
+        chain.init_first_block(params.clone());
+        // Blocks are mined 20 seconds apart while 10 seconds are expected
+        let start = chrono::Utc::now().timestamp() as u64 - 1000;
+        for block_idx in 2..=(2 + params.retarget_interval) {
+            let mut block = Block::new(
+                block_idx,
+                chain.get_last_block().unwrap().hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                chain.expected_difficulty(block_idx).unwrap(),
+            );
+            block.timestamp = start + block_idx * 2 * params.target_block_time;
+            mine(&mut block);
+            chain.blocks.push(block);
+        }
+        assert!(chain.validate_chain());
+
+        let last_block = chain.get_last_block().unwrap();
+        assert_eq!(last_block.difficulty, super::difficulty::retarget(&easy_target(), 40, 20));
+        assert!(last_block.difficulty > easy_target());
+    }
+
+    #[test]
+    fn test_parallel_mining() {
+        use super::{block::Record, chain::Chain};
+        use super::pow::{prove_the_work, MinerInputs, MiningOutcome, MiningState};
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let mut pending_records = vec![Record::new("mined in parallel".to_string(), &keypair)];
+        let (_new_last_block_tx, new_last_block_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_new_record_tx, new_record_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_mining_control_tx, mining_control_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut inputs = MinerInputs { new_last_block_rx, new_record_rx, mining_control_rx };
+
+        let block = match prove_the_work(4,
+            "miner",
+            chain.get_last_block().unwrap(),
+            &mut pending_records,
+            &mut MiningState::Mining { blocks_left: None },
+            &mut inputs,
+            &chain)
+        {
+            MiningOutcome::Mined(block) => *block,
+            _ => panic!("Block is mined"),
+        };
+        assert!(pending_records.is_empty());
+        assert_eq!(block.records.len(), 1);
+        assert_eq!(block.difficulty, easy_target());
+        assert_eq!(block.miner_peer_id.as_deref(), Some("miner"));
+
+        chain.blocks.push(block);
+        assert!(chain.validate_chain());
+    }
+
+    #[test]
+    fn test_miner_reacts_immediately() {
+        use super::{block::Record, store::MemoryBlockStore};
+        use super::pow::{prove_the_work, MinerInputs, MiningCommand, MiningOutcome, MiningState};
+        use std::time::{Duration, Instant};
+
+        // No token is smaller than zero, so the workers only stop when they are told to
+        let mut params = easy_params();
+        params.initial_difficulty = Target::zero();
+        let genesis = Block::genesis(params);
+        let store = MemoryBlockStore::from(vec![genesis.clone()]);
+
+        let (_new_last_block_tx, new_last_block_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (new_record_tx, new_record_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (mining_control_tx, mining_control_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut inputs = MinerInputs { new_last_block_rx, new_record_rx, mining_control_rx };
+        let miner = std::thread::spawn(move || {
+            let mut pending_records = Vec::new();
+            let mut state = MiningState::Mining { blocks_left: None };
+            let outcome = prove_the_work(2,
+                "miner",
+                &genesis,
+                &mut pending_records,
+                &mut state,
+                &mut inputs,
+                &store);
+            (outcome, pending_records, state)
+        });
+
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        new_record_tx.send(Record::new("never mined".to_string(), &keypair)).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let stopped_at = Instant::now();
+        mining_control_tx.send(MiningCommand::Stop).unwrap();
+        let (outcome, pending_records, state) = miner.join().unwrap();
+        assert!(stopped_at.elapsed() < Duration::from_millis(500));
+        assert!(matches!(outcome, MiningOutcome::Stopped));
+        assert_eq!(state, MiningState::Stopped);
+        // Records of the dropped block are kept for the next one
+        assert_eq!(pending_records.len(), 1);
+    }
+
+    #[test]
+    fn test_miner_after_losing_a_race() {
+        use super::{block::Record, chain::Chain};
+        use super::metadata::{ChainMetadata, DEFAULT_NETWORK_ID};
+        use super::pow::{spawn_miner, MinerInputs, MiningCommand, MiningState};
+        use super::store::{BlockReader, BlockStore, MemoryBlockStore};
+        use super::storage::spawn_storage;
+        use std::time::Duration;
+
+        let genesis = Block::genesis(easy_params());
+        let mut store = MemoryBlockStore::from(vec![genesis.clone()]);
+        store.set_metadata(&ChainMetadata::new(std::slice::from_ref(&genesis), 0, DEFAULT_NETWORK_ID).unwrap()).unwrap();
+        let (storage, chain) = spawn_storage(store, DEFAULT_NETWORK_ID);
+
+        let (new_mined_block_tx, mut new_mined_block_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (new_last_block_tx, new_last_block_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (new_record_tx, new_record_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (mining_control_tx, mining_control_rx) = tokio::sync::mpsc::unbounded_channel();
+        let inputs = MinerInputs { new_last_block_rx, new_record_rx, mining_control_rx };
+        let miner = spawn_miner(new_mined_block_tx, inputs, MiningState::Stopped, 1,
+            "miner".to_string(), storage.clone(), chain.clone());
+
+        // A block of a peer is stored, but the miner does not know about it yet
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let record = Record::new("mined by both".to_string(), &keypair);
+        let peer_block = |previous: &Block, records: &[Record]| {
+            let mut block = Block::new(previous.idx + 1, previous.hash(&Sha256Pow), 0, Vec::new(),
+                "".to_string(), Vec::new(), easy_target());
+            block.add_record_by_data("mined by the peer".to_string(), &keypair);
+            records.iter().for_each(|record| block.add_record(record.clone()));
+            mine(&mut block);
+            block
+        };
+        let first_peer_block = peer_block(&genesis, std::slice::from_ref(&record));
+        storage.append(first_peer_block.clone()).unwrap();
+
+        // The miner mines block 2 as well and its block is rejected by the store. The record is
+        // at another place in the block of the peer, but it is not mined again.
+        new_record_tx.send(record).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        mining_control_tx.send(MiningCommand::MineBlocks(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(chain.tip().as_ref(), Some(&first_peer_block));
+        new_last_block_tx.send(first_peer_block).unwrap();
+        let block = new_mined_block_rx.blocking_recv().unwrap();
+        assert_eq!(block.idx, 3);
+        assert!(block.records.is_empty());
+        assert_eq!(chain.tip().as_ref(), Some(&block));
+        assert!(Chain::load_from_store(&chain.snapshot()).unwrap().validate_chain());
+
+        // Commands given while the miner waits for the block of the peer are handled right away
+        storage.append(peer_block(&block, &[])).unwrap();
+        mining_control_tx.send(MiningCommand::MineBlocks(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        drop(mining_control_tx);
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(miner.is_finished());
+        assert!(new_mined_block_rx.try_recv().is_err());
+        drop(new_last_block_tx);
+        miner.join().unwrap();
+    }
+
+    #[test]
+    fn test_chain_stats() {
+        use super::{block::Record, stats::chain_stats};
+
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let mut blocks = vec![Block::genesis(easy_params())];
+        // Block times 10, 20, 60 and 30 seconds
+        for (idx, timestamp) in [(2, 1000), (3, 1010), (4, 1030), (5, 1090), (6, 1120)] {
+            let mut block = Block::new(idx, Default::default(), 0, Vec::new(), "".to_string(),
+                Vec::new(), easy_target());
+            block.timestamp = timestamp;
+            block.miner_peer_id = if idx % 2 == 0 { Some("even".to_string()) } else { None };
+            (0..idx).for_each(|_| block.add_record(Record::new("data".to_string(), &keypair)));
+            blocks.push(block);
+        }
+
+        let stats = chain_stats(&blocks, &[2, 100]);
+        assert_eq!(stats.num_blocks, 6);
+        assert_eq!(stats.blocks[1].block_time, None);
+        assert_eq!(stats.blocks[3].block_time, Some(20));
+
+        assert_eq!(stats.windows[0].blocks, 2);
+        assert_eq!(stats.windows[0].average_block_time, Some(45.0));
+        assert_eq!(stats.windows[0].median_block_time, Some(45.0));
+        assert_eq!(stats.windows[0].hashrate, Some(2.0 * easy_target().work().to_f64() / 90.0));
+        assert_eq!(stats.windows[1].blocks, 5);
+        assert_eq!(stats.windows[1].average_block_time, Some(30.0));
+        assert_eq!(stats.windows[1].median_block_time, Some(25.0));
+
+        assert_eq!(stats.average_records_per_block, Some(4.0));
+        assert_eq!(stats.blocks_per_miner.get("even"), Some(&3));
+        assert_eq!(stats.blocks_per_miner.get("unknown"), Some(&2));
+        assert!(stats.blocks.iter().skip(1).all(|block| block.difficulty_bits == easy_target().to_compact()));
+
+        let csv = stats.to_csv();
+        assert_eq!(csv.lines().count(), 7);
+        assert!(csv.lines().nth(4).unwrap().starts_with("4,1030,20,"));
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+        assert_eq!(json["windows"][1]["median_block_time"], 25.0);
+        assert!(stats.to_table().contains("Blocks per miner"));
+    }
+
+    #[test]
+    fn test_mining_commands() {
+        use super::pow::{MiningCommand, MiningState};
+
+        let mine_two = MiningState::Stopped.apply(MiningCommand::MineBlocks(2));
+        assert_eq!(mine_two, MiningState::Mining { blocks_left: Some(2) });
+
+        // Resuming keeps the number of blocks left
+        let paused = mine_two.block_mined().apply(MiningCommand::Pause);
+        assert_eq!(paused, MiningState::Paused { blocks_left: Some(1) });
+        let resumed = paused.apply(MiningCommand::Start);
+        assert_eq!(resumed, MiningState::Mining { blocks_left: Some(1) });
+        assert_eq!(resumed.block_mined(), MiningState::Stopped);
+
+        assert_eq!(MiningState::Stopped.apply(MiningCommand::Pause), MiningState::Stopped);
+        assert_eq!(paused.apply(MiningCommand::Stop), MiningState::Stopped);
+        assert_eq!(MiningState::Stopped.apply(MiningCommand::MineBlocks(0)), MiningState::Stopped);
+        assert_eq!(MiningState::Mining { blocks_left: None }.block_mined(),
+            MiningState::Mining { blocks_left: None });
+    }
+
+    #[test]
+    fn test_heaviest_chain_wins() {
+        use super::chain::{Chain, ChainType, find_heaviest_chain};
+
+        let mine_chain = |initial_difficulty: Target, num_blocks: u64| {
+            let mut params = easy_params();
+            params.initial_difficulty = initial_difficulty;
+            let mut chain = Chain::new(0);
+            chain.init_first_block(params);
+            for block_idx in 2..(2 + num_blocks) {
+                let mut block = Block::new(
+                    block_idx,
+                    chain.get_last_block().unwrap().hash(&Sha256Pow),
+                    0,
+                    Vec::new(),
+                    "".to_string(),
+                    Vec::new(),
+                    chain.expected_difficulty(block_idx).unwrap(),
+                );
+                mine(&mut block);
+                chain.blocks.push(block);
+            }
+            chain
+        };
+
+        let hard_difficulty = easy_target().scale(1, 256);
+        let long_easy_chain = mine_chain(easy_target(), 2);
+        let short_hard_chain = mine_chain(hard_difficulty, 1);
+        assert!(long_easy_chain.blocks.len() > short_hard_chain.blocks.len());
+
+        let choice = find_heaviest_chain(&long_easy_chain, &short_hard_chain);
+        assert_eq!(choice.chosen_chain_type, ChainType::Remote);
+        assert_eq!(choice.work_difference,
+            short_hard_chain.cumulative_work() - long_easy_chain.cumulative_work());
+        assert!(choice.work_difference > 0);
+
+        let choice = find_heaviest_chain(&short_hard_chain, &long_easy_chain);
+        assert_eq!(choice.chosen_chain_type, ChainType::Local);
+        assert!(choice.work_difference < 0);
+    }
+
+    #[test]
+    fn test_block_stores() {
+        use super::chain::Chain;
+        use super::metadata::{ChainMetadata, DEFAULT_NETWORK_ID};
+        use super::store::{BlockStore, FileBlockStore, MemoryBlockStore};
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        for _ in 0..3 {
+            let mut block = Block::new(chain.blocks.len() as u64 + 1,
+                chain.get_last_block().unwrap().hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                easy_target());
+            mine(&mut block);
+            chain.blocks.push(block);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        // The block file and its index are created with the first block
+        let filepath = dir.path().join("chain.jsonl");
+        let mut stores: Vec<Box<dyn BlockStore>> = vec![
+            Box::new(MemoryBlockStore::new()),
+            Box::new(FileBlockStore::new(filepath.to_str().unwrap())),
+        ];
+        let metadata = ChainMetadata::new(&chain.blocks, chain.num_sidelinks, DEFAULT_NETWORK_ID).unwrap();
+        for store in stores.iter_mut() {
+            assert!(store.is_empty());
+            assert_eq!(store.tip(), None);
+            store.set_metadata(&metadata).unwrap();
+            for block in &chain.blocks {
+                // Mining and validation only need the store
+                assert!(Chain::validate_block_in_store(block, store.as_ref()));
+                store.append(block).unwrap();
+            }
+
+            assert_eq!(store.len(), 4);
+            assert_eq!(store.get(0), None);
+            assert_eq!(store.get(2).as_ref(), chain.blocks.get(1));
+            assert_eq!(store.get(5), None);
+            assert_eq!(store.range(2, 4).unwrap(), chain.blocks[1..]);
+            assert_eq!(store.range(2, 5), None);
+            assert_eq!(store.get_many(&[4, 1]).unwrap(), vec![chain.blocks[3].clone(), chain.blocks[0].clone()]);
+            assert_eq!(store.last_n(2).unwrap(), chain.blocks[2..]);
+            assert_eq!(store.chain_params(), chain.chain_params());
+
+            store.truncate(2).unwrap();
+            assert_eq!(store.len(), 2);
+            assert_eq!(store.tip().as_ref(), chain.blocks.get(1));
+            // A block cannot be validated without its parent
+            assert!(!Chain::validate_block_in_store(&chain.blocks[3], store.as_ref()));
+        }
+    }
+
+    #[test]
+    fn test_record_index() {
+        use super::block::Record;
+        use super::chain::Chain;
+        use super::metadata::{ChainMetadata, DEFAULT_NETWORK_ID};
+        use super::store::{BlockReader, BlockStore, FileBlockStore, MemoryBlockStore};
+
+        let author_key = libp2p::identity::Keypair::generate_ed25519();
+        let records: Vec<Record> = ["a", "b", "c"].iter()
+            .map(|data| Record::new(data.to_string(), &author_key))
+            .collect();
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        let new_block = |chain: &Chain, records: &[Record]| {
+            let mut block = Block::new(chain.blocks.len() as u64 + 1,
+                chain.get_last_block().unwrap().hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                easy_target());
+            records.iter().for_each(|record| block.add_record(record.clone()));
+            mine(&mut block);
+            block
+        };
+        chain.blocks.push(new_block(&chain, &records[..2]));
+        // The record is numbered by its new place, but its content is in the chain already
+        let repeated = new_block(&chain, &records[1..]);
+        let next = new_block(&chain, &records[2..]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("chain.jsonl");
+        let filepath = filepath.to_str().unwrap();
+        let metadata = ChainMetadata::new(&chain.blocks, chain.num_sidelinks, DEFAULT_NETWORK_ID).unwrap();
+        let mut stores: Vec<Box<dyn BlockStore>> = vec![
+            Box::new(MemoryBlockStore::new()),
+            Box::new(FileBlockStore::new(filepath)),
+        ];
+        for store in stores.iter_mut() {
+            store.set_metadata(&metadata).unwrap();
+            store.replace(&chain.blocks[..1]).unwrap();
+            store.append(&chain.blocks[1]).unwrap();
+            assert_eq!(store.record_height(&records[0]), Some(2));
+            assert_eq!(store.record_height(&repeated.records[0]), Some(2));
+            assert_eq!(store.record_height(&records[2]), None);
+            assert!(Chain::store_contains_record(store.as_ref(), &records[1]));
+
+            assert!(!Chain::validate_block_in_store(&repeated, store.as_ref()));
+            assert!(Chain::validate_block_in_store(&next, store.as_ref()));
+            store.append(&next).unwrap();
+            assert_eq!(store.record_height(&records[2]), Some(3));
+
+            store.truncate(2).unwrap();
+            assert_eq!(store.record_height(&records[2]), None);
+            store.replace(&chain.blocks[..1]).unwrap();
+            assert_eq!(store.record_height(&records[0]), None);
+            store.append(&chain.blocks[1]).unwrap();
+        }
+
+        // A store opened later builds the index from the block file, as does a chain scan
+        assert_eq!(FileBlockStore::new(filepath).record_height(&records[1]), Some(2));
+        assert_eq!(chain.record_height(&records[1]), Some(2));
+        assert_eq!(chain.record_height(&records[2]), None);
+    }
+
+    #[test]
+    fn test_storage_rejects_conflicting_writes() {
+        use super::chain::Chain;
+        use super::metadata::DEFAULT_NETWORK_ID;
+        use super::store::{BlockReader, MemoryBlockStore};
+        use super::storage::{spawn_storage, StorageError};
+
+        let genesis = Block::genesis(easy_params());
+        let (storage, chain) = spawn_storage(MemoryBlockStore::from(vec![genesis.clone()]), DEFAULT_NETWORK_ID);
+        let next_block = |previous: &Block, miner: &str| {
+            let mut block = Block::new(previous.idx + 1,
+                previous.hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                easy_target());
+            block.miner_peer_id = Some(miner.to_string());
+            block
+        };
+
+        // A mined block and a block of a peer at the same height: only the first one is added
+        let mined_block = next_block(&genesis, "local");
+        let peer_block = next_block(&genesis, "peer");
+        storage.append(mined_block.clone()).unwrap();
+        assert!(matches!(storage.append(peer_block.clone()), Err(StorageError::Conflict(_))));
+        assert!(matches!(storage.append(next_block(&peer_block, "peer")), Err(StorageError::Conflict(_))));
+        assert_eq!(chain.tip(), Some(mined_block.clone()));
+
+        // A chain is only replaced if the local chain is still the one it was compared with
+        let remote_chain = Chain {
+            blocks: vec![genesis.clone(), peer_block.clone(), next_block(&peer_block, "peer")],
+            num_sidelinks: 1,
+        };
+        assert!(matches!(storage.replace(remote_chain.clone(), Some(genesis.hash(&Sha256Pow))),
+            Err(StorageError::Conflict(_))));
+        assert!(matches!(storage.replace(remote_chain.clone(), None), Err(StorageError::Conflict(_))));
+        storage.replace(remote_chain.clone(), Some(mined_block.hash(&Sha256Pow))).unwrap();
+        assert_eq!(chain.snapshot().range(1, 3), Some(remote_chain.blocks));
+        // The metadata is replaced with the chain
+        assert_eq!(chain.metadata().map(|metadata| metadata.num_sidelinks), Some(1));
+
+        assert!(matches!(storage.remove_tip(mined_block.hash(&Sha256Pow)), Err(StorageError::Conflict(_))));
+        storage.remove_tip(chain.tip().unwrap().hash(&Sha256Pow)).unwrap();
+        assert_eq!(chain.tip(), Some(peer_block));
+    }
+
+    #[test]
+    fn test_block_index() {
+        use super::chain::Chain;
+        use super::store::{BlockReader, BlockStore, FileBlockStore};
+        use std::io::Write;
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        for _ in 0..4 {
+            let mut block = Block::new(chain.blocks.len() as u64 + 1,
+                chain.get_last_block().unwrap().hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                easy_target());
+            mine(&mut block);
+            chain.blocks.push(block);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("chain.jsonl");
+        let filepath = filepath.to_str().unwrap();
+        let index_path = format!("{}.idx", filepath);
+        let (first_blocks, last_block) = (Chain { blocks: chain.blocks[..4].to_vec(), num_sidelinks: 0 },
+            &chain.blocks[4]);
+        first_blocks.save_blockchain_to_file(filepath).unwrap();
+        // Header and one offset per block
+        assert_eq!(std::fs::metadata(&index_path).unwrap().len(), 48 + 4 * 8);
+
+        let store = FileBlockStore::new(filepath);
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get(3).as_ref(), chain.blocks.get(2));
+
+        // A block appended without the store makes the index stale, so it is rebuilt once the
+        // chain is opened again. The open store checks the index only once.
+        let mut file = std::fs::OpenOptions::new().append(true).open(filepath).unwrap();
+        file.write_all(format!("{}\n", serde_json::to_string(last_block).unwrap()).as_bytes()).unwrap();
+        assert_eq!(store.len(), 4);
+        let mut store = FileBlockStore::new(filepath);
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.tip().as_ref(), Some(last_block));
+
+        // So is a missing one
+        std::fs::remove_file(&index_path).unwrap();
+        store.check_index().unwrap();
+        assert_eq!(store.range(1, 5).unwrap(), chain.blocks);
+
+        store.truncate(2).unwrap();
+        assert_eq!(std::fs::metadata(&index_path).unwrap().len(), 48 + 2 * 8);
+        store.append(&chain.blocks[2]).unwrap();
+        assert_eq!(Chain::load_from_file(filepath).unwrap().blocks, chain.blocks[..3]);
+        assert_eq!(store.get(3).as_ref(), chain.blocks.get(2));
+        assert!(!store.check_index().unwrap());
+
+        // A last block rewritten without the store keeps the length of the file, but not the
+        // hash of the last entry
+        let mut content = std::fs::read(filepath).unwrap();
+        let last_line_start = content[..content.len() - 1].iter().rposition(|&byte| byte == b'\n').unwrap() + 1;
+        let digit = last_line_start + content[last_line_start..].iter().position(u8::is_ascii_digit).unwrap();
+        content[digit] = if content[digit] == b'9' { b'0' } else { content[digit] + 1 };
+        std::fs::write(filepath, &content).unwrap();
+        assert!(store.check_index().unwrap());
+        store.truncate(0).unwrap();
+        assert_eq!(store.len(), 0);
+        assert!(!store.check_index().unwrap());
+    }
+
+    #[test]
+    fn test_block_store_recovery() {
+        use super::chain::Chain;
+        use super::store::{BlockReader, FileBlockStore, RecoveryReport};
+        use std::io::Write;
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        for _ in 0..2 {
+            let mut block = Block::new(chain.blocks.len() as u64 + 1,
+                chain.get_last_block().unwrap().hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                easy_target());
+            mine(&mut block);
+            chain.blocks.push(block);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("chain.jsonl");
+        let filepath = filepath.to_str().unwrap();
+        chain.save_blockchain_to_file(filepath).unwrap();
+        // Rewrites do not leave their temporary file behind
+        assert!(!std::path::Path::new(&format!("{}.tmp", filepath)).exists());
+        let store = FileBlockStore::new(filepath);
+        assert!(store.recover().unwrap().is_clean());
+
+        // Crash in the middle of an append
+        let block_string = serde_json::to_string(&chain.blocks[2]).unwrap();
+        let mut file = std::fs::OpenOptions::new().append(true).open(filepath).unwrap();
+        file.write_all(&block_string.as_bytes()[..10]).unwrap();
+        // Crash in the middle of a rewrite
+        std::fs::write(format!("{}.tmp", filepath), "{\"idx\"").unwrap();
+        // The index was not updated yet, so it matches the repaired file
+        assert_eq!(store.recover().unwrap(), RecoveryReport {
+            trimmed_bytes: 10,
+            removed_temp_file: true,
+            index_rebuilt: false,
+        });
+        assert_eq!(Chain::load_from_file(filepath).unwrap().blocks, chain.blocks);
+        assert_eq!(store.tip().as_ref(), chain.blocks.last());
+
+        // A last line which was extended, but never filled, is not a block either
+        file.write_all(b"\0\0\0\0\n").unwrap();
+        let report = store.recover().unwrap();
+        assert_eq!(report.trimmed_bytes, 5);
+        assert_eq!(store.len(), 3);
+        assert!(store.recover().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_chain_metadata() {
+        use super::chain::Chain;
+        use super::metadata::{ChainMetadata, CHAIN_FORMAT_VERSION, DEFAULT_NETWORK_ID};
+        use super::store::{BlockReader, BlockStore, FileBlockStore};
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        for _ in 0..2 {
+            let mut block = Block::new(chain.blocks.len() as u64 + 1,
+                chain.get_last_block().unwrap().hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                easy_target());
+            mine(&mut block);
+            chain.blocks.push(block);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("chain.jsonl");
+        let filepath = filepath.to_str().unwrap();
+        chain.save_blockchain_to_file(filepath).unwrap();
+        let mut store = FileBlockStore::new(filepath);
+        let metadata = store.metadata().unwrap();
+        assert_eq!(metadata, ChainMetadata {
+            format_version: CHAIN_FORMAT_VERSION,
+            network_id: DEFAULT_NETWORK_ID.to_string(),
+            num_sidelinks: 0,
+            target_block_time: 10,
+            pow_algorithm: Default::default(),
+            genesis_hash: chain.blocks[0].hash(&Sha256Pow),
+        });
+        metadata.check(&store, DEFAULT_NETWORK_ID).unwrap();
+        // The sidelink count comes from the metadata, not from the default of the network
+        assert_eq!(Chain::load_from_file(filepath).unwrap().num_sidelinks, 0);
+        assert_eq!(Chain::load_from_store(&store).unwrap(), chain);
+
+        // Chains of other networks, other genesis blocks and newer formats are rejected
+        assert!(metadata.check(&store, "testnet").is_err());
+        let other_genesis = ChainMetadata { genesis_hash: chain.blocks[1].hash(&Sha256Pow), ..metadata.clone() };
+        assert!(other_genesis.check(&store, DEFAULT_NETWORK_ID).is_err());
+        let newer_format = ChainMetadata { format_version: CHAIN_FORMAT_VERSION + 1, ..metadata.clone() };
+        assert!(newer_format.check(&store, DEFAULT_NETWORK_ID).is_err());
+        let other_sidelinks = ChainMetadata { num_sidelinks: 2, ..metadata.clone() };
+        assert!(other_sidelinks.check(&store, DEFAULT_NETWORK_ID).is_err());
+        store.set_metadata(&other_sidelinks).unwrap();
+        assert_eq!(Chain::load_from_store(&store).unwrap().num_sidelinks, 2);
+
+        // A chain stored without metadata gets it from its blocks
+        std::fs::remove_file(format!("{}.meta", filepath)).unwrap();
+        assert_eq!(store.metadata(), None);
+        assert_eq!(ChainMetadata::infer(&store, 5, DEFAULT_NETWORK_ID), Some(metadata));
+    }
+
+    #[test]
+    fn test_mined_chain_keeps_its_sidelinks() {
+        use super::chain::Chain;
+        use super::metadata::{ChainMetadata, DEFAULT_NETWORK_ID};
+        use super::pow::{prove_the_work, MinerInputs, MiningOutcome, MiningState};
+        use super::store::{BlockReader, BlockStore, FileBlockStore};
+
+        // Chain initialized the way the init command does it, genesis has no sidelinks
+        let num_sidelinks = 5;
+        let mut chain = Chain::new(num_sidelinks);
+        chain.init_first_block(easy_params());
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("chain.jsonl");
+        let filepath = filepath.to_str().unwrap();
+        let mut store = FileBlockStore::new(filepath);
+        store.replace(&chain.blocks).unwrap();
+        store.set_metadata(&ChainMetadata::new(&chain.blocks, num_sidelinks, DEFAULT_NETWORK_ID).unwrap())
+            .unwrap();
+
+        let (_new_last_block_tx, new_last_block_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_new_record_tx, new_record_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_mining_control_tx, mining_control_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut inputs = MinerInputs { new_last_block_rx, new_record_rx, mining_control_rx };
+        // Past the block which is the first to link as many blocks as the chain requires
+        while store.len() < num_sidelinks as u64 + 3 {
+            let block = match prove_the_work(2,
+                "miner",
+                &store.tip().unwrap(),
+                &mut Vec::new(),
+                &mut MiningState::Mining { blocks_left: None },
+                &mut inputs,
+                &store)
+            {
+                MiningOutcome::Mined(block) => *block,
+                _ => panic!("Block is mined"),
+            };
+            assert_eq!(block.num_sidelinks, Chain::num_sidelinks_of_block(block.idx, num_sidelinks));
+            assert!(Chain::validate_block_in_store(&block, &store));
+            store.append(&block).unwrap();
+        }
+
+        // The reopened chain is accepted by the startup checks
+        let store = FileBlockStore::new(filepath);
+        store.metadata().unwrap().check(&store, DEFAULT_NETWORK_ID).unwrap();
+        let chain = Chain::load_from_store(&store).unwrap();
+        assert_eq!(chain.num_sidelinks, num_sidelinks);
+        assert_eq!(chain.get_last_block().unwrap().num_sidelinks, num_sidelinks);
+        assert!(chain.validate_chain());
+
+        // Blocks with fewer sidelinks than the chain requires are rejected
+        let mut fewer_sidelinks = chain.clone();
+        fewer_sidelinks.num_sidelinks = num_sidelinks - 1;
+        assert!(!fewer_sidelinks.validate_chain());
+        let mut chain = chain;
+        let mut block = chain.blocks.pop().unwrap();
+        block.num_sidelinks -= 1;
+        block.validation_sidelinks.pop();
+        mine(&mut block);
+        assert!(!Chain::validate_block_in_store(&block, &chain));
+    }
+
+    #[test]
+    fn test_block_versions() {
+        use super::block::BLOCK_VERSION;
+        use super::chain::Chain;
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        let mut block = Block::new(2,
+            chain.get_last_block().unwrap().hash(&Sha256Pow),
+            0,
+            Vec::new(),
+            "".to_string(),
+            Vec::new(),
+            easy_target());
+        mine(&mut block);
+        let serialized = serde_json::to_string(&block).unwrap();
+        assert!(serialized.starts_with(&format!("{{\"version\":{},", BLOCK_VERSION)));
+        assert_eq!(serde_json::from_str::<Block>(&serialized).unwrap(), block);
+
+        // Newer blocks cannot be read, blocks with a version must have the fields of their version
+        let newer_block = serialized.replacen(&format!("\"version\":{}", BLOCK_VERSION),
+            &format!("\"version\":{}", BLOCK_VERSION + 1), 1);
+        assert!(serde_json::from_str::<Block>(&newer_block).is_err());
+        let incomplete_block = serialized.replace("\"num_sidelinks\":0,", "");
+        assert!(serde_json::from_str::<Block>(&incomplete_block).is_err());
+
+        // Blocks without the version field are of version 0, which are read but never valid
+        let old_block: Block = serde_json::from_str(&serialized.replacen(&format!("\"version\":{},", BLOCK_VERSION), "", 1)
+            .replace("\"num_sidelinks\":0,", "")).unwrap();
+        assert_eq!(old_block, Block { version: 0, ..block.clone() });
+        chain.blocks.push(block);
+        assert!(chain.validate_chain());
+        chain.blocks[1] = old_block;
+        assert!(!chain.validate_chain());
+    }
+
+    #[test]
+    fn test_binary_encoding_round_trip() {
+        use super::block::{Record, BLOCK_VERSION};
+        use super::encoding::{from_bytes, to_bytes, BlockFormat};
+
+        let mut block = Block::new(2,
+            Block::genesis(easy_params()).hash(&Sha256Pow),
+            1,
+            vec![Block::genesis(easy_params()).hash(&Sha256Pow)],
+            "".to_string(),
+            Vec::new(),
+            easy_target());
+        block.add_record(Record::new("zażółć gęślą jaźń".to_string(), &libp2p::identity::Keypair::generate_ed25519()));
+        block.add_record(Record::new("".to_string(), &libp2p::identity::Keypair::generate_ed25519()));
+        block.miner_peer_id = Some("miner".to_string());
+        mine(&mut block);
+        let genesis = Block::genesis(super::difficulty::ChainParams {
+            initial_hashrate: Some(1000),
+            pow_algorithm: super::pow_algorithm::PowAlgorithmKind::Sha3,
+            ..easy_params()
+        });
+
+        for block in [block, genesis] {
+            let bytes = to_bytes(&block);
+            assert_eq!(from_bytes::<Block>(&bytes).unwrap(), block);
+            for format in [BlockFormat::Json, BlockFormat::Binary] {
+                assert_eq!(format.decode_block(&format.encode_block(&block).unwrap()).unwrap(), block);
+            }
+            // Truncated or extended encodings are not blocks
+            assert!(from_bytes::<Block>(&bytes[..bytes.len() - 1]).is_err());
+            assert!(from_bytes::<Block>(&[bytes.as_slice(), &[0]].concat()).is_err());
+        }
+
+        let newer_block = Block { version: BLOCK_VERSION + 1, ..Block::genesis(easy_params()) };
+        assert!(from_bytes::<Block>(&to_bytes(&newer_block)).is_err());
+    }
+
+    #[test]
+    fn test_binary_encoding_test_vectors() {
+        use super::block::Record;
+        use super::encoding::to_bytes;
+        use openssl::sha::sha256;
+
+        // Encoded header of a genesis block, field by field
+        let genesis = Block::genesis(easy_params());
+        let expected_header = concat!(
+            "0000000000000001",
+            "0000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000",
+            "0000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "01", "003050ece70eaf64000000000000000000000000000000000000000000000000",
+                "000000000000000a", "0000000000000003", "00", "00",
+            "00",
+            "0000000000000000",
+        );
+        assert_eq!(hex(&to_bytes(&genesis.header(&Sha256Pow))), expected_header);
+        assert_eq!(genesis.header(&Sha256Pow).pow_prefix(), to_bytes(&genesis.header(&Sha256Pow))[..expected_header.len() / 2 - 8]);
+        assert_eq!(genesis.hash(&Sha256Pow).as_bytes(), &sha256(&to_bytes(&genesis.header(&Sha256Pow))));
+        assert_eq!(genesis.hash(&Sha256Pow).to_string(),
+            "49d868e5dd5d496826038fe2b1910b302209a16a04fad76651d0a74d00c83a7a");
+
+        let record = Record {
+            idx: (2, 1),
+            timestamp: 1687109265,
+            data: "abc".to_string(),
+            author_peer_id: "peer".to_string(),
+            public_key: "key".to_string(),
+            signature: "sig".to_string(),
+        };
+        assert_eq!(hex(&to_bytes(&record)), concat!(
+            "0000000000000002", "0000000000000001",
+            "00000000648f3e91",
+            "0000000000000003", "616263",
+            "0000000000000004", "70656572",
+            "0000000000000003", "6b6579",
+            "0000000000000003", "736967",
+        ));
+        // SHA-256(0x00 || encoded record)
+        assert_eq!(hex(&super::merkle::hash_leaf(&record, &Sha256Pow)),
+            "5793aefa7e6ad35e460e97313c05c0e9213623e2a4068124c6b5ae52714bd954");
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_block_hash() {
+        use super::hash::BlockHash;
+
+        let hash = Block::genesis(easy_params()).hash(&Sha256Pow);
+        let hex_hash = "49d868e5dd5d496826038fe2b1910b302209a16a04fad76651d0a74d00c83a7a";
+        assert_eq!(hash.to_string(), hex_hash);
+        assert_eq!(hex_hash.parse::<BlockHash>(), Ok(hash));
+        assert_eq!(hex_hash.to_uppercase().parse::<BlockHash>(), Ok(hash));
+        assert_eq!(format!("{:?}", hash), format!("BlockHash({})", hex_hash));
+        for invalid in ["", &hex_hash[1..], &format!("{}0", hex_hash), &hex_hash.replace('e', "g"),
+            &hex_hash.replacen("49", "ą", 1), "e6X5nW0+f6zU91DJOJtpPEnEBEHo7hW8u0qg0czTJOc="]
+        {
+            assert!(invalid.parse::<BlockHash>().is_err(), "{:?} parsed", invalid);
+        }
+
+        // JSON holds the hex, base64 hashes and the old genesis parent of stored blocks are
+        // still read
+        assert_eq!(serde_json::to_string(&hash).unwrap(), format!("\"{}\"", hex_hash));
+        assert_eq!(serde_json::from_str::<BlockHash>(&format!("\"{}\"", hex_hash)).unwrap(), hash);
+        let base64_hash = format!("\"{}\"", openssl::base64::encode_block(hash.as_bytes()));
+        assert_eq!(serde_json::from_str::<BlockHash>(&base64_hash).unwrap(), hash);
+        assert_eq!(serde_json::from_str::<BlockHash>(&format!("\"{}\"", "0".repeat(32))).unwrap(), BlockHash::zero());
+        assert!(serde_json::from_str::<BlockHash>("\"AAAA\"").is_err());
+        assert_eq!(Block::genesis(easy_params()).previous_block_hash, BlockHash::zero());
+
+        // Hashes compare as big-endian numbers
+        let mut bytes = [0; 32];
+        bytes[31] = 0xff;
+        let small = BlockHash::from_bytes(bytes);
+        bytes[0] = 1;
+        let large = BlockHash::from_bytes(bytes);
+        assert!(BlockHash::zero() < small && small < large);
+        assert_eq!([large, BlockHash::zero(), small].iter().max(), Some(&large));
+    }
+
+    #[test]
+    fn test_binary_block_store() {
+        use super::chain::Chain;
+        use super::encoding::BlockFormat;
+        use super::store::{BlockReader, BlockStore, FileBlockStore};
+        use std::io::Write;
+
+        let mut chain = Chain::new(0);
+        chain.init_first_block(easy_params());
+        for _ in 0..3 {
+            let mut block = Block::new(chain.blocks.len() as u64 + 1,
+                chain.get_last_block().unwrap().hash(&Sha256Pow),
+                0,
+                Vec::new(),
+                "".to_string(),
+                Vec::new(),
+                easy_target());
+            mine(&mut block);
+            chain.blocks.push(block);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let json_path = dir.path().join("chain.jsonl");
+        let json_path = json_path.to_str().unwrap();
+        let binary_path = dir.path().join("chain.bin");
+        let binary_path = binary_path.to_str().unwrap();
+        FileBlockStore::new(json_path).replace(&chain.blocks).unwrap();
+        let mut store = FileBlockStore::with_format(binary_path, BlockFormat::Binary);
+        assert_eq!(FileBlockStore::detect_format(binary_path).unwrap(), None);
+        store.replace(&chain.blocks[..3]).unwrap();
+        store.append(&chain.blocks[3]).unwrap();
+        assert_eq!(FileBlockStore::detect_format(binary_path).unwrap(), Some(BlockFormat::Binary));
+        assert_eq!(FileBlockStore::detect_format(json_path).unwrap(), Some(BlockFormat::Json));
+        assert!(std::fs::metadata(binary_path).unwrap().len() < std::fs::metadata(json_path).unwrap().len());
+        assert_eq!(store.range(1, 4).unwrap(), chain.blocks);
+        assert_eq!(Chain::load_from_file(binary_path).unwrap().blocks, chain.blocks);
+
+        // The index is rebuilt from the length prefixes
+        std::fs::remove_file(format!("{}.idx", binary_path)).unwrap();
+        let mut store = FileBlockStore::with_format(binary_path, BlockFormat::Binary);
+        assert_eq!(store.get(3).as_ref(), chain.blocks.get(2));
+
+        // Crash in the middle of an append
+        store.truncate(3).unwrap();
+        let entry = [&(100u64).to_be_bytes()[..], &[1; 40]].concat();
+        let mut file = std::fs::OpenOptions::new().append(true).open(binary_path).unwrap();
+        file.write_all(&entry).unwrap();
+        assert_eq!(store.recover().unwrap().trimmed_bytes, 48);
+        assert_eq!(store.tip().as_ref(), chain.blocks.get(2));
+        // A length which was never written
+        file.write_all(&[0; 20]).unwrap();
+        assert_eq!(store.recover().unwrap().trimmed_bytes, 20);
+        assert_eq!(store.len(), 3);
     }
 
     mod file_operations {
@@ -63,7 +1479,7 @@ mod test {
             {"idx":9,"previous_block_hash":"bY7SoGgUHF07oGgmZyvyX68fUIW0OWnSvaQEi7yLwTQ=","validation_sidelinks":[],"pow":"3530195229330195409","timestamp":1687109385,"records":[{"idx":[9,1],"timestamp":1687109381,"data":"papapa","author_peer_id":"12D3KooWFmb524g674gmQnuFu9CME4e6yXLgb3hpKLuagPNUhBQj"}],"difficulty":[0,0,0,63,218,110,249,240,181,42,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}    
          */
 
-// TODO: broken tests because no num_sidelnks field in the blocks below
+        // Blocks of version 0, written before num_sidelinks was added
         const SAMPLE_FILE: &str = r##"{"idx":1,"previous_block_hash":"00000000000000000000000000000000","validation_sidelinks":[],"pow":"","timestamp":0,"records":[],"difficulty":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}
 {"idx":2,"previous_block_hash":"mDgKLzrjHxk/fpBrKby9puNvbVMVunf44ns3uj3d9UY=","validation_sidelinks":[],"pow":"18358677514904226553","timestamp":1687109269,"records":[{"idx":[2,1],"timestamp":1687109265,"data":"dupa123","author_peer_id":"12D3KooWDYEAgpLJzm289WwxsB3hf9H9b7rUrepvbgXmaGa1zXsZ"}],"difficulty":[0,0,0,63,218,110,249,240,181,42,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}
 {"idx":3,"previous_block_hash":"fc+EskuYUs1uDVJI6aciGbZ2cXXIYE1n12Iljj/23HI=","validation_sidelinks":[],"pow":"7074847124089670442","timestamp":1687109279,"records":[],"difficulty":[0,0,0,63,218,110,249,240,181,42,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}
@@ -77,7 +1493,7 @@ mod test {
         
         #[test]
         fn test_get_last_n_blocks_from_file() {
-            use crate::blockchain::chain::Chain;
+            use crate::blockchain::store::{BlockReader, FileBlockStore};
             use tempfile::NamedTempFile;
             use std::io::Write;
 
@@ -89,16 +1505,18 @@ mod test {
             println!("file_contents: {:?}", file_contents);
 
             let blocks_to_read = 3;
-            let blocks = Chain::get_last_n_blocks_from_file(
-                blocks_to_read,
-                filename_as_str);
+            let blocks = FileBlockStore::new(filename_as_str).last_n(blocks_to_read);
             
             println!("blocks: {:?}", blocks);
+            let blocks = blocks.unwrap();
+            assert_eq!(blocks.iter().map(|block| block.idx).collect::<Vec<_>>(), vec![7, 8, 9]);
+            assert!(blocks.iter().all(|block| block.version == 0 && block.num_sidelinks == 0));
+            assert_eq!(blocks[1].records[2].data, "pamamama");
         }
 
         #[test]
         fn test_get_chosen_set_of_blocks_from_file() {
-            use crate::blockchain::chain::Chain;
+            use crate::blockchain::store::{BlockReader, FileBlockStore};
             use tempfile::NamedTempFile;
             use std::io::Write;
 
@@ -110,13 +1528,13 @@ mod test {
             println!("file_contents: {:?}", file_contents);
 
             let blocks_to_read = vec![0, 1, 3, 8, 9, 120];
-            let blocks = Chain::get_blocks_by_indices_from_file_in_given_order(
-                &blocks_to_read,
-                None,
-                filename_as_str);
+            let blocks = FileBlockStore::new(filename_as_str).get_many(&blocks_to_read);
             
-            // Won't print blocks with indicees 0 and 120 since they do not exist
+            // Won't print any blocks since blocks with indicees 0 and 120 do not exist
             println!("blocks: {:?}", blocks);
+            assert_eq!(blocks, None);
+            let blocks = FileBlockStore::new(filename_as_str).get_many(&[1, 3, 8, 9]).unwrap();
+            assert_eq!(blocks.iter().map(|block| block.idx).collect::<Vec<_>>(), vec![1, 3, 8, 9]);
         }
     }
-}
\ No newline at end of file
+}
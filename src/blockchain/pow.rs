@@ -1,24 +1,79 @@
-use openssl::sha::sha256;
+use chrono::Utc;
 use rand::Rng;
 use tokio::sync::mpsc;
-use core::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
+use libp2p::futures::executor::block_on;
 use log::{info, error};
 
-use crate::blockchain::{block::Block, chain::Chain};
+use crate::blockchain::{block::{Block, BlockHeader, Record}, difficulty, hash::BlockHash};
+use crate::blockchain::chain::Chain;
+use crate::blockchain::store::BlockReader;
+use crate::blockchain::storage::{StorageError, StorageWriter};
+use crate::blockchain::pow_algorithm::{PowAlgorithm, PowAlgorithmKind};
+use crate::blockchain::target::Target;
 
-use super::block::{Record, self};
+// Number of nonces a worker checks between two looks at the stop flag. Small enough for the
+// workers to stop within a few milliseconds after a new record or a new last block arrives.
+const NONCES_PER_BATCH: u64 = 4096;
+// How often every worker reports its hashrate
+const HASHRATE_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// How long the hashrate benchmark runs
+pub const HASHRATE_BENCHMARK_WINDOW: Duration = Duration::from_secs(3);
 
-pub fn get_token_from_block(block: &Block) -> [u8; 32] {
-    sha256(&[block.previous_block_hash.as_bytes(),
-        // &block.difficulty,
-        &(block.pow.parse::<u64>().unwrap().to_be_bytes())].concat())
+// The token covers the whole block header (including the Merkle root over the records), so
+// none of the block's contents can be changed without invalidating the proof of work.
+// Returns None if the pow of the block is not a nonce, e.g. in a block sent by a peer.
+pub fn get_token_from_block(block: &Block, algorithm: &dyn PowAlgorithm) -> Option<[u8; 32]> {
+    let nonce = block.pow.parse::<u64>().ok()?;
+    Some(get_new_token(block, nonce, algorithm))
 }
 
-pub fn get_new_token(new_block_so_far: &Block, nonce: u64) -> [u8; 32] {
-    sha256(&[new_block_so_far.previous_block_hash.as_bytes(),
-        // &new_block_so_far.difficulty,
-        &nonce.to_be_bytes()].concat())
+pub fn get_new_token(new_block_so_far: &Block, nonce: u64, algorithm: &dyn PowAlgorithm) -> [u8; 32] {
+    get_token_from_prefix(&new_block_so_far.header(algorithm).pow_prefix(), nonce, algorithm)
+}
+
+// Same as get_token_from_block, but usable when only the header of a block is known.
+// Returns None if the header does not contain a nonce.
+pub fn get_token_from_header(header: &BlockHeader, algorithm: &dyn PowAlgorithm) -> Option<[u8; 32]> {
+    let nonce = header.pow.parse::<u64>().ok()?;
+    Some(get_token_from_prefix(&header.pow_prefix(), nonce, algorithm))
+}
+
+// Building the prefix requires serializing the header and computing the Merkle root, so the
+// miner does it once per block template and not for every nonce (see PowAlgorithm::midstate
+// for skipping the prefix altogether)
+pub fn get_token_from_prefix(pow_prefix: &[u8], nonce: u64, algorithm: &dyn PowAlgorithm) -> [u8; 32] {
+    algorithm.hash(&[pow_prefix, &nonce.to_be_bytes()].concat())
+}
+
+pub fn default_mining_threads() -> usize {
+    thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1)
+}
+
+// The miner does not choose the difficulty, it has to use the one required by the chain.
+// It is determined for every new block template, so retargets are picked up as soon as the
+// miner starts working on a block at the retarget height.
+fn next_difficulty(block_idx: u64, store: &dyn BlockReader) -> Target {
+    difficulty::expected_difficulty(block_idx, |idx| store.get(idx)).unwrap_or_else(|| {
+        error!("Cannot determine the difficulty of block {} from the store", block_idx);
+        // No token is smaller than zero, so nothing gets mined until the next refresh
+        Target::zero()
+    })
+}
+
+// Sidelinks are a part of the header, so they have to be known before the nonce search starts
+fn fill_sidelinks(block: &mut Block, store: &dyn BlockReader, algorithm: &dyn PowAlgorithm) {
+    block.validation_sidelinks.clear();
+    let sidelink_indices = block.derive_sidelink_indices(algorithm);
+    if let Some(sidelinked_blocks) = store.get_many(&sidelink_indices) {
+        sidelinked_blocks.iter().for_each(|sidelinked_block| {
+            block.add_sidelink(sidelinked_block.hash(algorithm));
+        });
+    } else {
+        error!("Cannot get sidelinked blocks from the store");
+    }
 }
 
 /*
@@ -31,202 +86,563 @@ pub fn get_new_token(new_block_so_far: &Block, nonce: u64) -> [u8; 32] {
     (consider the probability that no string of this length produces an output satisfying the
     required property).
 */
-fn prove_the_work(difficulty: &Vec<u8>,
-    num_sidelinks: usize,
-    last_block: &Block,
-    new_last_block_rx: &mut mpsc::UnboundedReceiver<Block>,
-    new_record_rx: &mut mpsc::UnboundedReceiver<Record>,
-) -> Block {
-    // println!("Proving the work... (mining a block)");
-    // Generate a random initial nonce so that the work of every node would not just be
-    // a race of who can find the lowest nonce the fastest.
-    let mut nonce = rand::thread_rng().gen::<u64>();
-    let mut counter = 0;
+// Searches for a nonce of the template starting from first_nonce until a solution is found or
+// the search is stopped (either by another worker finding a solution or by the coordinator).
+// Returns the number of hashes computed.
+fn search_nonces(worker_id: usize,
+    template: &Block,
+    algorithm: &dyn PowAlgorithm,
+    first_nonce: u64,
+    stop: &AtomicBool,
+    solution_tx: mpsc::UnboundedSender<u64>,
+) -> u64 {
+    let midstate = algorithm.midstate(&template.header(algorithm).pow_prefix());
+    let difficulty = &template.difficulty;
+    let mut nonce = first_nonce;
+    let mut hashes: u64 = 0;
+    let started = Instant::now();
+    let mut last_log = started;
+
+    while !stop.load(Ordering::Relaxed) {
+        for _ in 0..NONCES_PER_BATCH {
+            if difficulty.is_met_by(&midstate.token(nonce)) {
+                info!("[MINER] Worker {} found nonce {} for block {} after {} hashes \
+                    ({:.0} H/s)", worker_id, nonce, template.idx, hashes,
+                    hashes as f64 / started.elapsed().as_secs_f64());
+                // Cancel the other workers right away, the coordinator may still be waking up
+                stop.store(true, Ordering::Relaxed);
+                solution_tx.send(nonce).ok();
+                return hashes;
+            }
+            nonce = nonce.wrapping_add(1);
+        }
+        hashes += NONCES_PER_BATCH;
+
+        if last_log.elapsed() >= HASHRATE_LOG_INTERVAL {
+            info!("[MINER] Worker {} mining block {}, hashrate: {:.0} H/s",
+                worker_id, template.idx, hashes as f64 / started.elapsed().as_secs_f64());
+            last_log = Instant::now();
+        }
+    }
+
+    hashes
+}
+
+/*
+    Measures the hashrate of this machine by running the nonce search used for mining on
+    num_threads threads for the given time, so the result includes everything a real attempt
+    costs (hashing the nonce with the midstate and comparing the token with the target).
+    The target of the benchmarked template is zero, so no worker stops before the time is up.
+    Returns hashes per second of all threads together.
+ */
+pub fn benchmark_hashrate(num_threads: usize,
+    window: Duration,
+    algorithm: &dyn PowAlgorithm,
+) -> f64 {
+    let num_threads = num_threads.max(1);
+    let template = Block::new(2,
+        BlockHash::zero(),
+        0,
+        Vec::new(),
+        "".to_string(),
+        Vec::new(),
+        Target::zero());
+    let stop = AtomicBool::new(false);
+    let (solution_tx, _solution_rx) = mpsc::unbounded_channel();
+    let range_length = u64::MAX / num_threads as u64;
+    let started = Instant::now();
+
+    let hashes: u64 = thread::scope(|scope| {
+        let workers: Vec<_> = (0..num_threads).map(|worker_id| {
+            let (template, stop, solution_tx) = (&template, &stop, solution_tx.clone());
+            scope.spawn(move || {
+                search_nonces(worker_id,
+                    template,
+                    algorithm,
+                    worker_id as u64 * range_length,
+                    stop,
+                    solution_tx)
+            })
+        }).collect();
+
+        thread::sleep(window);
+        stop.store(true, Ordering::Relaxed);
+        workers.into_iter()
+            .map(|worker| worker.join().expect("benchmark worker does not panic"))
+            .sum()
+    });
 
+    let hashrate = hashes as f64 / started.elapsed().as_secs_f64();
+    info!("[MINER] Benchmark of {} on {} thread(s): {:.0} H/s",
+        algorithm.name(), num_threads, hashrate);
+    hashrate
+}
+
+// Makes the template a successor of the new last block of the chain
+fn rebase_template(new_block: &mut Block,
+    new_last_block: &Block,
+    store: &dyn BlockReader,
+    algorithm: &dyn PowAlgorithm,
+) {
+    new_block.previous_block_hash = new_last_block.hash(algorithm);
+    info!("New last block with hash {} received. Discarding the current block and \
+        starting mining a new block with the data of the new last block.",
+        new_block.previous_block_hash);
+
+    new_block.idx = new_last_block.idx + 1;
+    // Block times (and so the difficulty adjustment) are measured from the moment
+    // the work on the block started
+    new_block.timestamp = Utc::now().timestamp() as u64;
+    new_block.num_sidelinks = Chain::num_sidelinks_of_block(new_block.idx, store.num_sidelinks());
+
+    // Compare sets of records in new_block and new_last_block
+    // Discard any records present in the new_last_block from the new_block
+    // Update indices of records which are left in the new_block so that they are
+    // equal to the index of the new_block
+    let mut new_block_records = Vec::new();
+    let mut record_counter = 0;
+    for record in new_block.records.iter() {
+        if !new_last_block.includes_record(record) {
+            record_counter += 1;
+            let mut updated_record = record.clone();
+            updated_record.idx = (new_block.idx, record_counter);
+            info!("Refreshed record {:?}->{:?}", record, updated_record);
+            new_block_records.push(updated_record);
+        }
+    }
+    new_block.records = new_block_records;
+    new_block.difficulty = next_difficulty(new_block.idx, store);
+    fill_sidelinks(new_block, store, algorithm);
+}
+
+// Commands the user (or startup flags) can give to the miner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MiningCommand {
+    // Mine until stopped (or resume a paused miner)
+    Start,
+    // Drop the block being mined, its records are kept for the next block
+    Stop,
+    // Stop the workers but keep the block being mined, so that start can carry on with it
+    Pause,
+    // Mine the given number of blocks, then stop
+    MineBlocks(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MiningState {
+    Stopped,
+    // blocks_left is None when mining without a limit
+    Mining { blocks_left: Option<u64> },
+    Paused { blocks_left: Option<u64> },
+}
+
+impl MiningState {
+    pub fn apply(self, command: MiningCommand) -> MiningState {
+        match (command, self) {
+            (MiningCommand::Start, MiningState::Paused { blocks_left }) => {
+                MiningState::Mining { blocks_left }
+            },
+            (MiningCommand::Start, _) => MiningState::Mining { blocks_left: None },
+            (MiningCommand::Stop, _) | (MiningCommand::MineBlocks(0), _) => MiningState::Stopped,
+            (MiningCommand::MineBlocks(num_blocks), _) => {
+                MiningState::Mining { blocks_left: Some(num_blocks) }
+            },
+            (MiningCommand::Pause, MiningState::Mining { blocks_left }) => {
+                MiningState::Paused { blocks_left }
+            },
+            (MiningCommand::Pause, state) => state,
+        }
+    }
+
+    // State after one of our blocks made it into the chain
+    pub fn block_mined(self) -> MiningState {
+        match self {
+            MiningState::Mining { blocks_left: Some(blocks_left) } if blocks_left <= 1 => {
+                MiningState::Stopped
+            },
+            MiningState::Mining { blocks_left: Some(blocks_left) } => {
+                MiningState::Mining { blocks_left: Some(blocks_left - 1) }
+            },
+            state => state,
+        }
+    }
+}
+
+// Receiving ends of the channels through which the rest of the node talks to the miner
+pub struct MinerInputs {
+    pub new_last_block_rx: mpsc::UnboundedReceiver<Block>,
+    pub new_record_rx: mpsc::UnboundedReceiver<Record>,
+    pub mining_control_rx: mpsc::UnboundedReceiver<MiningCommand>,
+}
+
+pub enum MiningOutcome {
+    Mined(Box<Block>),
+    // Mining was stopped by the user, records of the dropped block are in pending_records
+    Stopped,
+    // The node is shutting down and dropped its side of the channels
+    Closed,
+}
+
+// What interrupted the search for a nonce
+enum MinerEvent {
+    Solution(u64),
+    NewRecord(Record),
+    NewLastBlock(Block),
+    Control(MiningCommand),
+    Closed,
+}
+
+async fn next_miner_event(solution_rx: &mut mpsc::UnboundedReceiver<u64>,
+    inputs: &mut MinerInputs,
+) -> MinerEvent {
+    tokio::select! {
+        biased;
+        command = inputs.mining_control_rx.recv() => {
+            command.map_or(MinerEvent::Closed, MinerEvent::Control)
+        }
+        block = inputs.new_last_block_rx.recv() => {
+            block.map_or(MinerEvent::Closed, MinerEvent::NewLastBlock)
+        }
+        Some(nonce) = solution_rx.recv() => MinerEvent::Solution(nonce),
+        record = inputs.new_record_rx.recv() => {
+            record.map_or(MinerEvent::Closed, MinerEvent::NewRecord)
+        }
+    }
+}
+
+fn log_mining_state(state: MiningState) {
+    match state {
+        MiningState::Stopped => println!("[MINER] Mining stopped"),
+        MiningState::Paused { .. } => println!("[MINER] Mining paused"),
+        MiningState::Mining { blocks_left: None } => println!("[MINER] Mining"),
+        MiningState::Mining { blocks_left: Some(blocks_left) } => {
+            println!("[MINER] Mining, {} block(s) left", blocks_left)
+        },
+    }
+}
+
+/*
+    Mines a block on top of last_block using num_threads workers.
+    Every worker searches its own part of the nonce space: the space is split into num_threads
+    equal ranges starting at a random offset. The first worker to find a solution stops the
+    others. Meanwhile the calling thread waits for a solution, a new record, a new last block
+    or a mining command, whichever comes first. Anything but a solution raises the stop flag
+    right away, the template is updated and the search starts again (unless mining is paused,
+    in which case no workers are started until mining is resumed).
+    Records which arrive while a solution is being found are left in pending_records, so that
+    they can be put into the next block.
+ */
+#[allow(clippy::too_many_arguments)]
+pub fn prove_the_work(num_threads: usize,
+    miner_peer_id: &str,
+    last_block: &Block,
+    pending_records: &mut Vec<Record>,
+    state: &mut MiningState,
+    inputs: &mut MinerInputs,
+    store: &dyn BlockReader,
+) -> MiningOutcome {
+    let algorithm = store.chain_params()
+        .map(|params| params.pow_algorithm)
+        .unwrap_or_else(|| {
+            error!("Cannot read the chain parameters from the store, mining with the default algorithm");
+            PowAlgorithmKind::default()
+        })
+        .algorithm();
     let block_idx = last_block.idx + 1;
-    let num_sidelinks = if num_sidelinks >= (block_idx - 1) as usize {
-        (block_idx - 2) as usize
-    } else {
-        num_sidelinks
-    };
     let mut new_block = Block::new(
-        last_block.idx + 1,
-        last_block.hash(),
-        num_sidelinks,
+        block_idx,
+        last_block.hash(algorithm),
+        Chain::num_sidelinks_of_block(block_idx, store.num_sidelinks()),
         Vec::new(),
         "".to_string(),
         Vec::new(),
-        difficulty.clone(),
+        next_difficulty(block_idx, store),
     );
+    new_block.miner_peer_id = Some(miner_peer_id.to_string());
+    pending_records.drain(..).for_each(|record| new_block.add_record(record));
+    fill_sidelinks(&mut new_block, store, algorithm);
 
     loop {
-        let hash_result = get_new_token(&new_block, nonce);
-        let token = hash_result.as_ref();
-        // Compare which one is smaller
-        // println!("token: {:?}\ndifficulty: {:?}", token, difficulty);
-        if token < difficulty.as_slice() {
-            // println!("Found a valid nonce: {}", nonce);
-            break;
-        }
-        if nonce % 10000000 == 0 {
-            // Check if something came through the channel
-            if let Ok(new_record) = new_record_rx.try_recv() {
-                info!("New record received by the pow thread: \"{:?}\". \
-                    Adding it to (currently) block with idx {}", new_record, new_block.idx);
-                // If something came through the channel, add it to the block
-                new_block.add_record(new_record);
+        let num_workers = if let MiningState::Paused { .. } = state { 0 } else { num_threads };
+        let stop = AtomicBool::new(false);
+        let (solution_tx, mut solution_rx) = mpsc::unbounded_channel();
+        // Generate a random initial nonce so that the work of every node would not just be
+        // a race of who can find the lowest nonce the fastest.
+        let first_nonce = rand::thread_rng().gen::<u64>();
+        let range_length = u64::MAX / num_threads.max(1) as u64;
+        let started = Instant::now();
+
+        let event = thread::scope(|scope| {
+            for worker_id in 0..num_workers {
+                let (template, stop, solution_tx) = (&new_block, &stop, solution_tx.clone());
+                let worker_first_nonce = first_nonce.wrapping_add(worker_id as u64 * range_length);
+                scope.spawn(move || {
+                    search_nonces(worker_id,
+                        template,
+                        algorithm,
+                        worker_first_nonce,
+                        stop,
+                        solution_tx)
+                });
             }
-            // TODO: try recv these two
-            // let difficulty = if let Some(difficulty) = difficulty_rx.recv().await {
-            //     difficulty
-            // } else {
-            //     panic!("Cannot get difficulty from channel");
-            // };
-            // let num_sidelinks = if let Some(num_sidelinks) = sidelinks_rx.recv().await {
-            //     num_sidelinks
-            // } else {
-            //     panic!("Cannot get number of sidelinks from channel");
-            // };
-            if let Ok(new_last_block) = new_last_block_rx.try_recv() {
-                // println!("New last block received: {:?}", new_last_block);
-                // If something came through the channel, discard the current block and start
-                // mining a new block with the data of the new last block
-                nonce = rand::thread_rng().gen::<u64>();
-                new_block.previous_block_hash = new_last_block.hash();
-                info!("New last block with hash {} received. Discarding the current block and \
-                    starting mining a new block with the data of the new last block.",
-                    new_block.previous_block_hash);
-
-                new_block.idx = new_last_block.idx + 1;
-                new_block.num_sidelinks = if num_sidelinks >= (new_block.idx - 1) as usize {
-                    (new_block.idx - 2) as usize
-                } else {
-                    num_sidelinks
-                };
-
-                // Compare sets of records in new_block and new_last_block
-                // Discard any records present in the new_last_block from the new_block
-                // Update indices of records which are left in the new_block so that they are
-                // equal to the index of the new_block
-                let mut new_block_records = Vec::new();
-                let mut record_counter = 0;
-                for record in new_block.records.iter() {
-                    if !new_last_block.records.contains(record) {
-                        let mut updated_record = record.clone();
-                        updated_record.idx = (new_block.idx, record_counter);
-                        info!("Refreshed record {:?}->{:?}", record, updated_record);
-                        new_block_records.push(updated_record);
-                        record_counter += 1;
-                    }
-                }
-                new_block.records = new_block_records;
+            // Without workers the solution channel has to be closed, or the miner would
+            // wait for a solution forever
+            drop(solution_tx);
+
+            let event = block_on(next_miner_event(&mut solution_rx, inputs));
+            stop.store(true, Ordering::Relaxed);
+            event
+        });
 
-                counter = 0;
-                continue;
+        // Everything else that arrived while the workers were stopping is handled at once
+        let mut solution = None;
+        let mut new_records = Vec::new();
+        let mut new_last_block = None;
+        let mut commands = Vec::new();
+        match event {
+            MinerEvent::Solution(nonce) => solution = Some(nonce),
+            MinerEvent::NewRecord(record) => new_records.push(record),
+            MinerEvent::NewLastBlock(block) => new_last_block = Some(block),
+            MinerEvent::Control(command) => commands.push(command),
+            MinerEvent::Closed => {
+                info!("[MINER] Channels closed, stopping the miner.");
+                return MiningOutcome::Closed;
             }
-            println!("Mining... Current nonce: {}.", nonce);
         }
-        nonce += 1;
-        counter += 1;
+        while let Ok(command) = inputs.mining_control_rx.try_recv() {
+            commands.push(command);
+        }
+        while let Ok(record) = inputs.new_record_rx.try_recv() {
+            new_records.push(record);
+        }
+        while let Ok(block) = inputs.new_last_block_rx.try_recv() {
+            new_last_block = Some(block);
+        }
+        if num_workers > 0 {
+            info!("[MINER] Search for block {} interrupted after {} ms", new_block.idx,
+                started.elapsed().as_millis());
+        }
+
+        if !commands.is_empty() {
+            *state = commands.into_iter().fold(*state, MiningState::apply);
+            log_mining_state(*state);
+        }
+        if *state == MiningState::Stopped {
+            // A solution found in the meantime is dropped as well, the user asked to stop
+            pending_records.append(&mut new_block.records);
+            pending_records.extend(new_records);
+            return MiningOutcome::Stopped;
+        }
+
+        if let Some(new_last_block) = new_last_block {
+            // Whatever was found is not valid anymore, since the chain has a new last block
+            new_records.into_iter().for_each(|record| new_block.add_record(record));
+            rebase_template(&mut new_block, &new_last_block, store, algorithm);
+            continue;
+        }
+
+        if let Some(nonce) = solution {
+            pending_records.extend(new_records);
+            new_block.pow = nonce.to_string();
+            return MiningOutcome::Mined(Box::new(new_block));
+        }
+
+        for new_record in new_records {
+            info!("New record received by the pow thread: \"{:?}\". \
+                Adding it to (currently) block with idx {}", new_record, new_block.idx);
+            new_block.add_record(new_record);
+        }
     }
+}
 
-    // println!("Number of iterations: {}", counter);
-    new_block.pow = nonce.to_string();
-    new_block
+// While mining is stopped the miner only keeps track of the chain and the records, so that it
+// can start right away once told to. Returns false if the channels were closed.
+fn wait_while_stopped(state: &mut MiningState,
+    last_block: &mut Block,
+    pending_records: &mut Vec<Record>,
+    inputs: &mut MinerInputs,
+) -> bool {
+    // Nothing is mined, so nothing can be sent on the solution channel
+    let (_, mut no_solution_rx) = mpsc::unbounded_channel();
+    while *state == MiningState::Stopped {
+        let event = block_on(next_miner_event(&mut no_solution_rx, inputs));
+        if !handle_idle_event(event, state, last_block, pending_records) {
+            return false;
+        }
+    }
+
+    true
+}
+
+// After a block of another peer got into the store first, the miner waits for it to arrive on
+// the new last block channel. Commands and records are handled in the meantime as usual.
+// Returns false if the channels were closed.
+fn wait_for_new_last_block(state: &mut MiningState,
+    last_block: &mut Block,
+    pending_records: &mut Vec<Record>,
+    inputs: &mut MinerInputs,
+) -> bool {
+    let (_, mut no_solution_rx) = mpsc::unbounded_channel();
+    loop {
+        let event = block_on(next_miner_event(&mut no_solution_rx, inputs));
+        let is_new_last_block = matches!(event, MinerEvent::NewLastBlock(_));
+        if !handle_idle_event(event, state, last_block, pending_records) {
+            return false;
+        }
+        if is_new_last_block {
+            return true;
+        }
+    }
+}
+
+// Handles an event received while no block is being mined. Returns false if the channels
+// were closed.
+fn handle_idle_event(event: MinerEvent,
+    state: &mut MiningState,
+    last_block: &mut Block,
+    pending_records: &mut Vec<Record>,
+) -> bool {
+    match event {
+        MinerEvent::Control(command) => {
+            *state = state.apply(command);
+            log_mining_state(*state);
+        },
+        MinerEvent::NewLastBlock(new_last_block) => {
+            pending_records.retain(|record| !new_last_block.includes_record(record));
+            *last_block = new_last_block;
+        },
+        MinerEvent::NewRecord(record) => pending_records.push(record),
+        MinerEvent::Solution(_) => {},
+        MinerEvent::Closed => return false,
+    }
+
+    true
 }
 
 /*
     How this works:
-        1. The mining task is spawned and it starts mining a block with the data of the last
-            block in the chain.
+        1. The miner runs on its own thread (see spawn_miner) and starts mining a block with the
+            data of the last block in the chain (unless it was started in the stopped state).
         2. If a new block is mined, it is sent to the *main* function via the channel.
-        3. If a new block is added to the chain, the mining task is notified and after mining the
-            previous block it discards it and starts mining a new block with the data of the new
-            last block in the chain.
+        3. If a new block is added to the chain or a new record arrives, the workers are stopped
+            immediately and the block being mined is rebuilt with the new data.
+        4. Mining commands start, stop or pause the miner at any point.
  */
-pub async fn mine_blocks(new_mined_block_tx: &mpsc::UnboundedSender<Block>,
-    new_last_block_rx: &mut mpsc::UnboundedReceiver<Block>,
-    new_record_rx: &mut mpsc::UnboundedReceiver<Record>,
-    difficulty_rx: &mut mpsc::UnboundedReceiver<Vec<u8>>,
-    sidelinks_rx: &mut mpsc::UnboundedReceiver<usize>,
-    blockchain_filepath: &str
+pub fn mine_blocks(new_mined_block_tx: &mpsc::UnboundedSender<Block>,
+    inputs: &mut MinerInputs,
+    initial_state: MiningState,
+    num_threads: usize,
+    miner_peer_id: &str,
+    storage: &StorageWriter,
+    store: &dyn BlockReader,
 ) {
-    let mut last_block = if let Some(block) =
-        Chain::get_last_block_from_file(blockchain_filepath)
-    {
+    let mut last_block = if let Some(block) = store.tip() {
         block
     } else {
         // Lock the thread and wait on the channel
         info!("[MINER]: Waiting for chain initialization...\
             (either get somebody's chain or use the init command)");
-        new_last_block_rx.recv().await.unwrap()
+        match inputs.new_last_block_rx.blocking_recv() {
+            Some(block) => block,
+            None => return,
+        }
     };
 
-    let difficulty = last_block.difficulty.clone();
-    let num_sidelinks = last_block.num_sidelinks;
-
-    // Mining task, create a copy of the difficulty vector
-    let difficulty = difficulty.clone();
+    let num_threads = num_threads.max(1);
+    let mut pending_records = Vec::new();
+    let mut state = initial_state;
 
     let thread_id = thread::current().id();
-    info!("Miner starting thread ID: {:?}", thread_id);
+    info!("Miner starting thread ID: {:?}, number of mining threads: {}", thread_id, num_threads);
+    log_mining_state(state);
 
     loop {
-        let mut mined_block = prove_the_work(&difficulty,
-            num_sidelinks,
+        if !wait_while_stopped(&mut state, &mut last_block, &mut pending_records, inputs) {
+            return;
+        }
+
+        let mined_block = match prove_the_work(num_threads,
+            miner_peer_id,
             &last_block,
-            new_last_block_rx,
-            new_record_rx);
-        // println!("New proof of work: {}", new_pow);
-        tokio::select! {
-            Some(new_last_block) =  new_last_block_rx.recv() => {
-                // If we mined a block but somebody mined it faster than our previous block is not
-                // valid anymore and we need to mine a new block with new data
-                last_block = new_last_block;
+            &mut pending_records,
+            &mut state,
+            inputs,
+            store)
+        {
+            MiningOutcome::Mined(block) => *block,
+            MiningOutcome::Stopped => continue,
+            MiningOutcome::Closed => return,
+        };
+
+        if let Ok(new_last_block) = inputs.new_last_block_rx.try_recv() {
+            // If we mined a block but somebody mined it faster than our previous block is not
+            // valid anymore and we need to mine a new block with new data. Records which did
+            // not make it into the new last block are mined again.
+            pending_records.extend(mined_block.records
+                .into_iter()
+                .filter(|record| !new_last_block.includes_record(record)));
+            last_block = new_last_block;
+            continue;
+        }
+
+        info!("Sending new block with such proof of work via channel: {}", mined_block.pow);
+        let new_last_block = mined_block.clone();
+        let mined_block_hash = mined_block.hash(store.pow_algorithm());
+        if let Err(e) = storage.append(mined_block.clone()) {
+            error!("Error appending block to the store. Block will be discarded: {}.", e);
+            if let StorageError::Conflict(_) = e {
+                // A block of another peer got in first. Every change of the tip is sent on
+                // the new last block channel, so the miner waits for the winning block and
+                // only keeps the records which did not make it into it.
+                pending_records.extend(mined_block.records);
+                if !wait_for_new_last_block(&mut state, &mut last_block, &mut pending_records, inputs) {
+                    return;
+                }
             }
-            _ = tokio::task::yield_now() => {
-                let sidelink_indices = mined_block.derive_sidelink_indices();
-                // info!("!!! Storing: sidelink indices: {:?}", sidelink_indices);
-                if let Some(sidelinked_blocks) =
-                    Chain::get_blocks_by_indices_from_file_in_given_order(&sidelink_indices,
-                        Some(sidelink_indices.clone()),
-                        blockchain_filepath)
-                {
-                    sidelinked_blocks.iter().for_each(|block| {
-                        // println!("Sidelinked block: {:?}", block);
-                        mined_block.add_sidelink(block.hash()); 
-                    });
-                } else {
-                    error!("Cannot get sidelinked blocks from file");
-                    continue;
-                };
-
-                // println!("Old block: {:?}", last_block);
-                // println!("New block: {:?}", mined_block);
-                info!("Sending new block with such proof of work via channel: {}", mined_block.pow);
-                let new_last_block = mined_block.clone();
-                if let Err(e) = Chain::append_block_to_file(&mined_block, blockchain_filepath) {
-                    error!("Error appending block to file. Block will be discarded: {}.", e);
+        } else {
+            info!("Block appended to the store.");
+            if let Err(e) = new_mined_block_tx.send(mined_block) {
+                error!("Error sending new mined block via channel, {}", e);
+                if let Err(e) = storage.remove_tip(mined_block_hash) {
+                    error!("Tried to remove last block from the store due to
+                        usuccessful broadcast of the new block but error occured: {}", e);
                 } else {
-                    info!("Block appended to file.");
-                    if let Err(e) = new_mined_block_tx.send(mined_block) {
-                        error!("Error sending new mined block via channel, {}", e);
-                        if let Err(e) = Chain::remove_last_block_from_file(blockchain_filepath) {
-                            error!("Tried to remove last block from the file due to
-                                usuccessful broadcast of the new block but error occured: {}", e);
-                        } else {
-                            info!("Last block removed from file since broadcast of the block\
-                                failed.");
-                        }
-                    } else {
-                        info!("Sent new mined block via channel");
-                        last_block = new_last_block;
-                    }
+                    info!("Last block removed from the store since broadcast of the block\
+                        failed.");
+                }
+            } else {
+                info!("Sent new mined block via channel");
+                last_block = new_last_block;
+                let previous_state = state;
+                state = state.block_mined();
+                if state != previous_state {
+                    log_mining_state(state);
                 }
             }
-            // _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {
-            //     info!("Mining...");
-            // }
         }
     }
-}
\ No newline at end of file
+}
+
+// Runs the miner on a dedicated thread, so that the busy workers never block the tokio runtime
+// handling the network and user input
+pub fn spawn_miner(new_mined_block_tx: mpsc::UnboundedSender<Block>,
+    mut inputs: MinerInputs,
+    initial_state: MiningState,
+    num_threads: usize,
+    miner_peer_id: String,
+    storage: StorageWriter,
+    store: impl BlockReader + Send + 'static,
+) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("miner".to_string())
+        .spawn(move || {
+            mine_blocks(&new_mined_block_tx,
+                &mut inputs,
+                initial_state,
+                num_threads,
+                &miner_peer_id,
+                &storage,
+                &store)
+        })
+        .expect("Miner thread can be spawned")
+}
@@ -0,0 +1,120 @@
+use openssl::hash::{Hasher, MessageDigest};
+use openssl::sha::{sha256, Sha256};
+use serde::{Serialize, Deserialize};
+
+/*
+    Hash function used for the proof of work. Block hashes, Merkle trees and sidelinks of a
+    chain are made with it as well, so a chain depends on a single hash function.
+    Tokens of every algorithm are 32 bytes long, so difficulties (and the work of chains) stay
+    comparable no matter which algorithm a chain uses.
+ */
+pub trait PowAlgorithm: Sync {
+    fn name(&self) -> &'static str;
+    fn hash(&self, data: &[u8]) -> [u8; 32];
+    // State after absorbing the PoW prefix of a block template, so that only the nonce has to
+    // be processed for every attempt
+    fn midstate(&self, pow_prefix: &[u8]) -> Box<dyn PowMidstate>;
+}
+
+pub trait PowMidstate {
+    // Same as hash(pow_prefix || nonce) of the algorithm the midstate comes from
+    fn token(&self, nonce: u64) -> [u8; 32];
+}
+
+pub struct Sha256Pow;
+
+struct Sha256Midstate {
+    hasher: Sha256,
+}
+
+impl PowAlgorithm for Sha256Pow {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        sha256(data)
+    }
+
+    fn midstate(&self, pow_prefix: &[u8]) -> Box<dyn PowMidstate> {
+        let mut hasher = Sha256::new();
+        hasher.update(pow_prefix);
+        Box::new(Sha256Midstate { hasher })
+    }
+}
+
+impl PowMidstate for Sha256Midstate {
+    fn token(&self, nonce: u64) -> [u8; 32] {
+        let mut hasher = self.hasher.clone();
+        hasher.update(&nonce.to_be_bytes());
+        hasher.finish()
+    }
+}
+
+// SHA3-256 (Keccak) has a completely different construction than SHA-256, so hardware built
+// for one of them is of no use for the other
+pub struct Sha3Pow;
+
+struct Sha3Midstate {
+    hasher: Hasher,
+}
+
+fn sha3_finish(mut hasher: Hasher) -> [u8; 32] {
+    let digest = hasher.finish().expect("SHA3-256 hashing works");
+    digest.as_ref().try_into().expect("SHA3-256 digest is 32 bytes long")
+}
+
+impl PowAlgorithm for Sha3Pow {
+    fn name(&self) -> &'static str {
+        "sha3-256"
+    }
+
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        let mut hasher = Hasher::new(MessageDigest::sha3_256()).expect("SHA3-256 is available");
+        hasher.update(data).expect("SHA3-256 hashing works");
+        sha3_finish(hasher)
+    }
+
+    fn midstate(&self, pow_prefix: &[u8]) -> Box<dyn PowMidstate> {
+        let mut hasher = Hasher::new(MessageDigest::sha3_256()).expect("SHA3-256 is available");
+        hasher.update(pow_prefix).expect("SHA3-256 hashing works");
+        Box::new(Sha3Midstate { hasher })
+    }
+}
+
+impl PowMidstate for Sha3Midstate {
+    fn token(&self, nonce: u64) -> [u8; 32] {
+        let mut hasher = self.hasher.clone();
+        hasher.update(&nonce.to_be_bytes()).expect("SHA3-256 hashing works");
+        sha3_finish(hasher)
+    }
+}
+
+// Algorithm of a chain as stored in its genesis parameters
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PowAlgorithmKind {
+    #[default]
+    Sha256,
+    Sha3,
+}
+
+impl PowAlgorithmKind {
+    pub fn algorithm(&self) -> &'static dyn PowAlgorithm {
+        match self {
+            PowAlgorithmKind::Sha256 => &Sha256Pow,
+            PowAlgorithmKind::Sha3 => &Sha3Pow,
+        }
+    }
+
+    // Chains created before the algorithm could be chosen use SHA-256. The default is not
+    // serialized, so that the genesis blocks (and hashes) of such chains stay the same.
+    pub fn is_default(&self) -> bool {
+        *self == PowAlgorithmKind::default()
+    }
+
+    pub fn from_name(name: &str) -> Option<PowAlgorithmKind> {
+        [PowAlgorithmKind::Sha256, PowAlgorithmKind::Sha3]
+            .into_iter()
+            .find(|kind| kind.algorithm().name() == name)
+    }
+}
@@ -0,0 +1,77 @@
+use serde::{Serialize, Deserialize};
+
+use crate::blockchain::{
+    block::{BlockHeader, Record},
+    hash::BlockHash,
+    merkle::MerkleProof,
+    pow,
+    pow_algorithm::PowAlgorithmKind,
+};
+
+/*
+    Self-contained proof that a record is a part of the chain.
+    The receipt can be checked without access to the chain (or a running node):
+        1. the Merkle proof ties the record to the Merkle root stored in block_header,
+        2. the proof of work of block_header commits to that Merkle root,
+        3. every header in header_chain points to the hash of the header before it and carries
+           a valid proof of work, up to the tip of the chain known when the receipt was made.
+    The verifier still has to compare the returned tip hash against a tip they trust.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordReceipt {
+    pub record: Record,
+    pub proof: MerkleProof,
+    pub block_header: BlockHeader,
+    // Headers of all blocks following the record's block, ending with the tip
+    pub header_chain: Vec<BlockHeader>,
+    // Hash function of the proof of work of the chain
+    #[serde(default)]
+    pub pow_algorithm: PowAlgorithmKind,
+}
+
+fn header_has_valid_pow(header: &BlockHeader, pow_algorithm: PowAlgorithmKind) -> bool {
+    if let Some(token) = pow::get_token_from_header(header, pow_algorithm.algorithm()) {
+        header.difficulty.is_met_by(&token)
+    } else {
+        false
+    }
+}
+
+impl RecordReceipt {
+    // Returns the hash of the tip the receipt leads to or a description of the first problem
+    // found in the receipt
+    pub fn verify(&self) -> Result<BlockHash, String> {
+        if self.record.idx.0 != self.block_header.idx {
+            return Err(format!("Record belongs to block {} but the receipt contains \
+                the header of block {}", self.record.idx.0, self.block_header.idx));
+        }
+
+        let algorithm = self.pow_algorithm.algorithm();
+        if !self.proof.verify(&self.record, &self.block_header.merkle_root, algorithm) {
+            return Err(format!("Merkle proof does not lead to the Merkle root of block {}",
+                self.block_header.idx));
+        }
+
+        if !header_has_valid_pow(&self.block_header, self.pow_algorithm) {
+            return Err(format!("Invalid proof of work of block {}", self.block_header.idx));
+        }
+
+        let mut previous_header = &self.block_header;
+        for header in &self.header_chain {
+            if header.idx != previous_header.idx + 1 {
+                return Err(format!("Block {} follows block {} in the header chain",
+                    header.idx, previous_header.idx));
+            }
+            if header.previous_block_hash != previous_header.hash(algorithm) {
+                return Err(format!("Block {} does not point to the hash of block {}",
+                    header.idx, previous_header.idx));
+            }
+            if !header_has_valid_pow(header, self.pow_algorithm) {
+                return Err(format!("Invalid proof of work of block {}", header.idx));
+            }
+            previous_header = header;
+        }
+
+        Ok(previous_header.hash(algorithm))
+    }
+}
@@ -0,0 +1,207 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::blockchain::block::Block;
+
+// Windows (in blocks) used when the user does not choose any
+pub const DEFAULT_STATS_WINDOWS: [usize; 2] = [10, 100];
+
+// Statistics of the last `blocks` blocks of the chain. Windows longer than the chain are
+// shortened to the number of mined blocks.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct WindowStats {
+    pub window: usize,
+    pub blocks: usize,
+    // In seconds, None if there are no block times in the window
+    pub average_block_time: Option<f64>,
+    pub median_block_time: Option<f64>,
+    // Work of the blocks divided by the time it took to mine them, in hashes per second
+    pub hashrate: Option<f64>,
+}
+
+// One row of the history of the chain
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlockStats {
+    pub idx: u64,
+    pub timestamp: u64,
+    // Seconds since the previous block, None for the genesis block and the first mined block
+    // (the genesis block has no meaningful timestamp)
+    pub block_time: Option<i64>,
+    // Compact encoding of the target
+    pub difficulty_bits: u32,
+    // Expected number of hashes needed to mine the block
+    pub work: f64,
+    pub records: usize,
+    pub miner_peer_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    pub num_blocks: usize,
+    pub windows: Vec<WindowStats>,
+    pub average_records_per_block: Option<f64>,
+    // Blocks mined before miners were recorded are counted as "unknown"
+    pub blocks_per_miner: BTreeMap<String, usize>,
+    pub blocks: Vec<BlockStats>,
+}
+
+fn block_time(previous_block: &Block, block: &Block) -> Option<i64> {
+    if previous_block.genesis_params.is_some() {
+        return None;
+    }
+    Some(block.timestamp as i64 - previous_block.timestamp as i64)
+}
+
+fn average(values: &[i64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<i64>() as f64 / values.len() as f64)
+}
+
+fn median(values: &[i64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut values = values.to_vec();
+    values.sort_unstable();
+    let middle = values.len() / 2;
+    if values.len() % 2 == 1 {
+        Some(values[middle] as f64)
+    } else {
+        Some((values[middle - 1] + values[middle]) as f64 / 2.0)
+    }
+}
+
+fn window_stats(history: &[BlockStats], window: usize) -> WindowStats {
+    // The genesis block is not mined, so it is never part of a window
+    let mined = history.get(1..).unwrap_or_default();
+    let in_window = &mined[mined.len().saturating_sub(window)..];
+    let block_times: Vec<i64> = in_window.iter().filter_map(|block| block.block_time).collect();
+    let work: f64 = in_window.iter()
+        .filter(|block| block.block_time.is_some())
+        .map(|block| block.work)
+        .sum();
+    let total_time: i64 = block_times.iter().sum();
+
+    WindowStats {
+        window,
+        blocks: in_window.len(),
+        average_block_time: average(&block_times),
+        median_block_time: median(&block_times),
+        hashrate: if total_time > 0 { Some(work / total_time as f64) } else { None },
+    }
+}
+
+/*
+    Statistics derived from the timestamps, difficulties and records of the blocks of a chain
+    (starting with the genesis block). Block times and the hashrate are computed for the last
+    `window` blocks of every given window.
+    The hashrate is an estimate of the hashrate of the whole network: blocks take on average
+    as many hashes as their work, so the network computed about as many hashes as the work of
+    the blocks in the time it took to mine them.
+ */
+pub fn chain_stats(blocks: &[Block], windows: &[usize]) -> ChainStats {
+    let history: Vec<BlockStats> = blocks.iter()
+        .enumerate()
+        .map(|(position, block)| BlockStats {
+            idx: block.idx,
+            timestamp: block.timestamp,
+            block_time: position.checked_sub(1)
+                .and_then(|previous| block_time(&blocks[previous], block)),
+            difficulty_bits: block.difficulty.to_compact(),
+            work: block.difficulty.work().to_f64(),
+            records: block.records.len(),
+            miner_peer_id: block.miner_peer_id.clone(),
+        })
+        .collect();
+
+    let mined = history.get(1..).unwrap_or_default();
+    let mut blocks_per_miner = BTreeMap::new();
+    for block in mined {
+        let miner = block.miner_peer_id.clone().unwrap_or_else(|| "unknown".to_string());
+        *blocks_per_miner.entry(miner).or_insert(0) += 1;
+    }
+    let average_records_per_block = if mined.is_empty() {
+        None
+    } else {
+        Some(mined.iter().map(|block| block.records).sum::<usize>() as f64 / mined.len() as f64)
+    };
+
+    ChainStats {
+        num_blocks: blocks.len(),
+        windows: windows.iter().map(|&window| window_stats(&history, window)).collect(),
+        average_records_per_block,
+        blocks_per_miner,
+        blocks: history,
+    }
+}
+
+fn format_optional(value: Option<f64>, precision: usize) -> String {
+    match value {
+        Some(value) => format!("{:.*}", precision, value),
+        None => "-".to_string(),
+    }
+}
+
+impl ChainStats {
+    // Human readable summary. The difficulty history only lists the blocks at which the
+    // difficulty changed, the full history is in the JSON and CSV output.
+    pub fn to_table(&self) -> String {
+        let mut table = format!("Blocks: {}\n\n", self.num_blocks);
+
+        table.push_str(&format!("{:>8} {:>8} {:>14} {:>14} {:>16}\n",
+            "window", "blocks", "avg time [s]", "median [s]", "hashrate [H/s]"));
+        for window in &self.windows {
+            table.push_str(&format!("{:>8} {:>8} {:>14} {:>14} {:>16}\n",
+                window.window,
+                window.blocks,
+                format_optional(window.average_block_time, 1),
+                format_optional(window.median_block_time, 1),
+                format_optional(window.hashrate, 0)));
+        }
+
+        table.push_str(&format!("\nRecords per block: {}\n",
+            format_optional(self.average_records_per_block, 2)));
+
+        table.push_str("\nDifficulty history:\n");
+        table.push_str(&format!("{:>8} {:>12} {:>14}\n", "block", "bits", "work"));
+        let mut previous_bits = None;
+        for block in self.blocks.iter().skip(1) {
+            if previous_bits != Some(block.difficulty_bits) {
+                table.push_str(&format!("{:>8} {:>#12x} {:>14.3e}\n",
+                    block.idx, block.difficulty_bits, block.work));
+                previous_bits = Some(block.difficulty_bits);
+            }
+        }
+
+        table.push_str("\nBlocks per miner:\n");
+        for (miner, blocks) in &self.blocks_per_miner {
+            table.push_str(&format!("{:>8} {}\n", blocks, miner));
+        }
+
+        table
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("can serialize chain stats")
+    }
+
+    // One row per block, which is what charts are drawn from; the window statistics can be
+    // computed from these rows
+    pub fn to_csv(&self) -> String {
+        let mut csv = "idx,timestamp,block_time,difficulty_bits,work,records,miner_peer_id\n".to_string();
+        for block in &self.blocks {
+            csv.push_str(&format!("{},{},{},{:#010x},{},{},{}\n",
+                block.idx,
+                block.timestamp,
+                block.block_time.map(|time| time.to_string()).unwrap_or_default(),
+                block.difficulty_bits,
+                block.work,
+                block.records,
+                block.miner_peer_id.as_deref().unwrap_or_default()));
+        }
+
+        csv
+    }
+}
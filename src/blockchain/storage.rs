@@ -0,0 +1,240 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::{mpsc, Arc, RwLock, RwLockReadGuard};
+use std::thread;
+use log::{info, warn};
+
+use crate::blockchain::block::{Block, Record};
+use crate::blockchain::chain::Chain;
+use crate::blockchain::hash::BlockHash;
+use crate::blockchain::metadata::ChainMetadata;
+use crate::blockchain::store::{BlockReader, BlockStore};
+
+type SharedStore = Arc<RwLock<Box<dyn BlockStore + Send + Sync>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageError {
+    // The chain changed since the caller looked at it, e.g. a block of a peer and a mined block
+    // at the same height. Nothing was written.
+    Conflict(String),
+    // The store failed to write the change
+    Failed(String),
+    // The storage thread is gone
+    Closed,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Conflict(reason) => write!(f, "conflicting write rejected: {}", reason),
+            StorageError::Failed(reason) => write!(f, "write failed: {}", reason),
+            StorageError::Closed => write!(f, "the storage thread is not running"),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+type Reply = mpsc::Sender<Result<(), StorageError>>;
+
+enum StorageCommand {
+    Append { block: Block, reply: Reply },
+    // expected_tip is the hash of the tip the caller based the new chain on (None if it saw
+    // no chain at all)
+    Replace { chain: Chain, expected_tip: Option<BlockHash>, reply: Reply },
+    RemoveTip { hash: BlockHash, reply: Reply },
+}
+
+impl fmt::Debug for StorageCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageCommand::Append { block, .. } => write!(f, "Append({})", block.idx),
+            StorageCommand::Replace { chain, .. } => write!(f, "Replace({} blocks)", chain.blocks.len()),
+            StorageCommand::RemoveTip { hash, .. } => write!(f, "RemoveTip({})", hash),
+        }
+    }
+}
+
+/*
+    Handle for changing the local chain. Every change is sent to the storage thread, which
+    applies them one by one and checks each of them against the current tip first, so that
+    two writers that saw the same chain (e.g. the miner and the network handler appending a
+    block at the same height) cannot both succeed. The calls block until the change is written.
+ */
+#[derive(Debug, Clone)]
+pub struct StorageWriter {
+    commands: mpsc::Sender<StorageCommand>,
+}
+
+impl StorageWriter {
+    fn send(&self, command: impl FnOnce(Reply) -> StorageCommand) -> Result<(), StorageError> {
+        let (reply, result) = mpsc::channel();
+        self.commands.send(command(reply)).map_err(|_| StorageError::Closed)?;
+        result.recv().map_err(|_| StorageError::Closed)?
+    }
+
+    // Adds the block if it extends the current tip. The caller is responsible for validating it.
+    pub fn append(&self, block: Block) -> Result<(), StorageError> {
+        self.send(|reply| StorageCommand::Append { block, reply })
+    }
+
+    // Replaces the chain (and its metadata) if its tip is still the one the caller saw
+    pub fn replace(&self, chain: Chain, expected_tip: Option<BlockHash>) -> Result<(), StorageError> {
+        self.send(|reply| StorageCommand::Replace { chain, expected_tip, reply })
+    }
+
+    // Removes the last block if it is the given one
+    pub fn remove_tip(&self, hash: BlockHash) -> Result<(), StorageError> {
+        self.send(|reply| StorageCommand::RemoveTip { hash, reply })
+    }
+}
+
+/*
+    Read-only handle to the local chain. Single reads can be made directly on the handle;
+    reads which have to see the same chain (e.g. loading the whole chain) should go through a
+    snapshot, which keeps the chain from changing until it is dropped.
+ */
+#[derive(Clone)]
+pub struct ChainReader {
+    store: SharedStore,
+}
+
+pub struct ChainSnapshot<'a> {
+    store: RwLockReadGuard<'a, Box<dyn BlockStore + Send + Sync>>,
+}
+
+impl ChainReader {
+    // Writes wait for the snapshot, so it should not be kept for long
+    pub fn snapshot(&self) -> ChainSnapshot<'_> {
+        // A panic of a writer is reported by the storage thread, the store itself is still
+        // in the state the last write left it in
+        ChainSnapshot { store: self.store.read().unwrap_or_else(|e| e.into_inner()) }
+    }
+}
+
+impl BlockReader for ChainReader {
+    fn get(&self, height: u64) -> Option<Block> {
+        self.snapshot().get(height)
+    }
+
+    fn range(&self, start: u64, end: u64) -> Option<Vec<Block>> {
+        self.snapshot().range(start, end)
+    }
+
+    fn tip(&self) -> Option<Block> {
+        self.snapshot().tip()
+    }
+
+    fn len(&self) -> u64 {
+        self.snapshot().len()
+    }
+
+    fn record_height(&self, record: &Record) -> Option<u64> {
+        self.snapshot().record_height(record)
+    }
+
+    fn metadata(&self) -> Option<ChainMetadata> {
+        self.snapshot().metadata()
+    }
+}
+
+impl BlockReader for ChainSnapshot<'_> {
+    fn get(&self, height: u64) -> Option<Block> {
+        self.store.get(height)
+    }
+
+    fn range(&self, start: u64, end: u64) -> Option<Vec<Block>> {
+        self.store.range(start, end)
+    }
+
+    fn tip(&self) -> Option<Block> {
+        self.store.tip()
+    }
+
+    fn len(&self) -> u64 {
+        self.store.len()
+    }
+
+    fn record_height(&self, record: &Record) -> Option<u64> {
+        self.store.record_height(record)
+    }
+
+    fn metadata(&self) -> Option<ChainMetadata> {
+        self.store.metadata()
+    }
+}
+
+fn check_tip(store: &dyn BlockReader, expected_tip: Option<BlockHash>) -> Result<(), StorageError> {
+    let tip_hash = store.tip().map(|tip| tip.hash(store.pow_algorithm()));
+    if tip_hash == expected_tip {
+        return Ok(());
+    }
+    let describe = |hash: Option<BlockHash>| hash.map_or("<none>".to_string(), |hash| hash.to_string());
+    Err(StorageError::Conflict(format!("expected tip {}, but the tip is {}",
+        describe(expected_tip), describe(tip_hash))))
+}
+
+// The metadata follows the chain, a chain of another peer may have other parameters
+fn replace_chain(store: &mut dyn BlockStore, chain: &Chain, network_id: &str) -> Result<(), Box<dyn Error>> {
+    store.replace(&chain.blocks)?;
+    let metadata = ChainMetadata::new(&chain.blocks, chain.num_sidelinks, network_id)
+        .ok_or("the chain has no genesis block with chain parameters")?;
+    store.set_metadata(&metadata)
+}
+
+fn apply(store: &mut dyn BlockStore, network_id: &str, command: StorageCommand) -> (Result<(), StorageError>, Reply) {
+    let failed = |e: Box<dyn Error>| StorageError::Failed(e.to_string());
+    match command {
+        StorageCommand::Append { block, reply } => {
+            let result = match store.tip() {
+                Some(tip) if block.idx != tip.idx + 1 || block.previous_block_hash != tip.hash(store.pow_algorithm()) => {
+                    Err(StorageError::Conflict(format!("block {} does not extend the tip {} ({})",
+                        block.idx, tip.idx, tip.hash(store.pow_algorithm()))))
+                },
+                None if block.idx != 1 => {
+                    Err(StorageError::Conflict(format!("block {} cannot start a chain", block.idx)))
+                },
+                _ => store.append(&block).map_err(failed),
+            };
+            (result, reply)
+        },
+        StorageCommand::Replace { chain, expected_tip, reply } => {
+            let result = check_tip(store, expected_tip)
+                .and_then(|_| replace_chain(store, &chain, network_id).map_err(failed));
+            (result, reply)
+        },
+        StorageCommand::RemoveTip { hash, reply } => {
+            let result = check_tip(store, Some(hash))
+                .and_then(|_| store.truncate(store.len() - 1).map_err(failed));
+            (result, reply)
+        },
+    }
+}
+
+// Starts the only writer of the store. The thread stops once every StorageWriter is dropped.
+// Chains are stored as chains of the given network.
+pub fn spawn_storage(store: impl BlockStore + Send + Sync + 'static, network_id: &str) -> (StorageWriter, ChainReader) {
+    let store: SharedStore = Arc::new(RwLock::new(Box::new(store)));
+    let (commands_tx, commands_rx) = mpsc::channel::<StorageCommand>();
+
+    let thread_store = store.clone();
+    let network_id = network_id.to_string();
+    thread::Builder::new()
+        .name("storage".to_string())
+        .spawn(move || {
+            for command in commands_rx {
+                info!("[STORAGE] {:?}", command);
+                let mut store = thread_store.write().unwrap_or_else(|e| e.into_inner());
+                let (result, reply) = apply(store.as_mut(), &network_id, command);
+                drop(store);
+                if let Err(e) = &result {
+                    warn!("[STORAGE] {}", e);
+                }
+                // The caller may have given up waiting, the write stands anyway
+                reply.send(result).ok();
+            }
+        })
+        .expect("Storage thread can be spawned");
+
+    (StorageWriter { commands: commands_tx }, ChainReader { store })
+}
@@ -0,0 +1,815 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+use log::{info, warn};
+use openssl::sha::sha256;
+
+use crate::blockchain::block::{Block, Record};
+use crate::blockchain::chain::{Chain, NUM_SIDELINKS};
+use crate::blockchain::difficulty::ChainParams;
+use crate::blockchain::encoding::BlockFormat;
+use crate::blockchain::hash::HASH_BYTES;
+use crate::blockchain::metadata::ChainMetadata;
+use crate::blockchain::pow_algorithm::PowAlgorithm;
+
+/*
+    Read access to the blocks of the local chain. Blocks are addressed by their height, which
+    is the same as Block::idx, i.e. the genesis block has height 1.
+    Everything that reads the chain (validation, mining, user commands) works against this
+    trait, so it does not matter where the blocks are kept.
+ */
+pub trait BlockReader {
+    // None if there is no such block or it cannot be read
+    fn get(&self, height: u64) -> Option<Block>;
+    // Blocks with heights start..=end, None if any of them is missing
+    fn range(&self, start: u64, end: u64) -> Option<Vec<Block>>;
+    fn tip(&self) -> Option<Block>;
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Blocks with the given heights in the given order, None if any of them is missing
+    fn get_many(&self, heights: &[u64]) -> Option<Vec<Block>> {
+        heights.iter().map(|&height| self.get(height)).collect()
+    }
+
+    // Last n blocks of the chain (all of them if the chain is shorter)
+    fn last_n(&self, n: u64) -> Option<Vec<Block>> {
+        let len = self.len();
+        if len == 0 {
+            return Some(Vec::new());
+        }
+        self.range(len.saturating_sub(n) + 1, len)
+    }
+
+    // Consensus parameters stored in the genesis block
+    fn chain_params(&self) -> Option<ChainParams> {
+        self.get(1)?.genesis_params
+    }
+
+    // Hash function of the chain (see ChainParams::pow_algorithm), SHA-256 if the chain has
+    // no parameters
+    fn pow_algorithm(&self) -> &'static dyn PowAlgorithm {
+        self.chain_params()
+            .map(|params| params.pow_algorithm)
+            .unwrap_or_default()
+            .algorithm()
+    }
+
+    // Height of the block containing a record with the same content (Record::signing_payload),
+    // None if there is none. Stores keep an index of the records (see RecordIndex), others
+    // have to scan the whole chain.
+    fn record_height(&self, record: &Record) -> Option<u64> {
+        let content = record.signing_payload();
+        (1..=self.len())
+            .filter_map(|height| self.get(height))
+            .find(|block| block.records.iter().any(|included| included.signing_payload() == content))
+            .map(|block| block.idx)
+    }
+
+    // Chain-level information stored with the blocks, None if the store does not keep it
+    fn metadata(&self) -> Option<ChainMetadata> {
+        None
+    }
+
+    // Number of sidelinks the chain requires (see ChainMetadata::num_sidelinks). Chains
+    // stored before their metadata was kept fall back to the default of the network.
+    fn num_sidelinks(&self) -> usize {
+        self.metadata()
+            .map(|metadata| metadata.num_sidelinks)
+            .unwrap_or(unsafe { NUM_SIDELINKS })
+    }
+}
+
+// Storage of the local chain. The running node changes it only through the storage thread
+// (see storage::spawn_storage), every other component gets read access.
+pub trait BlockStore: BlockReader {
+    // Adds the block on top of the chain. The caller is responsible for validating it.
+    fn append(&mut self, block: &Block) -> Result<(), Box<dyn Error>>;
+    // Keeps only the first len blocks
+    fn truncate(&mut self, len: u64) -> Result<(), Box<dyn Error>>;
+    // Replaces the whole chain, e.g. with a heavier chain received from another peer
+    fn replace(&mut self, blocks: &[Block]) -> Result<(), Box<dyn Error>>;
+    fn set_metadata(&mut self, metadata: &ChainMetadata) -> Result<(), Box<dyn Error>>;
+}
+
+// Heights of the blocks containing the records, by the SHA-256 hash of the content of a record
+// (Record::signing_payload). Records are looked up for every record gossiped by a peer and
+// every record of a new block, so they are not searched for in the blocks.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordIndex(HashMap<[u8; 32], u64>);
+
+impl RecordIndex {
+    pub fn new(blocks: &[Block]) -> RecordIndex {
+        let mut index = RecordIndex::default();
+        blocks.iter().for_each(|block| index.add(block));
+        index
+    }
+
+    // A record included twice keeps the height of its first block
+    pub fn add(&mut self, block: &Block) {
+        for record in &block.records {
+            self.0.entry(sha256(&record.signing_payload())).or_insert(block.idx);
+        }
+    }
+
+    // Keeps only the records of the first len blocks
+    pub fn truncate(&mut self, len: u64) {
+        self.0.retain(|_, height| *height <= len);
+    }
+
+    pub fn get(&self, record: &Record) -> Option<u64> {
+        self.0.get(&sha256(&record.signing_payload())).copied()
+    }
+}
+
+fn get_from_slice(blocks: &[Block], height: u64) -> Option<Block> {
+    blocks.get(height.checked_sub(1)? as usize).cloned()
+}
+
+fn range_from_slice(blocks: &[Block], start: u64, end: u64) -> Option<Vec<Block>> {
+    if start == 0 || end > blocks.len() as u64 {
+        return None;
+    }
+    blocks.get(start as usize - 1..end as usize).map(<[Block]>::to_vec)
+}
+
+// Blocks kept in memory, e.g. for tests or for chains received from other peers
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryBlockStore {
+    blocks: Vec<Block>,
+    records: RecordIndex,
+    metadata: Option<ChainMetadata>,
+}
+
+#[allow(dead_code)]
+impl MemoryBlockStore {
+    pub fn new() -> MemoryBlockStore {
+        MemoryBlockStore::default()
+    }
+}
+
+impl From<Vec<Block>> for MemoryBlockStore {
+    fn from(blocks: Vec<Block>) -> MemoryBlockStore {
+        MemoryBlockStore { records: RecordIndex::new(&blocks), blocks, metadata: None }
+    }
+}
+
+impl BlockReader for MemoryBlockStore {
+    fn get(&self, height: u64) -> Option<Block> {
+        get_from_slice(&self.blocks, height)
+    }
+
+    fn range(&self, start: u64, end: u64) -> Option<Vec<Block>> {
+        range_from_slice(&self.blocks, start, end)
+    }
+
+    fn tip(&self) -> Option<Block> {
+        self.blocks.last().cloned()
+    }
+
+    fn len(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    fn record_height(&self, record: &Record) -> Option<u64> {
+        self.records.get(record)
+    }
+
+    fn metadata(&self) -> Option<ChainMetadata> {
+        self.metadata.clone()
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn append(&mut self, block: &Block) -> Result<(), Box<dyn Error>> {
+        self.blocks.push(block.clone());
+        self.records.add(block);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Box<dyn Error>> {
+        self.blocks.truncate(len as usize);
+        self.records.truncate(len);
+        Ok(())
+    }
+
+    fn replace(&mut self, blocks: &[Block]) -> Result<(), Box<dyn Error>> {
+        self.blocks = blocks.to_vec();
+        self.records = RecordIndex::new(blocks);
+        Ok(())
+    }
+
+    fn set_metadata(&mut self, metadata: &ChainMetadata) -> Result<(), Box<dyn Error>> {
+        self.metadata = Some(metadata.clone());
+        Ok(())
+    }
+}
+
+// A chain is kept in memory as well, so blocks can be validated against it directly
+impl BlockReader for Chain {
+    fn get(&self, height: u64) -> Option<Block> {
+        get_from_slice(&self.blocks, height)
+    }
+
+    fn range(&self, start: u64, end: u64) -> Option<Vec<Block>> {
+        range_from_slice(&self.blocks, start, end)
+    }
+
+    fn tip(&self) -> Option<Block> {
+        self.blocks.last().cloned()
+    }
+
+    fn len(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    fn num_sidelinks(&self) -> usize {
+        self.num_sidelinks
+    }
+}
+
+impl BlockStore for Chain {
+    fn append(&mut self, block: &Block) -> Result<(), Box<dyn Error>> {
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Box<dyn Error>> {
+        self.blocks.truncate(len as usize);
+        Ok(())
+    }
+
+    fn replace(&mut self, blocks: &[Block]) -> Result<(), Box<dyn Error>> {
+        self.blocks = blocks.to_vec();
+        Ok(())
+    }
+
+    // The chain keeps only the part of the metadata it needs to validate blocks
+    fn set_metadata(&mut self, metadata: &ChainMetadata) -> Result<(), Box<dyn Error>> {
+        self.num_sidelinks = metadata.num_sidelinks;
+        Ok(())
+    }
+}
+
+/*
+    Blocks stored in a file, one entry per block in the order of their heights. The entries
+    depend on the format of the file (see encoding::BlockFormat):
+        - json: the JSON serialized block followed by a newline (JSON Lines),
+        - binary: the length of the encoded block (8 bytes, big-endian) followed by the block
+          in the canonical binary encoding.
+    The format of an existing file can be told from its first byte (see detect_format).
+    Next to the file there is an index (<file>.idx) with the byte offset of every entry, so
+    that a block is read with a single seek instead of a scan of the file:
+        - 8 bytes: length of the block file the index was built for,
+        - 8 bytes: offset of the last entry of the block file (its length if there is none),
+        - 32 bytes: SHA-256 hash of the last entry,
+        - 8 bytes for every block: offset of its entry in the block file,
+    all numbers big-endian. The index is kept up to date by the store. If the block file was
+    changed without it (its length or its last entry differs from the ones in the index), the
+    index is rebuilt before it is used. The block file is compared with the index once, when
+    the store reads it for the first time (or by check_index), changes made behind the back
+    of an open store are not noticed.
+    Appended blocks are synced to the disk before append returns. Full rewrites go to a
+    temporary file (<file>.tmp) which then replaces the block file, so that a crash leaves
+    either the old or the new chain. A crash during an append can still leave a partial last
+    entry, which the startup recovery (FileBlockStore::recover) cuts off.
+    Chain-level metadata (see metadata::ChainMetadata) is kept in <file>.meta as JSON. It is
+    written after the blocks, so a chain can be found without it, but not the other way round.
+    The index of the records (see RecordIndex) is kept in memory only. It is built from the
+    block file when a record is looked up for the first time and follows the writes of the
+    store from then on.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileBlockStore {
+    path: String,
+    format: BlockFormat,
+    // Set once the index is known to match the block file
+    index_checked: OnceLock<()>,
+    records: OnceLock<RecordIndex>,
+}
+
+const INDEX_ENTRY_BYTES: u64 = 8;
+const INDEX_HEADER_BYTES: u64 = 8 + 8 + HASH_BYTES as u64;
+const FRAME_HEADER_BYTES: u64 = 8;
+// Chunk size used to look for the start of the last line of a JSON block file
+const RECOVERY_READ_BYTES: u64 = 4096;
+
+// What the startup recovery of a block file repaired
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryReport {
+    // Bytes of a partially written last block which were cut off
+    pub trimmed_bytes: u64,
+    // A full rewrite was interrupted before it replaced the block file
+    pub removed_temp_file: bool,
+    pub index_rebuilt: bool,
+}
+
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        *self == RecoveryReport::default()
+    }
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_clean() {
+            return write!(f, "nothing to repair");
+        }
+        let mut repairs = Vec::new();
+        if self.trimmed_bytes > 0 {
+            repairs.push(format!("cut off a partial last block ({} bytes)", self.trimmed_bytes));
+        }
+        if self.removed_temp_file {
+            repairs.push("removed the file of an interrupted rewrite".to_string());
+        }
+        if self.index_rebuilt {
+            repairs.push("rebuilt the index".to_string());
+        }
+        write!(f, "{}", repairs.join(", "))
+    }
+}
+
+// Replaces the file at path with the given content: the content is written and synced to a
+// temporary file first, which is then renamed over the file
+fn write_atomically(path: &str, temp_path: &str, content: &[u8]) -> io::Result<()> {
+    let mut temp_file = File::create(temp_path)?;
+    temp_file.write_all(content)?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+    std::fs::rename(temp_path, path)?;
+    // The rename itself is durable only once the directory is synced. Directories cannot be
+    // opened on every platform, in which case the rename is left to the OS.
+    let parent = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+// Offset of the first byte after the last newline before end, 0 if there is none
+fn line_start_before(file: &mut File, end: u64) -> io::Result<u64> {
+    let mut chunk_end = end;
+    while chunk_end > 0 {
+        let chunk_start = chunk_end.saturating_sub(RECOVERY_READ_BYTES);
+        let mut chunk = vec![0; (chunk_end - chunk_start) as usize];
+        file.seek(SeekFrom::Start(chunk_start))?;
+        file.read_exact(&mut chunk)?;
+        if let Some(position) = chunk.iter().rposition(|&byte| byte == b'\n') {
+            return Ok(chunk_start + position as u64 + 1);
+        }
+        chunk_end = chunk_start;
+    }
+    Ok(0)
+}
+
+impl FileBlockStore {
+    // Store of JSON serialized blocks
+    pub fn new(path: impl Into<String>) -> FileBlockStore {
+        FileBlockStore::with_format(path, BlockFormat::Json)
+    }
+
+    pub fn with_format(path: impl Into<String>, format: BlockFormat) -> FileBlockStore {
+        FileBlockStore { path: path.into(), format, index_checked: OnceLock::new(), records: OnceLock::new() }
+    }
+
+    // Format of the blocks in the file, None if there is no file or it is empty. JSON entries
+    // start with '{', binary ones with the high byte of a length, which is 0 for any block.
+    pub fn detect_format(path: &str) -> io::Result<Option<BlockFormat>> {
+        let mut first_byte = [0];
+        match File::open(path).and_then(|mut file| file.read(&mut first_byte)) {
+            Ok(0) => Ok(None),
+            Ok(_) => match first_byte[0] {
+                b'{' => Ok(Some(BlockFormat::Json)),
+                0 => Ok(Some(BlockFormat::Binary)),
+                byte => Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{} does not start with a block (first byte {:#04x})", path, byte))),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn encode_entry(&self, block: &Block) -> Result<Vec<u8>, Box<dyn Error>> {
+        let block = self.format.encode_block(block)?;
+        Ok(match self.format {
+            BlockFormat::Json => [block.as_slice(), b"\n"].concat(),
+            BlockFormat::Binary => [(block.len() as u64).to_be_bytes().as_slice(), &block].concat(),
+        })
+    }
+
+    fn decode_entry(&self, entry: &[u8]) -> io::Result<Block> {
+        let block = match self.format {
+            BlockFormat::Json => entry.strip_suffix(b"\n").unwrap_or(entry),
+            BlockFormat::Binary => entry.get(FRAME_HEADER_BYTES as usize..).unwrap_or_default(),
+        };
+        self.format.decode_block(block)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /*
+        Offsets of all entries of the block file and the end of the last complete one. An
+        entry which is not complete (a line without a newline, a binary entry shorter than its
+        length) is included and runs to the end of the file.
+     */
+    fn scan_entries(&self) -> io::Result<(Vec<u64>, u64)> {
+        let file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        let mut reader = io::BufReader::new(file);
+        let mut offsets = Vec::new();
+        let mut offset: u64 = 0;
+        let mut line = Vec::new();
+        while offset < len {
+            offsets.push(offset);
+            let entry_len = match self.format {
+                BlockFormat::Json => {
+                    line.clear();
+                    reader.read_until(b'\n', &mut line)? as u64
+                },
+                BlockFormat::Binary if len - offset >= FRAME_HEADER_BYTES => {
+                    let block_len = read_u64(&mut reader)?;
+                    // A block is never empty, a zero length is a header which was never written
+                    if block_len == 0 || block_len > len - offset - FRAME_HEADER_BYTES {
+                        break;
+                    }
+                    reader.seek_relative(block_len as i64)?;
+                    FRAME_HEADER_BYTES + block_len
+                },
+                BlockFormat::Binary => break,
+            };
+            if self.format == BlockFormat::Json && !line.ends_with(b"\n") {
+                break;
+            }
+            offset += entry_len;
+        }
+
+        Ok((offsets, offset))
+    }
+
+    fn index_path(&self) -> String {
+        format!("{}.idx", self.path)
+    }
+
+    fn temp_path(&self) -> String {
+        format!("{}.tmp", self.path)
+    }
+
+    fn metadata_path(&self) -> String {
+        format!("{}.meta", self.path)
+    }
+
+    fn data_len(&self) -> io::Result<u64> {
+        Ok(std::fs::metadata(&self.path)?.len())
+    }
+
+    fn index_is_stale(&self) -> io::Result<bool> {
+        let mut index = match File::open(self.index_path()) {
+            Ok(index) => index,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        let index_len = index.metadata()?.len();
+        if index_len < INDEX_HEADER_BYTES || !(index_len - INDEX_HEADER_BYTES).is_multiple_of(INDEX_ENTRY_BYTES) {
+            return Ok(true);
+        }
+        let mut header = vec![0; INDEX_HEADER_BYTES as usize];
+        index.read_exact(&mut header)?;
+        let last_offset = if index_len > INDEX_HEADER_BYTES {
+            index.seek(SeekFrom::End(-(INDEX_ENTRY_BYTES as i64)))?;
+            read_u64(&mut index)?
+        } else {
+            self.data_len()?
+        };
+        if last_offset > self.data_len()? {
+            return Ok(true);
+        }
+        // Same length is not enough, the block file may have been rewritten with other blocks
+        Ok(header != self.read_index_header(last_offset)?)
+    }
+
+    // Index header for the block file as it is on the disk, with its last entry at last_offset
+    fn read_index_header(&self, last_offset: u64) -> io::Result<Vec<u8>> {
+        let mut data = File::open(&self.path)?;
+        let data_len = data.metadata()?.len();
+        let mut last_entry = vec![0; (data_len - last_offset) as usize];
+        data.seek(SeekFrom::Start(last_offset))?;
+        data.read_exact(&mut last_entry)?;
+        Ok(index_header(data_len, last_offset, &last_entry))
+    }
+
+    // Builds the index from scratch by scanning the block file once. Returns the number of
+    // blocks in the file.
+    pub fn rebuild_index(&self) -> io::Result<u64> {
+        let (offsets, _) = self.scan_entries()?;
+        let num_blocks = offsets.len() as u64;
+        let last_offset = match offsets.last() {
+            Some(&offset) => offset,
+            None => self.data_len()?,
+        };
+        let mut index_file = self.read_index_header(last_offset)?;
+        for offset in offsets {
+            index_file.extend_from_slice(&offset.to_be_bytes());
+        }
+        write_atomically(&self.index_path(), &format!("{}.tmp", self.index_path()), &index_file)?;
+        info!("[BLOCK STORE] Index of {} rebuilt, {} blocks", self.path, num_blocks);
+
+        Ok(num_blocks)
+    }
+
+    // Rebuilds the index if the block file was changed behind its back, returns whether it
+    // did. A missing block file is not an error, the chain may not exist yet.
+    pub fn check_index(&self) -> io::Result<bool> {
+        if !Path::new(&self.path).exists() {
+            return Ok(false);
+        }
+        let stale = self.index_is_stale()?;
+        if stale {
+            warn!("[BLOCK STORE] Index of {} is missing or stale, rebuilding it", self.path);
+            self.rebuild_index()?;
+        }
+        let _ = self.index_checked.set(());
+        Ok(stale)
+    }
+
+    /*
+        Startup recovery after a crash or power loss:
+            - a temporary file left by an interrupted rewrite is removed (the block file was not
+              replaced yet, so it still holds the old chain),
+            - a partial last block (a last line without a newline or one which is not valid
+              JSON, a binary entry shorter than its length) is cut off,
+            - the index is rebuilt if it does not match the block file.
+        Only the end of the file is checked, blocks in the middle of the chain are never removed.
+     */
+    pub fn recover(&self) -> io::Result<RecoveryReport> {
+        let mut report = RecoveryReport::default();
+        match std::fs::remove_file(self.temp_path()) {
+            Ok(()) => report.removed_temp_file = true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if !Path::new(&self.path).exists() {
+            return Ok(report);
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let len = file.metadata()?.len();
+        let valid_len = match self.format {
+            BlockFormat::Json => FileBlockStore::complete_json_len(&mut file, len)?,
+            // Binary entries are written with their length, so a partial one is too short
+            BlockFormat::Binary => self.scan_entries()?.1,
+        };
+        if valid_len < len {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+            report.trimmed_bytes = len - valid_len;
+        }
+        drop(file);
+
+        report.index_rebuilt = self.check_index()?;
+        Ok(report)
+    }
+
+    // Length of the JSON block file without a partial last line. Only the end of the file is
+    // read, so it does not matter how long the chain is.
+    fn complete_json_len(file: &mut File, len: u64) -> io::Result<u64> {
+        // Everything after the last newline was not completely written
+        let valid_len = line_start_before(file, len)?;
+        if valid_len == 0 {
+            return Ok(0);
+        }
+        let last_line_start = line_start_before(file, valid_len - 1)?;
+        let mut last_line = vec![0; (valid_len - 1 - last_line_start) as usize];
+        file.seek(SeekFrom::Start(last_line_start))?;
+        file.read_exact(&mut last_line)?;
+        // Any complete JSON value, a block of a newer version is not a partial write
+        if serde_json::from_slice::<serde_json::Value>(&last_line).is_err() {
+            return Ok(last_line_start);
+        }
+        Ok(valid_len)
+    }
+
+    // Index which is up to date with the block file
+    fn open_index(&self) -> io::Result<File> {
+        if self.index_checked.get().is_none() {
+            self.check_index()?;
+        }
+        File::open(self.index_path())
+    }
+
+    fn num_blocks(index: &File) -> io::Result<u64> {
+        Ok((index.metadata()?.len() - INDEX_HEADER_BYTES) / INDEX_ENTRY_BYTES)
+    }
+
+    // Byte offsets of the entries of blocks start..=end and of the end of the last of them
+    fn read_offsets(&self, index: &mut File, start: u64, end: u64) -> io::Result<Vec<u64>> {
+        let num_blocks = FileBlockStore::num_blocks(index)?;
+        index.seek(SeekFrom::Start(INDEX_HEADER_BYTES + (start - 1) * INDEX_ENTRY_BYTES))?;
+        let mut offsets = Vec::new();
+        for _ in start..=end {
+            offsets.push(read_u64(index)?);
+        }
+        // The entry of the last block in the file ends where the file ends
+        if end < num_blocks {
+            offsets.push(read_u64(index)?);
+        } else {
+            index.seek(SeekFrom::Start(0))?;
+            offsets.push(read_u64(index)?);
+        }
+
+        Ok(offsets)
+    }
+
+    // All blocks of the file, unlike BlockReader::range with the reason if they cannot be read
+    pub fn read_all(&self) -> io::Result<Vec<Block>> {
+        let len = FileBlockStore::num_blocks(&self.open_index()?)?;
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(self.read_range(1, len)?.unwrap_or_default())
+    }
+
+    fn read_range(&self, start: u64, end: u64) -> io::Result<Option<Vec<Block>>> {
+        let mut index = self.open_index()?;
+        if start == 0 || end < start || end > FileBlockStore::num_blocks(&index)? {
+            return Ok(None);
+        }
+        let offsets = self.read_offsets(&mut index, start, end)?;
+
+        let mut data = File::open(&self.path)?;
+        data.seek(SeekFrom::Start(offsets[0]))?;
+        let mut bytes = vec![0; (offsets[offsets.len() - 1] - offsets[0]) as usize];
+        data.read_exact(&mut bytes)?;
+
+        let mut blocks = Vec::with_capacity(offsets.len() - 1);
+        for entry_offsets in offsets.windows(2) {
+            let entry = &bytes[(entry_offsets[0] - offsets[0]) as usize..(entry_offsets[1] - offsets[0]) as usize];
+            blocks.push(self.decode_entry(entry)?);
+        }
+
+        Ok(Some(blocks))
+    }
+}
+
+fn index_header(data_len: u64, last_offset: u64, last_entry: &[u8]) -> Vec<u8> {
+    [data_len.to_be_bytes().as_slice(), &last_offset.to_be_bytes(), &sha256(last_entry)].concat()
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl BlockReader for FileBlockStore {
+    fn get(&self, height: u64) -> Option<Block> {
+        self.range(height, height)?.pop()
+    }
+
+    fn range(&self, start: u64, end: u64) -> Option<Vec<Block>> {
+        match self.read_range(start, end) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                warn!("[BLOCK STORE] Error while reading blocks {}..={} from {}: {}",
+                    start, end, self.path, e);
+                None
+            }
+        }
+    }
+
+    fn tip(&self) -> Option<Block> {
+        match self.len() {
+            0 => None,
+            len => self.get(len),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self.open_index().and_then(|index| FileBlockStore::num_blocks(&index)) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("[BLOCK STORE] Error while reading the index of {}: {}", self.path, e);
+                0
+            }
+        }
+    }
+
+    fn record_height(&self, record: &Record) -> Option<u64> {
+        if let Some(records) = self.records.get() {
+            return records.get(record);
+        }
+        match self.read_all() {
+            Ok(blocks) => self.records.get_or_init(|| RecordIndex::new(&blocks)).get(record),
+            Err(e) => {
+                warn!("[BLOCK STORE] Error while reading the records of {}: {}", self.path, e);
+                None
+            }
+        }
+    }
+
+    fn metadata(&self) -> Option<ChainMetadata> {
+        let metadata = match std::fs::read(self.metadata_path()) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("[BLOCK STORE] Error while reading the metadata of {}: {}", self.path, e);
+                return None;
+            }
+        };
+        match serde_json::from_slice(&metadata) {
+            Ok(metadata) => Some(metadata),
+            Err(e) => {
+                warn!("[BLOCK STORE] Invalid metadata of {}: {}", self.path, e);
+                None
+            }
+        }
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn append(&mut self, block: &Block) -> Result<(), Box<dyn Error>> {
+        // The first block of a chain creates the block file, its index is built when it is opened
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let index = self.open_index()
+            .map_err(|e| format!("Error while opening the file to append the block: {}", e))?;
+        let num_blocks = FileBlockStore::num_blocks(&index)?;
+        let offset = file.metadata()?.len();
+
+        let entry = self.encode_entry(block)?;
+        file.write_all(&entry)?;
+        file.sync_data()?;
+
+        // The block file is written first, if anything fails afterwards the length in the
+        // index header does not match and the index is rebuilt
+        drop(index);
+        let mut index = OpenOptions::new().write(true).open(self.index_path())?;
+        index.seek(SeekFrom::Start(INDEX_HEADER_BYTES + num_blocks * INDEX_ENTRY_BYTES))?;
+        index.write_all(&offset.to_be_bytes())?;
+        // The header is synced after the entry, so it never covers an entry which is not there
+        index.sync_data()?;
+        index.seek(SeekFrom::Start(0))?;
+        index.write_all(&index_header(file.metadata()?.len(), offset, &entry))?;
+        index.sync_data()?;
+        if let Some(records) = self.records.get_mut() {
+            records.add(block);
+        }
+
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Box<dyn Error>> {
+        let mut index = self.open_index()?;
+        let num_blocks = FileBlockStore::num_blocks(&index)?;
+        if len >= num_blocks {
+            return Ok(());
+        }
+        index.seek(SeekFrom::Start(INDEX_HEADER_BYTES + len.saturating_sub(1) * INDEX_ENTRY_BYTES))?;
+        // Offset of the last kept block, then of the first removed one, where the kept blocks end
+        let last_offset = read_u64(&mut index)?;
+        let offset = if len == 0 { last_offset } else { read_u64(&mut index)? };
+        drop(index);
+
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_len(offset)?;
+        file.sync_all()?;
+        let header = self.read_index_header(if len == 0 { offset } else { last_offset })?;
+        let mut index = OpenOptions::new().write(true).open(self.index_path())?;
+        index.set_len(INDEX_HEADER_BYTES + len * INDEX_ENTRY_BYTES)?;
+        index.write_all(&header)?;
+        index.sync_data()?;
+        if let Some(records) = self.records.get_mut() {
+            records.truncate(len);
+        }
+
+        Ok(())
+    }
+
+    fn replace(&mut self, blocks: &[Block]) -> Result<(), Box<dyn Error>> {
+        let mut content = Vec::new();
+        for block in blocks {
+            content.extend_from_slice(&self.encode_entry(block)?);
+        }
+        // The live file is only replaced once the new chain is completely on the disk
+        write_atomically(&self.path, &self.temp_path(), &content)?;
+        self.records = OnceLock::from(RecordIndex::new(blocks));
+        self.rebuild_index()?;
+
+        Ok(())
+    }
+
+    fn set_metadata(&mut self, metadata: &ChainMetadata) -> Result<(), Box<dyn Error>> {
+        let metadata_path = self.metadata_path();
+        write_atomically(&metadata_path, &format!("{}.tmp", metadata_path),
+            serde_json::to_string_pretty(metadata)?.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use rug::{Integer, integer::Order};
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+use std::fmt;
+
+// Tokens are 256 bit long, so is every target
+pub const TARGET_BYTES: usize = 32;
+
+/*
+    Proof of work target (the "difficulty" of a block): a token is valid if it is smaller than
+    the target when both are read as big-endian numbers.
+    The value is kept as an exact big integer in the range [0, 2^256 - 1]. In JSON it is stored
+    as 32 big-endian bytes (the format difficulties were always stored in), and it can also be
+    encoded as compact "bits" (same format as in Bitcoin) for display and compact transfer.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target(Integer);
+
+impl Target {
+    pub fn max() -> Target {
+        Target((Integer::from(1) << (TARGET_BYTES as u32 * 8)) - 1)
+    }
+
+    // No token is smaller than zero, so nothing can be mined with this target
+    pub fn zero() -> Target {
+        Target(Integer::new())
+    }
+
+    // Values outside of the range of tokens are clamped to it
+    pub fn from_integer(value: Integer) -> Target {
+        if value < 0 {
+            Target::zero()
+        } else if value > Target::max().0 {
+            Target::max()
+        } else {
+            Target(value)
+        }
+    }
+
+    // Big-endian number of at most 32 bytes
+    pub fn from_bytes(bytes: &[u8]) -> Target {
+        Target::from_integer(Integer::from_digits(bytes, Order::MsfBe))
+    }
+
+    pub fn to_bytes(&self) -> [u8; TARGET_BYTES] {
+        let digits = self.0.to_digits::<u8>(Order::MsfBe);
+        let mut bytes = [0; TARGET_BYTES];
+        bytes[TARGET_BYTES - digits.len()..].copy_from_slice(&digits);
+        bytes
+    }
+
+    // Target at which a block takes the given number of hashes on average, i.e. the inverse
+    // of Target::work
+    pub fn from_expected_hashes(hashes: &Integer) -> Target {
+        if *hashes <= 1 {
+            return Target::max();
+        }
+        Target::from_integer((Integer::from(1) << (TARGET_BYTES as u32 * 8)) / hashes - 1u32)
+    }
+
+    pub fn is_met_by(&self, token: &[u8; TARGET_BYTES]) -> bool {
+        token < &self.to_bytes()
+    }
+
+    // Expected number of hashes needed to find a token smaller than the target, i.e.
+    // 2^256 / (target + 1)
+    pub fn work(&self) -> Integer {
+        (Integer::from(1) << (TARGET_BYTES as u32 * 8)) / (self.0.clone() + 1u32)
+    }
+
+    // target * numerator / denominator without any rounding but the final division
+    pub fn scale(&self, numerator: u64, denominator: u64) -> Target {
+        Target::from_integer(self.0.clone() * numerator / denominator.max(1))
+    }
+
+    /*
+        Compact encoding: the highest byte is the length of the target in bytes, the lower three
+        bytes are its most significant bytes (the mantissa). The highest bit of the mantissa is
+        a sign bit in Bitcoin, so it is never set. The encoding keeps only the top 23 bits of
+        the target, the rest is rounded down.
+     */
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.0.significant_digits::<u8>() as u32;
+        let mut mantissa = if size <= 3 {
+            self.0.to_u32().expect("target fits in 3 bytes") << (8 * (3 - size))
+        } else {
+            (self.0.clone() >> (8 * (size - 3))).to_u32().expect("mantissa fits in 3 bytes")
+        };
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        (size << 24) | mantissa
+    }
+
+    pub fn from_compact(bits: u32) -> Target {
+        let size = bits >> 24;
+        let mantissa = Integer::from(bits & 0x007f_ffff);
+        if size <= 3 {
+            Target::from_integer(mantissa >> (8 * (3 - size)))
+        } else {
+            Target::from_integer(mantissa << (8 * (size - 3)))
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let work = self.work();
+        if work < 1_000_000 {
+            write!(f, "{:#010x} ({} hashes per block)", self.to_compact(), work)
+        } else {
+            write!(f, "{:#010x} ({:.3e} hashes per block)", self.to_compact(), work.to_f64())
+        }
+    }
+}
+
+impl Serialize for Target {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_bytes().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Target {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Target, D::Error> {
+        let mut bytes = Vec::<u8>::deserialize(deserializer)?;
+        if bytes.len() > TARGET_BYTES {
+            return Err(serde::de::Error::invalid_length(bytes.len(), &"at most 32 bytes"));
+        }
+        // Shorter difficulties were compared byte by byte with the tokens, i.e. as if they
+        // were padded with zeros on the right
+        bytes.resize(TARGET_BYTES, 0);
+        Ok(Target::from_bytes(&bytes))
+    }
+}
@@ -8,7 +8,11 @@ use std::fs::File;
 use std::io::Write;
 use crate::blockchain::{
     chain::Chain,
-    block::Block
+    receipt::RecordReceipt,
+    pow::MiningCommand,
+    stats::{chain_stats, DEFAULT_STATS_WINDOWS},
+    store::BlockReader,
+    storage::ChainReader,
 };
 
 // TODO: remove all .expect and perform proper error handling
@@ -17,39 +21,101 @@ use crate::blockchain::{
     Possible commands:
         help                                    - print this message
         listpeers                               - print peers
-        init d=<difficulty> sl=<num sidelinks>  - initialize the blockchain
+        init d=<difficulty> sl=<num sidelinks> [pow=sha256|sha3-256] [bits=<compact target>] - initialize the blockchain
         blocks [<start>..<end>|[comma-separated indexes]|n|"all"] [file to write to]
-        rec <data>                              - add record to the last block of the chain
+        rec [--key <key file>] <data>           - add record signed with your identity (or the given key)
+        mine start|stop|pause|<n>               - start, stop or pause mining, or mine n blocks and stop
+        bench                                   - measure the hashrate of this node on all mining threads
+        keygen <file>                           - generate a new ed25519 key for signing records
         printblock  <block index>               - display contents of a chosen block
         numberblocks                            - display number of blocks in the chain
         talk <message>                          - send a text message to all other peers (will wave if no message is provided)
         myid                                    - print your peer id
         myfile                                  - print your blockchain file path
+        proof <block>.<record> <file>           - write a receipt proving that the record is in the chain
+        stats [<windows>] [table|json|csv] [file] - block times, hashrate and difficulty of the chain
+        verify-receipt <file>                   - verify a receipt written by the proof command
         exit                                    - exit the program
  */
 
 pub fn print_cmd_options() {
     println!("Possible commands:\n\
         \thelp                                      - print this message\n\
-        \tinit d=<difficulty> sl=<num sidelinks>    - initialize the blockchain\n\
+        \tinit d=<difficulty> sl=<num sidelinks> [pow=sha256|sha3-256] [bits=<compact target>] - initialize the blockchain\n\
         \tlistpeers                                 - print peers\n\
         \tblocks [<start>..<end>|[comma-separated indexes]|n|\"all\"] [file to write to]\n\
-        \trec <data>                                - add record to the last block of the chain\n\
+        \trec [--key <key file>] <data>             - add record signed with your identity (or the given key)\n\
+        \tmine start|stop|pause|<n>                 - start, stop or pause mining, or mine n blocks and stop\n\
+        \tbench                                     - measure the hashrate of this node on all mining threads\n\
+        \tkeygen <file>                             - generate a new ed25519 key for signing records\n\
         \tprintblock  <block index>                 - display contents of a chosen block\n\
         \tnumberblocks                              - display number of blocks in the chain\n\
         \ttalk <message>                            - send a text message to all other peers\n\
         \tmyid                                      - print your peer id\n\
         \tmyfile                                    - print your blockchain file path\n\
+        \tproof <block>.<record> <file>             - write a receipt proving that the record is in the chain\n\
+        \tstats [<windows>] [table|json|csv] [file] - block times, hashrate and difficulty of the chain\n\
+        \tverify-receipt <file>                     - verify a receipt written by the proof command\n\
         \texit                                      - exit the program"
     );
 }
 
+// Parses "mine start|stop|pause|<number of blocks>"
+pub fn parse_mining_cmd(user_input: &str) -> Option<MiningCommand> {
+    let mut user_input = user_input.split_whitespace().skip(1);
+    let command = match user_input.next()? {
+        "start" => MiningCommand::Start,
+        "stop" => MiningCommand::Stop,
+        "pause" => MiningCommand::Pause,
+        num_blocks => MiningCommand::MineBlocks(num_blocks.parse::<u64>().ok()?),
+    };
+
+    if user_input.next().is_some() {
+        None
+    } else {
+        Some(command)
+    }
+}
+
+// Does not need a running node, everything required for verification is in the receipt
+pub fn verify_receipt_file(receipt_file: &str) -> bool {
+    let receipt = match std::fs::read_to_string(receipt_file)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_str::<RecordReceipt>(&data).map_err(|e| e.to_string()))
+    {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            println!("Cannot read receipt from {}: {}", receipt_file, e);
+            return false;
+        }
+    };
+
+    match receipt.verify() {
+        Ok(tip_hash) => {
+            println!("Receipt is valid. Record {:?} is included in block {} of the chain \
+                ending with block {} with hash {}. Compare this hash with a tip you trust.",
+                receipt.record.idx,
+                receipt.block_header.idx,
+                receipt.header_chain.last().unwrap_or(&receipt.block_header).idx,
+                tip_hash);
+            true
+        },
+        Err(e) => {
+            println!("Receipt is invalid: {}", e);
+            false
+        }
+    }
+}
+
 // Processing of the user input which does not involve sending new events to other threads or peers
 pub fn process_simple_cmd(user_input: String,
     swarm: &mut libp2p::Swarm<BlockchainBehaviour>,
     local_peer_id: &libp2p::PeerId,
     blockchain_file: &str,
+    chain_reader: &ChainReader,
 ) {
+    // Every command sees one state of the chain, even if blocks are added meanwhile
+    let store = chain_reader.snapshot();
     let mut user_input = user_input.split_whitespace();
     match user_input.next() {
         Some("help") => {
@@ -106,20 +172,10 @@ pub fn process_simple_cmd(user_input: String,
                         .map(|x| x.parse::<u64>().unwrap())
                         .collect();
                 } else {
-                    if let Ok(num) = val.parse::<usize>() {
+                    if let Ok(num) = val.parse::<u64>() {
                         // Read last num blocks
-                        let blockchain_length = if let Ok(len) = Chain::get_blockchain_length(blockchain_file) {
-                            len
-                        } else {
-                            println!("Cannot get blockchain length");
-                            return;
-                        };
-                        let num_to_read = if num > blockchain_length {
-                            blockchain_length
-                        } else {
-                            num
-                        };
-                        blocks_to_read = ((blockchain_length - num_to_read + 1) as u64..=blockchain_length as u64).collect();
+                        let blockchain_length = store.len();
+                        blocks_to_read = (blockchain_length - num.min(blockchain_length) + 1..=blockchain_length).collect();
                     } else {
                         println!("Cannot parse block index");
                         return;
@@ -133,14 +189,12 @@ pub fn process_simple_cmd(user_input: String,
                 file_to_write_to = Some(val);
             }
             let blocks = if all_blocks {
-                Chain::get_last_n_blocks_from_file(
-                    Chain::get_blockchain_length(blockchain_file).unwrap(),
-                    blockchain_file)
+                store.last_n(store.len())
             } else {
-                Chain::get_blocks_by_indices_from_file_in_given_order(&
-                    &blocks_to_read,
-                    Some(blocks_to_read.clone()),
-                    blockchain_file)
+                // Blocks which do not exist are skipped
+                let blockchain_length = store.len();
+                blocks_to_read.retain(|&idx| idx >= 1 && idx <= blockchain_length);
+                store.get_many(&blocks_to_read)
             };
             if let Some(file_to_write_to) = file_to_write_to {
                 let mut file = File::create(file_to_write_to).unwrap();
@@ -176,10 +230,7 @@ pub fn process_simple_cmd(user_input: String,
                 println!("No block index provided");
                 return;
             };
-            let block = if let Some(block) = Chain::load_block_from_file(
-                block_index as u64,
-                blockchain_file)
-            {
+            let block = if let Some(block) = store.get(block_index as u64) {
                 block
             } else {
                 println!("Cannot load block from file");
@@ -189,11 +240,7 @@ pub fn process_simple_cmd(user_input: String,
         },
         Some("numberblocks") => {
             println!("numberblocks received");
-            if let Ok(len) = Chain::get_blockchain_length(blockchain_file) {
-                println!("Number of blocks: {}", len);
-            } else {
-                println!("Cannot get lengt of blockchain from file");
-            };
+            println!("Number of blocks: {}", store.len());
         },
         Some("talk") => {
             println!("talk received");
@@ -209,10 +256,104 @@ pub fn process_simple_cmd(user_input: String,
             println!("myid received");
             println!("Your peer id: {}", local_peer_id.to_string());
         },
+        Some("keygen") => {
+            println!("keygen received");
+            if let Some(key_file) = user_input.next() {
+                let keypair = libp2p::identity::Keypair::generate_ed25519();
+                if let Err(e) = crate::utils::save_keypair_to_file(&keypair, key_file) {
+                    println!("Cannot save the key to {}: {}", key_file, e);
+                } else {
+                    println!("Key of author {} saved to {}", keypair.public().to_peer_id(), key_file);
+                }
+            } else {
+                println!("No file to save the key to provided");
+            }
+        },
         Some("myfile") => {
             println!("myfile received");
             println!("Your blockchain file path: {}", blockchain_file);
         },
+        Some("proof") => {
+            println!("proof received");
+            let (block_idx, record_idx) = if let Some((block_idx, record_idx)) = user_input
+                .next()
+                .and_then(|val| val.split_once('.'))
+                .and_then(|(b, r)| Some((b.parse::<u64>().ok()?, r.parse::<u64>().ok()?)))
+            {
+                (block_idx, record_idx)
+            } else {
+                println!("Record should be given as <block index>.<record index>");
+                return;
+            };
+            let receipt_file = if let Some(val) = user_input.next() {
+                val
+            } else {
+                println!("No file to write the receipt to provided");
+                return;
+            };
+            let chain = if let Some(chain) = Chain::load_from_store(&store) {
+                chain
+            } else {
+                println!("Cannot load blockchain from file");
+                return;
+            };
+            if let Some(receipt) = chain.record_receipt(block_idx, record_idx) {
+                let receipt = serde_json::to_string_pretty(&receipt).expect("can serialize receipt");
+                if let Err(e) = std::fs::write(receipt_file, receipt) {
+                    println!("Cannot write the receipt to {}: {}", receipt_file, e);
+                } else {
+                    println!("Receipt for record {}.{} written to {}",
+                        block_idx, record_idx, receipt_file);
+                }
+            } else {
+                println!("No record {}.{} in the chain", block_idx, record_idx);
+            }
+        },
+        Some("verify-receipt") => {
+            println!("verify-receipt received");
+            if let Some(receipt_file) = user_input.next() {
+                verify_receipt_file(receipt_file);
+            } else {
+                println!("No receipt file provided");
+            }
+        },
+        Some("stats") => {
+            println!("stats received");
+            // e.g. stats 10,100 csv stats.csv
+            let mut windows = DEFAULT_STATS_WINDOWS.to_vec();
+            let mut format = "table";
+            let mut output_file = None;
+            for arg in user_input {
+                if let Ok(parsed) = arg.split(',').map(|window| window.parse::<usize>()).collect() {
+                    windows = parsed;
+                } else if ["table", "json", "csv"].contains(&arg) {
+                    format = arg;
+                } else {
+                    output_file = Some(arg);
+                }
+            }
+            let chain = if let Some(chain) = Chain::load_from_store(&store) {
+                chain
+            } else {
+                println!("Cannot load blockchain from file");
+                return;
+            };
+            let stats = chain_stats(&chain.blocks, &windows);
+            let output = match format {
+                "json" => stats.to_json(),
+                "csv" => stats.to_csv(),
+                _ => stats.to_table(),
+            };
+            if let Some(output_file) = output_file {
+                if let Err(e) = std::fs::write(output_file, output) {
+                    println!("Cannot write the stats to {}: {}", output_file, e);
+                } else {
+                    println!("Stats written to {}", output_file);
+                }
+            } else {
+                println!("{}", output);
+            }
+        },
         Some("exit") => {
             println!("exit received");
             std::process::exit(0);
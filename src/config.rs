@@ -0,0 +1,353 @@
+use libp2p::Multiaddr;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::error::Error;
+
+use crate::blockchain::encoding::BlockFormat;
+use crate::blockchain::chain::{DEFAULT_DIFFICULTY_IN_SECONDS, DEFAULT_NUM_OF_SIDELINKS, DEFAULT_RETARGET_INTERVAL};
+use crate::blockchain::difficulty::{MAX_RETARGET_INTERVAL, MAX_TARGET_BLOCK_TIME};
+use crate::blockchain::metadata::DEFAULT_NETWORK_ID;
+use crate::blockchain::pow_algorithm::PowAlgorithmKind;
+use crate::data_dir::DEFAULT_DATA_DIR;
+
+/*
+    Settings of the node, read from a TOML file given with --config <file>. Every setting is
+    optional, e.g.:
+
+        data_dir = "./pliers_data"
+        log_level = "info"
+        storage_format = "binary"
+
+        [network]
+        listen_addresses = ["/ip4/0.0.0.0/tcp/4001"]
+        mdns = false
+        bootstrap_peers = ["/ip4/192.168.1.20/tcp/4001"]
+        heartbeat_secs = 10
+        wire_format = "binary"
+
+        [mining]
+        threads = 4
+
+        [chain]
+        network_id = "pliers"
+        block_time_secs = 30.0
+        num_sidelinks = 5
+        retarget_interval = 10
+        pow_algorithm = "sha3-256"
+
+    Command-line flags override the file (see Config::apply_args). Unknown settings are
+    rejected, so that a typo does not silently fall back to the default.
+ */
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: String,
+    // Log filter in the RUST_LOG syntax, e.g. "info" or "warn,pliers=debug". RUST_LOG itself
+    // wins over it.
+    pub log_level: Option<String>,
+    // Format of the chain file, "json" or "binary" (see encoding::BlockFormat)
+    pub storage_format: String,
+    pub network: NetworkConfig,
+    pub mining: MiningConfig,
+    pub chain: ChainConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub listen_addresses: Vec<String>,
+    // Discovery of the peers in the local network
+    pub mdns: bool,
+    // Peers dialed on startup, e.g. the ones mDNS cannot find
+    pub bootstrap_peers: Vec<String>,
+    // Gossipsub heartbeat
+    pub heartbeat_secs: u64,
+    // Format of the blocks proposed to the peers, "json" or "binary". Blocks of the peers are
+    // accepted in both formats, but nodes older than the binary format only read JSON.
+    pub wire_format: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiningConfig {
+    // All cores if not set
+    pub threads: Option<usize>,
+}
+
+// Parameters of the chains created with the init command, unless the command sets them
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainConfig {
+    // Network the local chain belongs to, a stored chain of another network is not used
+    pub network_id: String,
+    pub block_time_secs: f64,
+    pub num_sidelinks: usize,
+    pub retarget_interval: u64,
+    pub pow_algorithm: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            data_dir: DEFAULT_DATA_DIR.to_string(),
+            log_level: None,
+            storage_format: BlockFormat::default().name().to_string(),
+            network: NetworkConfig::default(),
+            mining: MiningConfig::default(),
+            chain: ChainConfig::default(),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            listen_addresses: vec!["/ip4/0.0.0.0/tcp/0".to_string()],
+            mdns: true,
+            bootstrap_peers: Vec::new(),
+            // Long enough to not clutter the log when debugging
+            heartbeat_secs: 10,
+            wire_format: BlockFormat::default().name().to_string(),
+        }
+    }
+}
+
+impl Default for ChainConfig {
+    fn default() -> ChainConfig {
+        ChainConfig {
+            network_id: DEFAULT_NETWORK_ID.to_string(),
+            block_time_secs: DEFAULT_DIFFICULTY_IN_SECONDS,
+            num_sidelinks: DEFAULT_NUM_OF_SIDELINKS,
+            retarget_interval: DEFAULT_RETARGET_INTERVAL,
+            pow_algorithm: PowAlgorithmKind::default().algorithm().name().to_string(),
+        }
+    }
+}
+
+impl ChainConfig {
+    // Valid once the config is validated
+    pub fn pow_algorithm(&self) -> PowAlgorithmKind {
+        PowAlgorithmKind::from_name(&self.pow_algorithm).unwrap_or_default()
+    }
+}
+
+fn parse_block_format(setting: &str, name: &str) -> Result<BlockFormat, Box<dyn Error>> {
+    BlockFormat::from_name(name)
+        .ok_or_else(|| format!("{}: unknown format \"{}\", use one of: json, binary", setting, name).into())
+}
+
+fn parse_multiaddrs(setting: &str, addresses: &[String]) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+    addresses.iter()
+        .map(|address| address.parse::<Multiaddr>()
+            .map_err(|e| format!("{}: \"{}\" is not a valid multiaddress ({})", setting, address, e).into()))
+        .collect()
+}
+
+// Each directive is either a level or <module>=<level>
+fn validate_log_level(log_level: &str) -> Result<(), Box<dyn Error>> {
+    for directive in log_level.split(',').filter(|directive| !directive.is_empty()) {
+        let level = directive.rsplit('=').next().unwrap_or_default();
+        if level.parse::<LevelFilter>().is_err() {
+            return Err(format!("log_level: unknown level \"{}\" in \"{}\", use one of: \
+                off, error, warn, info, debug, trace", level, log_level).into());
+        }
+    }
+    Ok(())
+}
+
+// Value following a flag, e.g. 4 in --mining-threads 4
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>, Box<dyn Error>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(position) => match args.get(position + 1) {
+            Some(value) => Ok(Some(value.as_str())),
+            None => Err(format!("{} needs a value", flag).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+// Values of a flag which can be given several times
+fn flag_values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|pair| pair[0] == flag)
+        .map(|pair| pair[1].as_str())
+        .collect()
+}
+
+impl Config {
+    // Default config if no file is given
+    pub fn load(path: Option<&str>) -> Result<Config, Box<dyn Error>> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let config = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read the config file {}: {}", path, e))?;
+        let config = toml::from_str(&config)
+            .map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+
+        Ok(config)
+    }
+
+    /*
+        Flags overriding the config file:
+            --data-dir <dir>
+            --log-level <filter>
+            --listen <multiaddress>     (can be given several times, replaces the listen addresses)
+            --bootstrap <multiaddress>  (can be given several times, adds a bootstrap peer)
+            --no-mdns
+            --mining-threads <n>
+     */
+    pub fn apply_args(&mut self, args: &[String]) -> Result<(), Box<dyn Error>> {
+        if let Some(data_dir) = flag_value(args, "--data-dir")? {
+            self.data_dir = data_dir.to_string();
+        }
+        if let Some(log_level) = flag_value(args, "--log-level")? {
+            self.log_level = Some(log_level.to_string());
+        }
+        let listen_addresses = flag_values(args, "--listen");
+        if !listen_addresses.is_empty() {
+            self.network.listen_addresses = listen_addresses.into_iter().map(str::to_string).collect();
+        }
+        self.network.bootstrap_peers.extend(flag_values(args, "--bootstrap").into_iter().map(str::to_string));
+        if args.iter().any(|arg| arg == "--no-mdns") {
+            self.network.mdns = false;
+        }
+        if let Some(threads) = flag_value(args, "--mining-threads")? {
+            let threads = threads.parse::<usize>()
+                .map_err(|e| format!("--mining-threads: \"{}\" is not a number ({})", threads, e))?;
+            self.mining.threads = Some(threads);
+        }
+
+        Ok(())
+    }
+
+    // Checks every setting, so that the node does not fail later on (or run with nonsense)
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.data_dir.is_empty() {
+            return Err("data_dir: must not be empty".into());
+        }
+        if let Some(log_level) = &self.log_level {
+            validate_log_level(log_level)?;
+        }
+        self.storage_format()?;
+        if self.network.listen_addresses.is_empty() {
+            return Err("network.listen_addresses: at least one address is needed".into());
+        }
+        self.listen_addresses()?;
+        self.bootstrap_peers()?;
+        if self.network.heartbeat_secs == 0 {
+            return Err("network.heartbeat_secs: must be at least 1".into());
+        }
+        self.wire_format()?;
+        if self.mining.threads == Some(0) {
+            return Err("mining.threads: must be at least 1 (use --no-mining to not mine)".into());
+        }
+        if self.chain.network_id.trim().is_empty() {
+            return Err("chain.network_id: must not be empty".into());
+        }
+        if !(self.chain.block_time_secs >= 1.0 && self.chain.block_time_secs <= MAX_TARGET_BLOCK_TIME as f64) {
+            return Err(format!("chain.block_time_secs: must be between 1 and {}, got {}",
+                MAX_TARGET_BLOCK_TIME, self.chain.block_time_secs).into());
+        }
+        if !(1..=MAX_RETARGET_INTERVAL).contains(&self.chain.retarget_interval) {
+            return Err(format!("chain.retarget_interval: must be between 1 and {}",
+                MAX_RETARGET_INTERVAL).into());
+        }
+        if PowAlgorithmKind::from_name(&self.chain.pow_algorithm).is_none() {
+            return Err(format!("chain.pow_algorithm: unknown algorithm \"{}\", use one of: sha256, sha3-256",
+                self.chain.pow_algorithm).into());
+        }
+
+        Ok(())
+    }
+
+    pub fn storage_format(&self) -> Result<BlockFormat, Box<dyn Error>> {
+        parse_block_format("storage_format", &self.storage_format)
+    }
+
+    pub fn wire_format(&self) -> Result<BlockFormat, Box<dyn Error>> {
+        parse_block_format("network.wire_format", &self.network.wire_format)
+    }
+
+    pub fn listen_addresses(&self) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+        parse_multiaddrs("network.listen_addresses", &self.network.listen_addresses)
+    }
+
+    pub fn bootstrap_peers(&self) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+        parse_multiaddrs("network.bootstrap_peers", &self.network.bootstrap_peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_config_file_and_flags() {
+        let mut config: Config = toml::from_str(r#"
+            log_level = "warn,pliers=debug"
+
+            [network]
+            listen_addresses = ["/ip4/127.0.0.1/tcp/4001"]
+            mdns = false
+
+            [chain]
+            num_sidelinks = 2
+            pow_algorithm = "sha3-256"
+        "#).unwrap();
+        config.validate().unwrap();
+        // Settings missing from the file keep their defaults
+        assert_eq!(config.data_dir, DEFAULT_DATA_DIR);
+        assert_eq!(config.chain.retarget_interval, DEFAULT_RETARGET_INTERVAL);
+        assert_eq!(config.chain.num_sidelinks, 2);
+        assert_eq!(config.chain.pow_algorithm(), PowAlgorithmKind::Sha3);
+        assert_eq!(config.storage_format().unwrap(), BlockFormat::Json);
+
+        config.apply_args(&args("pliers --data-dir node2 --listen /ip4/0.0.0.0/tcp/4002 \
+            --listen /ip4/0.0.0.0/tcp/4003 --bootstrap /ip4/10.0.0.1/tcp/4001 --mining-threads 3")).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.data_dir, "node2");
+        assert_eq!(config.listen_addresses().unwrap().len(), 2);
+        assert_eq!(config.bootstrap_peers().unwrap().len(), 1);
+        assert_eq!(config.mining.threads, Some(3));
+        assert!(!config.network.mdns);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        // Typos are errors
+        assert!(toml::from_str::<Config>("[network]\nmnds = false").is_err());
+
+        let invalid_settings = [
+            "log_level = \"loud\"",
+            "storage_format = \"xml\"",
+            "[network]\nwire_format = \"text\"",
+            "[network]\nlisten_addresses = [\"localhost:4001\"]",
+            "[network]\nlisten_addresses = []",
+            "[network]\nheartbeat_secs = 0",
+            "[mining]\nthreads = 0",
+            "[chain]\nnetwork_id = \"\"",
+            "[chain]\nblock_time_secs = 0.0",
+            "[chain]\nblock_time_secs = 1e12",
+            "[chain]\nretarget_interval = 0",
+            "[chain]\nretarget_interval = 1000000000",
+            "[chain]\npow_algorithm = \"md5\"",
+        ];
+        for settings in invalid_settings {
+            let config: Config = toml::from_str(settings).unwrap();
+            let error = config.validate().unwrap_err().to_string();
+            // The message names the setting
+            let setting = settings.trim_start_matches('[').split([']', ' ']).next().unwrap();
+            assert!(error.starts_with(setting), "{}: {}", settings, error);
+        }
+
+        let mut config = Config::default();
+        assert!(config.apply_args(&args("pliers --mining-threads many")).is_err());
+        assert!(config.apply_args(&args("pliers --data-dir")).is_err());
+    }
+}
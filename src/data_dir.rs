@@ -0,0 +1,143 @@
+use chrono::Utc;
+use libp2p::{identity, PeerId};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::encoding::BlockFormat;
+use crate::utils;
+
+// Used when --data-dir is not given. Nodes running on the same machine need a directory each.
+pub const DEFAULT_DATA_DIR: &str = "./pliers_data";
+
+const KEYPAIR_FILE: &str = "identity.key";
+const JSON_BLOCKCHAIN_FILE: &str = "blockchain.jsonl";
+const BINARY_BLOCKCHAIN_FILE: &str = "blockchain.bin";
+const METADATA_FILE: &str = "node.json";
+
+// Information about the node kept next to its chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeMetadata {
+    pub peer_id: String,
+    // UTC timestamps in seconds
+    pub created_at: u64,
+    pub last_started_at: u64,
+    // Version of pliers which started the node last
+    pub version: String,
+}
+
+/*
+    Directory holding everything a node needs to resume after a restart:
+        - identity.key: the keypair of the node (and so its peer id), in the libp2p protobuf
+          encoding like any other key file (see utils::save_keypair_to_file),
+        - blockchain.jsonl or blockchain.bin: the chain store (see store::FileBlockStore) with
+          its index and metadata, depending on the storage format,
+        - node.json: node metadata.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataDir {
+    path: PathBuf,
+}
+
+impl DataDir {
+    // Creates the directory if it does not exist yet
+    pub fn open(path: impl AsRef<Path>) -> Result<DataDir, Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(&path)?;
+        Ok(DataDir { path: path.as_ref().to_path_buf() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn file_path(&self, file_name: &str) -> String {
+        self.path.join(file_name).to_string_lossy().into_owned()
+    }
+
+    pub fn blockchain_path(&self, format: BlockFormat) -> String {
+        self.file_path(match format {
+            BlockFormat::Json => JSON_BLOCKCHAIN_FILE,
+            BlockFormat::Binary => BINARY_BLOCKCHAIN_FILE,
+        })
+    }
+
+    // Chain file of the other format, if there is one. A node configured with another storage
+    // format than the one its chain was stored with would otherwise start a new chain.
+    pub fn other_blockchain_path(&self, format: BlockFormat) -> Option<String> {
+        [BlockFormat::Json, BlockFormat::Binary]
+            .into_iter()
+            .filter(|other_format| *other_format != format)
+            .map(|other_format| self.blockchain_path(other_format))
+            .find(|path| Path::new(path).exists())
+    }
+
+    // Identity of the node, generated on the first start
+    pub fn load_or_create_keypair(&self) -> Result<identity::Keypair, Box<dyn std::error::Error>> {
+        let keypair_path = self.file_path(KEYPAIR_FILE);
+        if Path::new(&keypair_path).exists() {
+            return utils::load_keypair_from_file(&keypair_path);
+        }
+
+        let keypair = identity::Keypair::generate_ed25519();
+        utils::save_keypair_to_file(&keypair, &keypair_path)?;
+        info!("Generated a new identity in {}", keypair_path);
+
+        Ok(keypair)
+    }
+
+    pub fn load_metadata(&self) -> Option<NodeMetadata> {
+        let metadata = std::fs::read(self.file_path(METADATA_FILE)).ok()?;
+        serde_json::from_slice(&metadata).ok()
+    }
+
+    // Records a start of the node with the given identity
+    pub fn record_start(&self, peer_id: &PeerId) -> Result<NodeMetadata, Box<dyn std::error::Error>> {
+        let now = Utc::now().timestamp() as u64;
+        let metadata = match self.load_metadata() {
+            Some(metadata) if metadata.peer_id != peer_id.to_string() => {
+                // The key file was replaced, the chain still belongs to the directory
+                warn!("Identity in {} changed from {} to {}", self.path.display(), metadata.peer_id, peer_id);
+                NodeMetadata { peer_id: peer_id.to_string(), ..metadata }
+            },
+            Some(metadata) => metadata,
+            None => NodeMetadata {
+                peer_id: peer_id.to_string(),
+                created_at: now,
+                last_started_at: now,
+                version: String::new(),
+            },
+        };
+        let metadata = NodeMetadata {
+            last_started_at: now,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            ..metadata
+        };
+        std::fs::write(self.file_path(METADATA_FILE), serde_json::to_string_pretty(&metadata)?)?;
+
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_dir_keeps_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::open(dir.path().join("node")).unwrap();
+        let peer_id = PeerId::from(data_dir.load_or_create_keypair().unwrap().public());
+        let first_start = data_dir.record_start(&peer_id).unwrap();
+
+        // A restart gets the same identity and the same chain file
+        let data_dir = DataDir::open(dir.path().join("node")).unwrap();
+        let keypair = data_dir.load_or_create_keypair().unwrap();
+        assert_eq!(PeerId::from(keypair.public()), peer_id);
+        assert_eq!(data_dir.blockchain_path(BlockFormat::Json),
+            dir.path().join("node").join("blockchain.jsonl").to_string_lossy());
+        assert_eq!(data_dir.other_blockchain_path(BlockFormat::Binary), None);
+        let second_start = data_dir.record_start(&peer_id).unwrap();
+        assert_eq!(second_start.created_at, first_start.created_at);
+        assert_eq!(data_dir.load_metadata(), Some(second_start));
+    }
+}
@@ -2,27 +2,41 @@ mod blockchain;
 mod utils;
 mod network;
 mod blockchain_io;
+mod config;
+mod data_dir;
 
-use crate::network::{event::{NetworkEvent, CHAIN_INITIALIZATION_DONE}, event_handling};
+use crate::network::{event::{NetworkEvent, BINARY_BLOCK_PROPOSALS, CHAIN_INITIALIZATION_DONE}, event_handling};
+use crate::network::hashrate::{HashrateEstimate, HASHRATE_ANNOUNCEMENT_INTERVAL};
 use crate::network::behaviour::{BlockchainBehaviour, BlockchainBehaviourEvent, Topics};
-use crate::blockchain_io::{process_simple_cmd, print_cmd_options};
+use crate::blockchain_io::{process_simple_cmd, print_cmd_options, verify_receipt_file, parse_mining_cmd};
+use crate::config::{ChainConfig, Config};
+use crate::data_dir::DataDir;
 use blockchain::{
-    pow,
-    chain::{Chain, DIFFICULTY_VALUE, DEFAULT_DIFFICULTY_IN_SECONDS, DEFAULT_NUM_OF_SIDELINKS},
+    pow::{self, MiningCommand, MiningState},
+    chain::Chain,
+    difficulty::ChainParams,
+    pow_algorithm::PowAlgorithmKind,
+    target::Target,
     block::Record,
+    encoding::BlockFormat,
+    metadata::ChainMetadata,
+    store::{BlockReader, BlockStore, FileBlockStore},
+    storage,
 };
 
 use libp2p::gossipsub::Behaviour;
 use tokio::{self, sync::mpsc, io::AsyncBufReadExt};
-use std::{time::Duration};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use libp2p::core::{upgrade};
 use libp2p::futures::StreamExt;
-use libp2p::swarm::{SwarmBuilder, SwarmEvent};
-use libp2p::{identity, Transport, noise, tcp, PeerId, yamux, gossipsub, mdns};
+use libp2p::swarm::{SwarmBuilder, SwarmEvent, behaviour::toggle::Toggle};
+use libp2p::{Transport, noise, tcp, PeerId, yamux, gossipsub, mdns};
 use std::thread;
 use log::{error, info, warn};
 
-fn parse_difficulty_and_sidelinks(user_input: &str) -> (f64, usize) {
+// Values which are not given are taken from the chain section of the config
+fn parse_difficulty_and_sidelinks(user_input: &str, defaults: &ChainConfig) -> (f64, usize) {
     let mut user_input = user_input.split_whitespace();
     let difficulty_in_secs = if let Some(difficulty) = user_input.next() {
         info!("difficulty: {:?}", difficulty);
@@ -35,13 +49,13 @@ fn parse_difficulty_and_sidelinks(user_input: &str) -> (f64, usize) {
             if let Ok(difficulty) = difficulty_str.parse::<f64>() {
                 difficulty
             } else {
-                DEFAULT_DIFFICULTY_IN_SECONDS
+                defaults.block_time_secs
             }    
         } else {
-            DEFAULT_DIFFICULTY_IN_SECONDS
+            defaults.block_time_secs
         }
     } else {
-        DEFAULT_DIFFICULTY_IN_SECONDS
+        defaults.block_time_secs
     };
 
     let num_sidelinks = if let Some(sidelinks_num) = user_input.next() {
@@ -55,29 +69,274 @@ fn parse_difficulty_and_sidelinks(user_input: &str) -> (f64, usize) {
             if let Ok(sidelinks_num) = sidelinks_num_str.parse::<usize>() {
                 sidelinks_num
             } else {
-                DEFAULT_NUM_OF_SIDELINKS
+                defaults.num_sidelinks
             }    
         } else {
-            DEFAULT_NUM_OF_SIDELINKS
+            defaults.num_sidelinks
         }
     } else {
-        DEFAULT_NUM_OF_SIDELINKS
+        defaults.num_sidelinks
     };
 
     info!("difficulty: {}, num_sidelinks: {}", difficulty_in_secs, num_sidelinks);
     (difficulty_in_secs, num_sidelinks)
 }
 
+// PoW hash function of a new chain, given as pow=<name> (e.g. pow=sha3-256) in the init command
+fn parse_pow_algorithm(user_input: &str, default: PowAlgorithmKind) -> Option<PowAlgorithmKind> {
+    let name = user_input.split_whitespace().find_map(|word| word.strip_prefix("pow="));
+    match name {
+        Some(name) => PowAlgorithmKind::from_name(name),
+        None => Some(default),
+    }
+}
+
+// Initial target of a new chain in the compact encoding, given as bits=<hex> in the init command
+fn parse_initial_target(user_input: &str) -> Result<Option<Target>, std::num::ParseIntError> {
+    let bits = user_input.split_whitespace().find_map(|word| word.strip_prefix("bits="));
+    match bits {
+        Some(bits) => {
+            let bits = u32::from_str_radix(bits.trim_start_matches("0x"), 16)?;
+            Ok(Some(Target::from_compact(bits)))
+        },
+        None => Ok(None),
+    }
+}
+
+// With --bench the hashrate is measured before the node starts, otherwise it is measured in the
+// background. Either way it is announced to the other peers and used for the initial difficulty.
+fn parse_startup_benchmark(args: &[String], num_threads: usize, pow_algorithm: PowAlgorithmKind) -> Option<f64> {
+    if !args.iter().any(|arg| arg == "--bench") {
+        return None;
+    }
+
+    let hashrate = utils::find_my_hashrate(num_threads, pow_algorithm.algorithm());
+    println!("Hashrate on {} mining thread(s): {:.0} H/s", num_threads, hashrate);
+    Some(hashrate)
+}
+
+// Chain asked for by the init command. Unless the initial target is given explicitly, it is
+// derived from the network hashrate, which may have to wait for the local benchmark.
+#[derive(Debug, Clone, Copy)]
+struct InitRequest {
+    difficulty_in_secs: f64,
+    num_sidelinks: usize,
+    pow_algorithm: PowAlgorithmKind,
+}
+
+impl InitRequest {
+    // Target at which the whole network (as far as this node knows it from the hashrate
+    // announcements) mines a block in the requested time, and the hashrate it assumes
+    fn network_difficulty(&self, hashrate_estimate: &HashrateEstimate) -> (Target, u64) {
+        let hashrate = hashrate_estimate.total(self.pow_algorithm, Instant::now());
+        info!("Network hashrate: {:.0} H/s ({} peer(s) announced their hashrate)",
+            hashrate, hashrate_estimate.num_peers(Instant::now()));
+        (utils::difficulty_from_secs(self.difficulty_in_secs, hashrate), hashrate.round() as u64)
+    }
+}
+
+// Stores the new chain and lets the miner and the other peers know about it
+fn init_chain(request: &InitRequest,
+    initial_difficulty: Target,
+    initial_hashrate: Option<u64>,
+    chain_config: &ChainConfig,
+    storage: &storage::StorageWriter,
+    new_last_block_tx: &mpsc::UnboundedSender<blockchain::block::Block>,
+    swarm: &mut libp2p::Swarm<BlockchainBehaviour>,
+) {
+    // A chain may have been received from another peer while the hashrate was measured
+    if unsafe { CHAIN_INITIALIZATION_DONE } {
+        warn!("Blockchain exists. Not initializing the blockchain");
+        return;
+    }
+
+    // Difficulty should be such that number of seconds to mine a block is equal to
+    // a value given by the user or DEFAULT_DIFFICULTY_IN_SECONDS if the user did not
+    // provide any value.
+    // Since max hash value for sha256 is 2^256-1, we can calculate the difficulty
+    // number later used for comparison with hashes as
+    //     2^256-1 / (<difficulty_in_seconds>> * <hashrate of the network>)
+    let mut blockchain = Chain::new(request.num_sidelinks);
+    info!("Initial difficulty set to {}", initial_difficulty);
+    let params = ChainParams {
+        initial_difficulty,
+        // Difficulty adjustment works with whole seconds
+        target_block_time: request.difficulty_in_secs.round().max(1.0) as u64,
+        retarget_interval: chain_config.retarget_interval,
+        pow_algorithm: request.pow_algorithm,
+        initial_hashrate,
+    };
+    // Other peers would reject the genesis block
+    if let Err(e) = params.check_bounds() {
+        error!("Cannot initialize the blockchain: {}", e);
+        return;
+    }
+    blockchain.init_first_block(params);
+
+    info!("Saving the new blockchain");
+    // Fails if a chain was received from another peer in the meantime
+    if let Err(e) = storage.replace(blockchain.clone(), None) {
+        error!("Error while saving blockchain to file, cancelling the init event: {}", e);
+        return;
+    }
+
+    unsafe {
+        CHAIN_INITIALIZATION_DONE = true;
+    }
+    // Send new last block to mining thread
+    new_last_block_tx.send(blockchain.get_last_block().unwrap().clone()).unwrap();
+
+    NetworkEvent::InitUsingChain(blockchain).send(swarm);
+}
+
+// Lets the other peers know how fast this node mines, if it already knows it
+fn announce_hashrate(hashrate_estimate: &HashrateEstimate,
+    local_peer_id: &PeerId,
+    swarm: &mut libp2p::Swarm<BlockchainBehaviour>,
+) {
+    if let Some((pow_algorithm, hashrate)) = hashrate_estimate.local() {
+        NetworkEvent::HashrateAnnouncement {
+            hashrate,
+            pow_algorithm,
+            from_peer_id: local_peer_id.to_string(),
+        }.send(swarm);
+    }
+}
+
+// Config file given with --config <file>, overridden by the command-line flags
+fn load_config(args: &[String]) -> Result<Config, Box<dyn std::error::Error>> {
+    let path = args.iter()
+        .position(|arg| arg == "--config")
+        .map(|position| args.get(position + 1).ok_or("--config needs a file"))
+        .transpose()?;
+    let mut config = Config::load(path.map(String::as_str))?;
+    config.apply_args(args)?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+/*
+    Checks that the stored chain can be used by this node before anything reads it: it has
+    to belong to the configured network and match its metadata. A chain stored without
+    metadata (e.g. by an older version) gets it now.
+ */
+fn check_chain_metadata(mut store: FileBlockStore, config: &ChainConfig) -> Result<(), Box<dyn std::error::Error>> {
+    if store.is_empty() {
+        return Ok(());
+    }
+    match store.metadata() {
+        Some(metadata) => metadata.check(&store, &config.network_id)
+            .map_err(|e| format!("Cannot use the stored chain: {}. Use another --data-dir or \
+                remove the chain to start a new one.", e).into()),
+        None => {
+            let metadata = ChainMetadata::infer(&store, config.num_sidelinks, &config.network_id)
+                .ok_or("Cannot use the stored chain: its genesis block has no chain parameters")?;
+            metadata.check(&store, &config.network_id)
+                .map_err(|e| format!("Cannot use the stored chain: {}. Use another --data-dir or \
+                    remove the chain to start a new one.", e))?;
+            warn!("The stored chain has no metadata, assuming {:?}", metadata);
+            store.set_metadata(&metadata)
+        },
+    }
+}
+
+// Mining starts right away unless --no-mining is given; --mine <n> mines n blocks and stops
+fn parse_initial_mining_state(args: &[String]) -> MiningState {
+    if args.iter().any(|arg| arg == "--no-mining") {
+        return MiningState::Stopped;
+    }
+
+    let num_blocks = args.iter()
+        .position(|arg| arg == "--mine")
+        .and_then(|position| args.get(position + 1))
+        .and_then(|num_blocks| num_blocks.parse::<u64>().ok());
+    match num_blocks {
+        Some(num_blocks) => MiningState::Stopped.apply(MiningCommand::MineBlocks(num_blocks)),
+        None => MiningState::Mining { blocks_left: None },
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>>{
-    pretty_env_logger::init();
+    // Receipts are meant to be checked by auditors who do not run a node
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("verify-receipt") {
+        pretty_env_logger::init();
+        let receipt_valid = if let Some(receipt_file) = args.get(2) {
+            verify_receipt_file(receipt_file)
+        } else {
+            println!("Usage: {} verify-receipt <file>", args[0]);
+            false
+        };
+        std::process::exit(if receipt_valid { 0 } else { 1 });
+    }
+    // The logger is not set up yet, so configuration errors go straight to stderr
+    let config = match load_config(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut logger = pretty_env_logger::formatted_builder();
+    match (std::env::var("RUST_LOG"), &config.log_level) {
+        (Ok(filters), _) => logger.parse_filters(&filters),
+        (Err(_), Some(log_level)) => logger.parse_filters(log_level),
+        (Err(_), None) => &mut logger,
+    };
+    logger.init();
+    info!("Configuration: {:?}", config);
 
-    let local_key = identity::Keypair::generate_ed25519();
+    let mining_threads = config.mining.threads.unwrap_or_else(pow::default_mining_threads);
+    let default_pow_algorithm = config.chain.pow_algorithm();
+    let initial_mining_state = parse_initial_mining_state(&args);
+    // Measured before the miner starts, so that it does not compete with the benchmark
+    let startup_hashrate = parse_startup_benchmark(&args, mining_threads, default_pow_algorithm);
+
+    // The identity and the chain are kept between restarts
+    let data_dir = DataDir::open(&config.data_dir)?;
+    let local_key = data_dir.load_or_create_keypair()?;
     let local_peer_id = PeerId::from(local_key.public());
-    let blockchain_filepath = format!("./blockchain_storage_{local_peer_id}.json");
+    let storage_format = config.storage_format()?;
+    BINARY_BLOCK_PROPOSALS.store(config.wire_format()? == BlockFormat::Binary, Ordering::Relaxed);
+    let blockchain_filepath = data_dir.blockchain_path(storage_format);
+    // The recovery of a file in another format would cut off blocks it cannot read
+    match FileBlockStore::detect_format(&blockchain_filepath)? {
+        Some(format) if format != storage_format => {
+            return Err(format!("The chain in {} is stored as {}, not as {}", blockchain_filepath,
+                format.name(), storage_format.name()).into());
+        },
+        Some(_) => {},
+        None => if let Some(other_filepath) = data_dir.other_blockchain_path(storage_format) {
+            return Err(format!("The chain in {} is not stored as {}, change storage_format to use it",
+                other_filepath, storage_format.name()).into());
+        },
+    }
+    let metadata = data_dir.record_start(&local_peer_id)?;
 
     info!("Starting the node... PEER ID: {local_peer_id}");
+    info!("[PEER ID {}] data directory: {} (created at {})",
+        local_peer_id, data_dir.path().display(), metadata.created_at);
     info!("[PEER ID {}] blockchain filepath: {}", local_peer_id, blockchain_filepath);
+    // The node may have crashed while writing the chain, or the block file may have been
+    // written without its index, e.g. by an older version
+    match FileBlockStore::with_format(blockchain_filepath.clone(), storage_format).recover() {
+        Ok(report) if report.is_clean() => {}
+        Ok(report) => warn!("Repaired the blockchain file: {}", report),
+        Err(e) => error!("Cannot recover the blockchain file: {}", e),
+    }
+    check_chain_metadata(FileBlockStore::with_format(blockchain_filepath.clone(), storage_format), &config.chain)?;
+    // The storage thread is the only writer of the chain, the other components read it through
+    // the chain reader
+    let (storage, chain_reader) = storage::spawn_storage(FileBlockStore::with_format(blockchain_filepath.clone(), storage_format),
+        &config.chain.network_id);
+    if !chain_reader.is_empty() {
+        info!("Resuming the chain with {} blocks", chain_reader.len());
+        unsafe {
+            CHAIN_INITIALIZATION_DONE = true;
+        }
+    }
 
     // Set encrypted DNS-enabled TCP transport over yamux multiplexing
     let tcp_transport = tcp::tokio::Transport::default()
@@ -88,7 +347,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
 
     // Set a gossipsub configuration
     let gossipsub_config = gossipsub::ConfigBuilder::default()
-        .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
+        .heartbeat_interval(Duration::from_secs(config.network.heartbeat_secs))
         .validation_mode(gossipsub::ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message signing)
         // .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
         .build()
@@ -96,12 +355,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
 
     // Build a gossipsub network behaviour
     let mut gossipsub: Behaviour = gossipsub::Behaviour::new(
-        gossipsub::MessageAuthenticity::Signed(local_key),
+        gossipsub::MessageAuthenticity::Signed(local_key.clone()),
         gossipsub_config,
     ).expect("Correct network behaviour configuration");
 
     // Create topics and subscribe to them
-    for topic in [Topics::Block, Topics::Chain, Topics::Message, Topics::Record].iter() {
+    for topic in [Topics::Block, Topics::Chain, Topics::Hashrate, Topics::Message, Topics::Record].iter() {
         let topic = gossipsub::IdentTopic::new(topic.to_string());
         gossipsub.subscribe(&topic).expect("Subscribed to topic");
         info!("Subscribed to topic: {:?}", topic);
@@ -109,48 +368,72 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
 
     // Create a swarm to manage peers and events
     let mut swarm = {
-        let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)?;
-        let behaviour = BlockchainBehaviour { gossipsub, mdns };
+        let mdns = if config.network.mdns {
+            Some(mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)?)
+        } else {
+            info!("mDNS discovery is disabled");
+            None
+        };
+        let behaviour = BlockchainBehaviour { gossipsub, mdns: Toggle::from(mdns) };
         SwarmBuilder::with_tokio_executor(tcp_transport, behaviour, local_peer_id).build()
     };
     
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0"
-        .parse()
-        .expect("Able to get a local socket")
-    ).expect("Swarm can be started");
+    // The addresses were validated with the config
+    for address in config.listen_addresses()? {
+        if let Err(e) = swarm.listen_on(address.clone()) {
+            error!("Cannot listen on {}: {}", address, e);
+            return Err(e.into());
+        }
+    }
     info!("Listening. Network info {:?}", swarm.network_info());
+    for address in config.bootstrap_peers()? {
+        info!("[NETWORK] Dialing bootstrap peer {}", address);
+        if let Err(e) = swarm.dial(address.clone()) {
+            warn!("[NETWORK] Cannot dial bootstrap peer {}: {}", address, e);
+        }
+    }
 
     let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
     // Channels for new mined blocks
     let (new_mined_block_tx, mut new_mined_block_rx) = mpsc::unbounded_channel();
     // Channels to inform the miner about new last block of the chain
-    let (new_last_block_tx, mut new_last_block_rx) = mpsc::unbounded_channel();
+    let (new_last_block_tx, new_last_block_rx) = mpsc::unbounded_channel();
     // Channel to send new records to the minder thread so that they will be appended to the
     // block being mined
-    let (new_record_tx, mut new_record_rx) = mpsc::unbounded_channel();
-    let (difficulty_tx, mut difficulty_rx) = mpsc::unbounded_channel();
-    let (sidelinks_tx, mut sidelinks_rx) = mpsc::unbounded_channel();
+    let (new_record_tx, new_record_rx) = mpsc::unbounded_channel();
+    // Channel for the mining commands of the user
+    let (mining_control_tx, mining_control_rx) = mpsc::unbounded_channel();
+    // Channel for the results of the hashrate benchmarks running in the background
+    let (local_hashrate_tx, mut local_hashrate_rx) = mpsc::unbounded_channel();
+    // Channel for init commands which waited for a benchmark, with its result
+    let (benchmarked_init_tx, mut benchmarked_init_rx) = mpsc::unbounded_channel::<(InitRequest, f64)>();
+
+    let mut hashrate_estimate = HashrateEstimate::new();
+    let mut hashrate_announcement = tokio::time::interval(HASHRATE_ANNOUNCEMENT_INTERVAL);
+    if let Some(hashrate) = startup_hashrate {
+        hashrate_estimate.set_local(default_pow_algorithm, hashrate);
+    } else {
+        // The miner waits for a chain, so nothing competes with the benchmark
+        let local_hashrate_tx = local_hashrate_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let hashrate = utils::find_my_hashrate(mining_threads, default_pow_algorithm.algorithm());
+            local_hashrate_tx.send((default_pow_algorithm, hashrate)).ok();
+        });
+    }
 
     // Clear the screen every 10 events
     let mut event_counter = 0;
     print_cmd_options();
     
     // Dispatch the mine_blocks function
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_time()
-        .worker_threads(3) // Set the number of worker threads
-        .build()
-        .unwrap();
-
-    let fpath_copy = blockchain_filepath.clone();
-    runtime.spawn(async move {
-        pow::mine_blocks(&new_mined_block_tx,
-            &mut new_last_block_rx,
-            &mut new_record_rx,
-            &mut difficulty_rx,
-            &mut sidelinks_rx,
-            &fpath_copy).await;
-    });
+    let miner_inputs = pow::MinerInputs { new_last_block_rx, new_record_rx, mining_control_rx };
+    pow::spawn_miner(new_mined_block_tx,
+        miner_inputs,
+        initial_mining_state,
+        mining_threads,
+        local_peer_id.to_string(),
+        storage.clone(),
+        chain_reader.clone());
 
     let thread_id = thread::current().id();
     info!("[SYSTEM] Main function thread ID: {:?}", thread_id);
@@ -166,6 +449,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
                 let block_proposal = NetworkEvent::BlockProposal(mined_block);
                 block_proposal.send(&mut swarm);
             }
+            Some((pow_algorithm, hashrate)) = local_hashrate_rx.recv() => {
+                info!("[HASHRATE] Local hashrate ({:?}): {:.0} H/s", pow_algorithm, hashrate);
+                hashrate_estimate.set_local(pow_algorithm, hashrate);
+                announce_hashrate(&hashrate_estimate, &local_peer_id, &mut swarm);
+            }
+            _ = hashrate_announcement.tick() => {
+                hashrate_estimate.remove_expired(Instant::now());
+                announce_hashrate(&hashrate_estimate, &local_peer_id, &mut swarm);
+            }
+            Some((init_request, hashrate)) = benchmarked_init_rx.recv() => {
+                info!("[HASHRATE] Local hashrate ({:?}): {:.0} H/s", init_request.pow_algorithm, hashrate);
+                hashrate_estimate.set_local(init_request.pow_algorithm, hashrate);
+                announce_hashrate(&hashrate_estimate, &local_peer_id, &mut swarm);
+                let (difficulty, initial_hashrate) = init_request.network_difficulty(&hashrate_estimate);
+                init_chain(&init_request,
+                    difficulty,
+                    Some(initial_hashrate),
+                    &config.chain,
+                    &storage,
+                    &new_last_block_tx,
+                    &mut swarm);
+            }
             cmd_line = stdin.next_line() => {
                 let line = cmd_line.expect("can get line").expect("can read line from stdin");
                 info!("[NEW_USER_INPUT] {:?}", line);
@@ -173,6 +478,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
                 // the process_cmd function
                 if line.starts_with("init") {
                     // Possible command is init d=<difficulty in secs> sl=<number of sidelinks>
+                    // The block time d is expected with the hashrate of the whole network (as far
+                    // as this node knows it from the hashrate announcements)
                     info!("Init received");
                     if unsafe { CHAIN_INITIALIZATION_DONE } {
                         warn!("Blockchain exists. Not initializing the blockchain");
@@ -190,60 +497,74 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
                     //     return;
                     // }
 
-                    let hashrate: f64 = utils::find_my_hashrate() as f64;
-                    info!("My hashrate: {}", hashrate);    // Spawn the block mining task
-
-                    let (difficulty_in_secs, num_sidelinks) = parse_difficulty_and_sidelinks(&line);
-                    let difficulty = utils::difficulty_from_secs(difficulty_in_secs, hashrate);
-
-                    // Difficulty should be such that number of seconds to mine a block is equal to
-                    // a value given by the user or DEFAULT_DIFFICULTY_IN_SECONDS if the user did not
-                    // provide any value.
-                    // Since max hash value for sha256 is 2^256-1, we can calculate the difficulty
-                    // number later used for comparison with hashes as
-                    //     2^256-1 / (<difficulty_in_seconds>> * <hashrate of the network>)
-
-                    let mut blockchain = Chain::new(num_sidelinks);
-                    blockchain.init_first_block();
-                    // blockchain.add_block(block::Block::genesis());
-                    
-                    info!("Saving blockchain to file {}", blockchain_filepath);
-                    if blockchain.save_blockchain_to_file(&blockchain_filepath).is_err() {
-                        error!("Error while saving blockchain to file, cancelling the init event");
-                    }
-
-                    // TODO: user input difficulty is ignored since the code is not ready for
-                    // dynamic difficulty adjustment        
-                    // info!("Trying to send to other peers Init event with difficulty: \
-                    //     {:?}[secs] (or {:?} as u8 vector) and number of sidelinks: {:?}",
-                    //     DEFAULT_DIFFICULTY_IN_SECONDS, difficulty, num_sidelinks);
-
-                    unsafe {
-                        CHAIN_INITIALIZATION_DONE = true;
-                        DIFFICULTY_VALUE = difficulty.clone();
-                        info!("Difficulty set to {:?}", DIFFICULTY_VALUE);
+                    let (difficulty_in_secs, num_sidelinks) = parse_difficulty_and_sidelinks(&line, &config.chain);
+                    let pow_algorithm = if let Some(pow_algorithm) = parse_pow_algorithm(&line, default_pow_algorithm) {
+                        pow_algorithm
+                    } else {
+                        warn!("Unknown PoW algorithm, use one of: sha256, sha3-256");
+                        continue;
+                    };
+                    let init_request = InitRequest { difficulty_in_secs, num_sidelinks, pow_algorithm };
+                    // An explicit target (bits=<compact target>) makes the chain independent
+                    // of the hashrate measured on this machine
+                    match parse_initial_target(&line) {
+                        Ok(Some(difficulty)) => {
+                            init_chain(&init_request, difficulty, None, &config.chain, &storage,
+                                &new_last_block_tx, &mut swarm);
+                        },
+                        Ok(None) if matches!(hashrate_estimate.local(), Some((algorithm, _)) if algorithm == pow_algorithm) => {
+                            let (difficulty, initial_hashrate) = init_request.network_difficulty(&hashrate_estimate);
+                            init_chain(&init_request, difficulty, Some(initial_hashrate), &config.chain,
+                                &storage, &new_last_block_tx, &mut swarm);
+                        },
+                        Ok(None) => {
+                            // The hashrate of this node has to be measured for the algorithm first
+                            // (the background benchmark uses the default one). The benchmark blocks
+                            // its threads for a few seconds, so the chain is created once it is done.
+                            info!("Measuring the hashrate of {} before creating the chain...",
+                                pow_algorithm.algorithm().name());
+                            let benchmarked_init_tx = benchmarked_init_tx.clone();
+                            tokio::task::spawn_blocking(move || {
+                                let hashrate = utils::find_my_hashrate(mining_threads, pow_algorithm.algorithm());
+                                benchmarked_init_tx.send((init_request, hashrate)).ok();
+                            });
+                        },
+                        Err(e) => {
+                            warn!("Invalid initial target: {}", e);
+                            continue;
+                        }
                     }
-                    // Send new last block to mining thread
-                    new_last_block_tx.send(blockchain.get_last_block().unwrap().clone()).unwrap();
-                    // Send difficulty to mining thread
-                    // difficulty_tx.send(difficulty).unwrap();
-                    // Send number of sidelinks to mining thread
-                    // sidelinks_tx.send(num_sidelinks).unwrap();
-                    
-                    NetworkEvent::InitUsingChain(blockchain).send(&mut swarm);             
                 } else if line.starts_with("rec") {
                     info!("rec received");
-                    let mut user_input = line.split_whitespace();
-                    // Second word is record data
-                    let record_data = if let Some(data) = user_input.nth(1) {
+                    let mut user_input = line.split_whitespace().skip(1).peekable();
+                    // Records are signed with the node's identity unless a key file is given
+                    let user_key = if user_input.peek() == Some(&"--key") {
+                        let key_file = if let Some(key_file) = user_input.nth(1) {
+                            key_file
+                        } else {
+                            warn!("No key file provided");
+                            continue;
+                        };
+                        match utils::load_keypair_from_file(key_file) {
+                            Ok(key) => Some(key),
+                            Err(e) => {
+                                error!("Cannot load key from {}: {}", key_file, e);
+                                continue;
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    // Next word is record data
+                    let record_data = if let Some(data) = user_input.next() {
                         data.to_string()
                     } else {
                         warn!("No record data provided");
                         continue;
                     };
 
-                    let new_record = Record::new(record_data.clone(), 
-                        local_peer_id.to_string());
+                    let new_record = Record::new(record_data,
+                        user_key.as_ref().unwrap_or(&local_key));
                     let new_record_clone = new_record.clone();
                     if let Err(e) = new_record_tx.send(new_record) {
                         error!("Error sending new record to the mining thread: {}", e);
@@ -251,8 +572,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
                         info!("Sending new record with data {:?} other peers", new_record_clone);
                         NetworkEvent::NewRecord(new_record_clone).send(&mut swarm);
                     }
+                } else if line.starts_with("bench") {
+                    // Tokens of the chain's algorithm (the configured one before init) are benchmarked.
+                    // The benchmark blocks its threads for a few seconds, so it does not run on
+                    // the thread handling the network. A running miner lowers the result.
+                    let pow_algorithm = chain_reader
+                        .chain_params()
+                        .map(|params| params.pow_algorithm)
+                        .unwrap_or(default_pow_algorithm);
+                    // The result is announced to the other peers as well
+                    let local_hashrate_tx = local_hashrate_tx.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let hashrate = utils::find_my_hashrate(mining_threads, pow_algorithm.algorithm());
+                        println!("Hashrate ({}) on {} mining thread(s): {:.0} H/s",
+                            pow_algorithm.algorithm().name(), mining_threads, hashrate);
+                        local_hashrate_tx.send((pow_algorithm, hashrate)).ok();
+                    });
+                } else if line.starts_with("mine") {
+                    let command = if let Some(command) = parse_mining_cmd(&line) {
+                        command
+                    } else {
+                        warn!("Usage: mine start|stop|pause|<number of blocks>");
+                        continue;
+                    };
+                    if let Err(e) = mining_control_tx.send(command) {
+                        error!("Error sending mining command to the mining thread: {}", e);
+                    }
                 } else {
-                    process_simple_cmd(line, &mut swarm, &local_peer_id, blockchain_filepath.as_str());
+                    process_simple_cmd(line, &mut swarm, &local_peer_id, blockchain_filepath.as_str(), &chain_reader);
                 }
             }
             network_event = swarm.select_next_some() => match network_event {
@@ -277,16 +624,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>>{
                     message_id: _id,
                     message,
                 })) => {
-                    // Decerialize the message
-                    let data = String::from_utf8_lossy(&message.data).to_string();
-                    // info!("[NETWORK] Received message: {:?}", data);
-                    event_handling::handle_incoming_network_event(&data,
+                    // info!("[NETWORK] Received message: {:?}", message.data);
+                    event_handling::handle_incoming_network_event(&message.data,
                         &local_peer_id,
                         &peer_id,
+                        message.source.as_ref(),
                         &mut swarm,
                         &new_last_block_tx,
                         &new_record_tx,
-                        &blockchain_filepath);
+                        &mut hashrate_estimate,
+                        &storage,
+                        &chain_reader);
                 }
                 SwarmEvent::NewListenAddr { address, .. } => {
                     info!("[NETWORK] Local node is listening on {address}");
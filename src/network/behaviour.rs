@@ -1,4 +1,4 @@
-use libp2p::swarm::NetworkBehaviour;
+use libp2p::swarm::{NetworkBehaviour, behaviour::toggle::Toggle};
 use libp2p::{gossipsub, mdns};
 
 #[derive(Clone, Debug)]
@@ -19,5 +19,6 @@ impl ToString for Topics {
 #[derive(NetworkBehaviour)]
 pub struct BlockchainBehaviour {
     pub gossipsub: gossipsub::Behaviour,
-    pub mdns: mdns::tokio::Behaviour,
+    // Disabled with network.mdns = false (or --no-mdns)
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
 }
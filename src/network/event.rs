@@ -1,14 +1,22 @@
 use serde::{Serialize, Deserialize};
 use libp2p::gossipsub;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::blockchain::{
+    encoding,
     block::{Block, Record},
     chain::Chain,
+    pow_algorithm::PowAlgorithmKind,
 };
 use crate::BlockchainBehaviour;
 use crate::network::behaviour::Topics;
 
 pub static mut CHAIN_INITIALIZATION_DONE: bool = false;
+// Whether block proposals are sent in the binary encoding (network.wire_format), set on startup
+pub static BINARY_BLOCK_PROPOSALS: AtomicBool = AtomicBool::new(false);
+// Prefix of a block proposal in the binary encoding, followed by the encoded block. Events in
+// JSON never start with a zero byte.
+const BINARY_BLOCK_PROPOSAL_PREFIX: &[u8] = b"\0blk";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum NetworkEvent {
@@ -18,10 +26,12 @@ pub enum NetworkEvent {
     RemoteChainRequest { asked_peer_id: String },
     RemoteChainResponse { chain_from_sender: Chain, chain_receiver: String },
     NewRecord(Record),
+    // Hashes per second a peer can compute with the given algorithm, announced periodically so
+    // that every peer can estimate the hashrate of the whole network
+    HashrateAnnouncement { hashrate: f64, pow_algorithm: PowAlgorithmKind, from_peer_id: String },
     // Messages are more of a gimmick and can be exchanged between nodes along with
     // the blocks and chains. They do not impact the blockchain in any way.
     Message { message: String, from_peer_id: String },
-    StartMining,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -35,8 +45,22 @@ impl NetworkEvent {
         serde_json::to_string(&self).expect("can serialize network event")
     }
 
-    pub fn from_string(string: &str) -> NetworkEvent {
-        serde_json::from_str(&string).expect("can deserialize network event")
+    // Block proposals are sent in the configured wire format, every other event as JSON
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            NetworkEvent::BlockProposal(block) if BINARY_BLOCK_PROPOSALS.load(Ordering::Relaxed) => {
+                [BINARY_BLOCK_PROPOSAL_PREFIX, &encoding::to_bytes(block)].concat()
+            },
+            _ => serde_json::to_vec(&self).expect("can serialize network event"),
+        }
+    }
+
+    // Events of peers in either format
+    pub fn from_bytes(bytes: &[u8]) -> Result<NetworkEvent, String> {
+        match bytes.strip_prefix(BINARY_BLOCK_PROPOSAL_PREFIX) {
+            Some(block) => encoding::from_bytes(block).map(NetworkEvent::BlockProposal),
+            None => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+        }
     }
 
     #[allow(dead_code)]
@@ -48,8 +72,8 @@ impl NetworkEvent {
             NetworkEvent::RemoteChainRequest { .. } => "RemoteChainRequest".to_string(),
             NetworkEvent::RemoteChainResponse { .. } => "RemoteChainResponse".to_string(),
             NetworkEvent::NewRecord{ .. } => "NewRecord".to_string(),
+            NetworkEvent::HashrateAnnouncement { .. } => "HashrateAnnouncement".to_string(),
             NetworkEvent::Message { .. } => "Message".to_string(),
-            NetworkEvent::StartMining => "StartMining".to_string(),
         }
     }
 
@@ -79,12 +103,13 @@ impl NetworkEvent {
                     record.timestamp,
                     record.author_peer_id)
             },
+            NetworkEvent::HashrateAnnouncement { hashrate, pow_algorithm, from_peer_id } => {
+                format!("HashrateAnnouncement {{ hashrate: {:.0}, pow: {:?}, from: {} }}",
+                    hashrate, pow_algorithm, from_peer_id)
+            },
             NetworkEvent::Message { message, from_peer_id } => {
                 format!("Message {{ message: {}, from: {} }}", message, from_peer_id)
             },
-            NetworkEvent::StartMining => {
-                "StartMining".to_string()
-            },
         }
     }
 
@@ -99,6 +124,7 @@ impl NetworkEvent {
             NetworkEvent::RemoteChainRequest { .. } => Topics::Chain,
             NetworkEvent::RemoteChainResponse { .. } => Topics::Chain,
             NetworkEvent::NewRecord{ .. } => Topics::Record,
+            NetworkEvent::HashrateAnnouncement { .. } => Topics::Hashrate,
             NetworkEvent::Message { .. } => Topics::Message,
             // If mining or user io event is received, do not send it to other peers
             _ => {
@@ -111,7 +137,7 @@ impl NetworkEvent {
         // println!("WIll publish data: {:?}", serde_json::to_vec(&self).expect("can serialize message"));
         if let Err(e) = swarm.behaviour_mut().gossipsub.publish(
             gossipsub::IdentTopic::new(topic.to_string()),
-            serde_json::to_vec(&self).expect("can serialize message"))
+            self.to_bytes())
         {
             if let libp2p::gossipsub::PublishError::InsufficientPeers = e {
                 println!("No peers to share event with :(");
@@ -124,3 +150,36 @@ impl NetworkEvent {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::difficulty::ChainParams;
+    use crate::blockchain::target::Target;
+
+    #[test]
+    fn test_block_proposal_wire_formats() {
+        let block = Block::genesis(ChainParams {
+            initial_difficulty: Target::max(),
+            target_block_time: 10,
+            retarget_interval: 3,
+            pow_algorithm: Default::default(),
+            initial_hashrate: None,
+        });
+        let proposal = NetworkEvent::BlockProposal(block.clone());
+        let json = proposal.to_bytes();
+        BINARY_BLOCK_PROPOSALS.store(true, Ordering::Relaxed);
+        let binary = proposal.to_bytes();
+        BINARY_BLOCK_PROPOSALS.store(false, Ordering::Relaxed);
+
+        assert!(binary.starts_with(BINARY_BLOCK_PROPOSAL_PREFIX) && binary.len() < json.len());
+        // Peers read both formats
+        for bytes in [json, binary] {
+            match NetworkEvent::from_bytes(&bytes) {
+                Ok(NetworkEvent::BlockProposal(received)) => assert_eq!(received, block),
+                event => panic!("Unexpected event: {:?}", event),
+            }
+        }
+        assert!(NetworkEvent::from_bytes(&[BINARY_BLOCK_PROPOSAL_PREFIX, &[1, 2]].concat()).is_err());
+    }
+}
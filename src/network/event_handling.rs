@@ -1,11 +1,17 @@
 use crate::blockchain::{
-    chain::{Chain, ChainType, ChainChoice, find_longest_chain, NUM_SIDELINKS},
+    chain::{Chain, ChainType, ChainChoice, find_heaviest_chain},
     block::{Block, Record},
+    hash::BlockHash,
+    storage::{ChainReader, StorageWriter},
+    store::BlockReader,
 };
 use crate::BlockchainBehaviour;
 use super::event::{NetworkEvent, CHAIN_INITIALIZATION_DONE};
+use super::hashrate::HashrateEstimate;
 use log::{warn, info, error};
 use tokio::sync::mpsc;
+use rug::Integer;
+use std::time::Instant;
 
 #[derive(Debug, PartialEq)]
 enum ChainAndFileValidity {
@@ -14,11 +20,22 @@ enum ChainAndFileValidity {
     InvalidFile,
 }
 
-fn verify_and_save_chain(chain: &Chain, blockchain_file: &str) -> ChainAndFileValidity {
+// expected_tip is the tip of the local chain the caller compared the chain with, the chain is
+// not saved if the local chain changed in the meantime
+fn verify_and_save_chain(chain: &Chain,
+    storage: &StorageWriter,
+    expected_tip: Option<BlockHash>,
+) -> ChainAndFileValidity {
     info!("Validating the chain and writing it to the file...");
     let chain_valid = chain.validate_chain();
     let chain_saved = if chain_valid {
-        chain.save_blockchain_to_file(blockchain_file).is_ok()
+        match storage.replace(chain.clone(), expected_tip) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Cannot save the chain: {}", e);
+                false
+            }
+        }
     } else {
         false
     };
@@ -42,14 +59,24 @@ fn verify_and_save_chain(chain: &Chain, blockchain_file: &str) -> ChainAndFileVa
 
 // Function to handle received chain in cases when there is some chain already present
 fn choose_chain(remote_chain: Chain,
-    blockchain_file: &str
+    storage: &StorageWriter,
+    chain: &ChainReader,
 ) -> Option<ChainChoice> {
     // Compare the received chain with the local chain and choose the one with
-    // the highest difficulty
+    // the highest cumulative work
     let mut winner_chain_choice: Option<ChainChoice> = None;
+    // The remote chain only replaces the local chain it was compared with
+    let mut local_tip = None;
     if unsafe { CHAIN_INITIALIZATION_DONE } {
-        if let Ok(local_chain) = Chain::load_from_file(blockchain_file) {
-            winner_chain_choice = Some(find_longest_chain(&local_chain, &remote_chain));
+        // The snapshot is released before the (slow) comparison
+        let local_chain = Chain::load_from_store(&chain.snapshot());
+        if let Some(local_chain) = local_chain {
+            let chain_choice = find_heaviest_chain(&local_chain, &remote_chain);
+            info!("Compared local chain ({} blocks) with remote chain ({} blocks); \
+                remote chain has {} more work",
+                local_chain.blocks.len(), remote_chain.blocks.len(), chain_choice.work_difference);
+            local_tip = local_chain.get_last_block().map(|block| block.hash(local_chain.pow_algorithm()));
+            winner_chain_choice = Some(chain_choice);
         }
     }
 
@@ -60,35 +87,41 @@ fn choose_chain(remote_chain: Chain,
             Veryfiyng remote chain and saving it as local chain");
 
         let remote_chain_valid_and_saved = verify_and_save_chain(&remote_chain,
-            blockchain_file);
+            storage,
+            None);
 
         if remote_chain_valid_and_saved == ChainAndFileValidity::ValidChainAndFile {
+            let work_difference = remote_chain.cumulative_work();
+            info!("No local chain to compare with; remote chain has {} more work",
+                work_difference);
             winner_chain_choice = Some(ChainChoice {
                 chosen_chain_type: ChainType::Remote,
                 chosen_chain: Some(remote_chain),
+                work_difference,
             });
         } else {
             winner_chain_choice = Some(ChainChoice {
                 chosen_chain_type: ChainType::NoChain,
                 chosen_chain: None,
+                work_difference: Integer::new(),
             });
         }
-    };
-
-    if winner_chain_choice.is_some() {
-        // If remote chain won store it as local chain\
+    } else {
+        // If remote chain won store it as local chain
         let unwrapped_choice = winner_chain_choice.as_ref().unwrap();
         if let ChainType::Remote = unwrapped_choice.chosen_chain_type {
             if let Some(remote_chain) = unwrapped_choice.chosen_chain.as_ref() {
-                if remote_chain.save_blockchain_to_file(blockchain_file).is_err() {
+                if let Err(e) = storage.replace(remote_chain.clone(), local_tip) {
                     error!("Error while saving remote blockchain to file,\
-                        cancelling the init event");
+                        cancelling the init event: {}", e);
                     winner_chain_choice = Some(ChainChoice {
                         chosen_chain_type: ChainType::NoChain,
                         chosen_chain: None,
+                        work_difference: Integer::new(),
                     });
+                } else {
+                    info!("Remote chain saved to file")
                 }
-                info!("Remote chain saved to file")
             }
         }
     }
@@ -97,7 +130,6 @@ fn choose_chain(remote_chain: Chain,
 }
 
 fn handle_chain_choice_result(chosen_chain: Option<ChainChoice>,
-    local_chain_file: &str,
     new_last_block_tx: &mpsc::UnboundedSender<Block>,
     chain_received_from_peer_id: &libp2p::PeerId,
     swarm: &mut libp2p::Swarm<BlockchainBehaviour>
@@ -128,7 +160,7 @@ fn handle_chain_choice_result(chosen_chain: Option<ChainChoice>,
             // event.send(swarm);
         },
         ChainType::Local => {
-            info!("Local chain won.");
+            info!("Local chain won by {} work.", Integer::from(-&chosen_chain.work_difference));
             if let Some(local_chain) = chosen_chain.chosen_chain {
                 let event = NetworkEvent::RemoteChainResponse{
                     chain_from_sender: local_chain,
@@ -138,7 +170,7 @@ fn handle_chain_choice_result(chosen_chain: Option<ChainChoice>,
             }
         },
         ChainType::Both => {
-            info!("Chains were equal.");
+            info!("Chains were equal (work difference: {}).", chosen_chain.work_difference);
         },
         ChainType::Remote => {
             new_last_block_tx.send(chosen_chain.chosen_chain
@@ -147,23 +179,25 @@ fn handle_chain_choice_result(chosen_chain: Option<ChainChoice>,
                 .unwrap()
                 .clone()
             ).unwrap();
-            info!("Remote chain from peer {} won.",
-                chain_received_from_peer_id.to_string());
+            info!("Remote chain from peer {} won by {} work.",
+                chain_received_from_peer_id, chosen_chain.work_difference);
         },
     }
 }
 
 fn handle_remote_chain_if_local_uninitialized(remote_chain: Chain,
-    local_chain_file: &str,
+    storage: &StorageWriter,
     new_last_block_tx: &mpsc::UnboundedSender<Block>,
     received_from_peer_id: &libp2p::PeerId,
     swarm: &mut libp2p::Swarm<BlockchainBehaviour>
 ) {
     let remote_chain_save_result = verify_and_save_chain(&remote_chain,
-        local_chain_file);
+        storage,
+        None);
     match remote_chain_save_result {
         ChainAndFileValidity::ValidChainAndFile => {
-            // TODO: calculate my hashrate and new difficulty and propagate it to other peers?
+            // The hashrate of this node is already shared with the other peers by the periodic
+            // hashrate announcements, the difficulty is adjusted by the chain itself
             info!("Received remote chain from {} and saved it to file",
                 received_from_peer_id.to_string());
             unsafe {
@@ -196,32 +230,43 @@ fn handle_remote_chain_if_local_uninitialized(remote_chain: Chain,
     }
 }
 
-pub fn handle_incoming_network_event(event_data: &String,
+#[allow(clippy::too_many_arguments)]
+// received_from_peer_id is the peer which propagated the message, message_source the peer
+// which signed it (and so its author)
+pub fn handle_incoming_network_event(event_data: &[u8],
     local_peer_id: &libp2p::PeerId,
     received_from_peer_id: &libp2p::PeerId,
+    message_source: Option<&libp2p::PeerId>,
     swarm: &mut libp2p::Swarm<BlockchainBehaviour>,
     new_last_block_tx: &mpsc::UnboundedSender<Block>,
     new_record_tx: &mpsc::UnboundedSender<Record>,
-    local_chain_file: &str,
+    hashrate_estimate: &mut HashrateEstimate,
+    storage: &StorageWriter,
+    chain: &ChainReader,
 ) {
-    let event = NetworkEvent::from_string(event_data);
+    let event = match NetworkEvent::from_bytes(event_data) {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("Invalid event received from {}: {}", received_from_peer_id, e);
+            return;
+        }
+    };
     info!("Received event: {:?}", event.variant_core_data());
     match event {
         NetworkEvent::InitUsingChain(remote_chain) => {
             if unsafe { !CHAIN_INITIALIZATION_DONE } {
-                // TODO: calculate my hashrate and new difficulty and propagate it to other peers?
                 handle_remote_chain_if_local_uninitialized(remote_chain,
-                    local_chain_file,
+                    storage,
                     &new_last_block_tx,
                     received_from_peer_id,
                     swarm);
             } else {
                 let chosen_chain = choose_chain(
                     remote_chain,
-                    local_chain_file);
+                    storage,
+                    chain);
                 
                 handle_chain_choice_result(chosen_chain,
-                    &local_chain_file,
                     &new_last_block_tx,
                     received_from_peer_id,
                     swarm);
@@ -230,16 +275,17 @@ pub fn handle_incoming_network_event(event_data: &String,
         NetworkEvent::BlockProposal(block) => {
             // Validate the block, if valid add it to the chain and send to the mining task
             // since now it should use this block as the last block in the chain
-            if Chain::validate_block_using_file(&block, local_chain_file) {
+            if Chain::validate_block_in_store(&block, &chain.snapshot()) {
                 info!("Block is valid");
-                let block_copy = block.clone();
-                if let Err(e) = new_last_block_tx.send(block_copy) {
+                // The block has to be in the store before the miner learns about it, since the
+                // miner reads sidelinked blocks from the store to build the next header. The
+                // store rejects it if the miner appended its own block at the same height first.
+                if let Err(e) = storage.append(block.clone()) {
+                    error!("Error while appending block to the store: {}", e);
+                } else if let Err(e) = new_last_block_tx.send(block) {
                     error!("Error sending new base block for mining via channel, {}", e);
                 } else {
                     info!("Sent new block to be the base for mining via channel");
-                    if let Err(e) = Chain::append_block_to_file(&block, local_chain_file) {
-                        error!("Error while appending block to file: {}", e);
-                    }
                 }
             } else {
                 error!("Block validation failed, asking the peer for the whole chain.");
@@ -250,7 +296,14 @@ pub fn handle_incoming_network_event(event_data: &String,
             }
         }
         NetworkEvent::NewRecord(data) => {
-            if let Err(e) = new_record_tx.send(data) {
+            // A block with such record would be rejected by every peer, so do not mine it
+            if !data.has_valid_signature() {
+                warn!("Record {:?} from {} has an invalid signature. Ignoring it.",
+                    data.data, received_from_peer_id);
+            } else if Chain::store_contains_record(&chain.snapshot(), &data) {
+                warn!("Record {:?} from {} is already in the chain. Ignoring it.",
+                    data.data, received_from_peer_id);
+            } else if let Err(e) = new_record_tx.send(data) {
                 error!("Error sending new record for mining via channel, {}", e);
             } else {
                 info!("Sent new record for mining via channel");
@@ -260,7 +313,8 @@ pub fn handle_incoming_network_event(event_data: &String,
             if asked_peer_id == local_peer_id.to_string() {
                 info!("Sending local chain to {}", asked_peer_id);
                 // Check if chain is ok and ignore if not
-                if let Ok(local_chain) = Chain::load_from_file(local_chain_file) {
+                let local_chain = Chain::load_from_store(&chain.snapshot());
+                if let Some(local_chain) = local_chain {
                     let event = NetworkEvent::RemoteChainResponse {
                         chain_from_sender: local_chain,
                         chain_receiver: received_from_peer_id.to_string(),
@@ -277,7 +331,7 @@ pub fn handle_incoming_network_event(event_data: &String,
             if chain_receiver == local_peer_id.to_string() {
                 if unsafe { !CHAIN_INITIALIZATION_DONE } {
                     handle_remote_chain_if_local_uninitialized(remote_chain,
-                        local_chain_file,
+                        storage,
                         &new_last_block_tx,
                         received_from_peer_id,
                         swarm);
@@ -285,16 +339,31 @@ pub fn handle_incoming_network_event(event_data: &String,
                     info!("Received local chain from {}", received_from_peer_id.to_string());
                     let chosen_chain = choose_chain(
                         remote_chain,
-                        local_chain_file);
+                        storage,
+                        chain);
                     
                     handle_chain_choice_result(chosen_chain,
-                        &local_chain_file,
                     &new_last_block_tx,
                         received_from_peer_id,
                         swarm);
                 }
             }
         }
+        NetworkEvent::HashrateAnnouncement { hashrate, pow_algorithm, from_peer_id } => {
+            // The peer id in the event is only declared by the sender, the hashrate is kept
+            // under the peer which signed the message
+            let source = match message_source {
+                Some(source) if source.to_string() == from_peer_id => source.to_string(),
+                _ => {
+                    warn!("Hashrate announcement of {} was sent by {:?}. Ignoring it.",
+                        from_peer_id, message_source);
+                    return;
+                }
+            };
+            hashrate_estimate.record_announcement(&source, pow_algorithm, hashrate, Instant::now());
+            info!("Network hashrate ({:?}) estimated at {:.0} H/s",
+                pow_algorithm, hashrate_estimate.total(pow_algorithm, Instant::now()));
+        }
         NetworkEvent::Message { message, from_peer_id } => {
             info!("Received Message event: {:?} from {:?}", message, from_peer_id);
         }
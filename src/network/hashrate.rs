@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::blockchain::pow_algorithm::PowAlgorithmKind;
+
+const HASHRATE_ANNOUNCEMENT_SECS: u64 = 30;
+// How often every node announces its hashrate to the other peers
+pub const HASHRATE_ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(HASHRATE_ANNOUNCEMENT_SECS);
+// Peers which missed three announcements in a row are assumed to be gone
+const HASHRATE_ANNOUNCEMENT_TTL: Duration = Duration::from_secs(3 * HASHRATE_ANNOUNCEMENT_SECS);
+// Far above what a node mining on CPUs reaches, so that a single peer cannot make the network
+// look arbitrarily fast (and the difficulty of a new chain arbitrarily high)
+const MAX_ANNOUNCED_HASHRATE: f64 = 1e10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Announcement {
+    pow_algorithm: PowAlgorithmKind,
+    hashrate: f64,
+    received_at: Instant,
+}
+
+/*
+    Estimate of the hashrate of the whole network: the sum of the hashrate measured by this node
+    and the latest hashrates announced by the other peers.
+    Hashrates of different PoW algorithms are not comparable, so every announcement says which
+    algorithm it was measured with and only announcements of the same algorithm are summed.
+ */
+#[derive(Debug, Default)]
+pub struct HashrateEstimate {
+    local: Option<(PowAlgorithmKind, f64)>,
+    peers: HashMap<String, Announcement>,
+}
+
+impl HashrateEstimate {
+    pub fn new() -> HashrateEstimate {
+        HashrateEstimate::default()
+    }
+
+    // Hashrate of this node, None until its benchmark finishes
+    pub fn local(&self) -> Option<(PowAlgorithmKind, f64)> {
+        self.local
+    }
+
+    pub fn set_local(&mut self, pow_algorithm: PowAlgorithmKind, hashrate: f64) {
+        self.local = Some((pow_algorithm, hashrate));
+    }
+
+    // A newer announcement of a peer replaces its previous one
+    pub fn record_announcement(&mut self,
+        peer_id: &str,
+        pow_algorithm: PowAlgorithmKind,
+        hashrate: f64,
+        now: Instant,
+    ) {
+        // Announcements are not trusted, but they should at least be plausible numbers
+        if !(0.0..=MAX_ANNOUNCED_HASHRATE).contains(&hashrate) {
+            return;
+        }
+        self.peers.insert(peer_id.to_string(), Announcement {
+            pow_algorithm,
+            hashrate,
+            received_at: now,
+        });
+    }
+
+    // Number of peers with an announcement which has not expired yet
+    pub fn num_peers(&self, now: Instant) -> usize {
+        self.peers.values().filter(|announcement| is_fresh(announcement, now)).count()
+    }
+
+    // Total hashrate (hashes per second) of this node and the peers for the given algorithm,
+    // 0 if nothing is known about it
+    pub fn total(&self, pow_algorithm: PowAlgorithmKind, now: Instant) -> f64 {
+        let local = match self.local {
+            Some((algorithm, hashrate)) if algorithm == pow_algorithm => hashrate,
+            _ => 0.0,
+        };
+        let peers: f64 = self.peers.values()
+            .filter(|announcement| announcement.pow_algorithm == pow_algorithm)
+            .filter(|announcement| is_fresh(announcement, now))
+            .map(|announcement| announcement.hashrate)
+            .sum();
+
+        local + peers
+    }
+
+    // Forgets the peers which stopped announcing
+    pub fn remove_expired(&mut self, now: Instant) {
+        self.peers.retain(|_, announcement| is_fresh(announcement, now));
+    }
+}
+
+fn is_fresh(announcement: &Announcement, now: Instant) -> bool {
+    now.saturating_duration_since(announcement.received_at) < HASHRATE_ANNOUNCEMENT_TTL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_hashrate_estimate() {
+        let now = Instant::now();
+        let mut estimate = HashrateEstimate::new();
+        assert_eq!(estimate.total(PowAlgorithmKind::Sha256, now), 0.0);
+
+        estimate.set_local(PowAlgorithmKind::Sha256, 100.0);
+        estimate.record_announcement("peer1", PowAlgorithmKind::Sha256, 50.0, now);
+        // The latest announcement of a peer counts
+        estimate.record_announcement("peer1", PowAlgorithmKind::Sha256, 200.0, now);
+        estimate.record_announcement("peer2", PowAlgorithmKind::Sha3, 30.0, now);
+        estimate.record_announcement("peer3", PowAlgorithmKind::Sha256, f64::NAN, now);
+        estimate.record_announcement("peer4", PowAlgorithmKind::Sha256, MAX_ANNOUNCED_HASHRATE * 2.0, now);
+        estimate.record_announcement("peer5", PowAlgorithmKind::Sha256, -1.0, now);
+        assert_eq!(estimate.num_peers(now), 2);
+        assert_eq!(estimate.total(PowAlgorithmKind::Sha256, now), 300.0);
+        assert_eq!(estimate.total(PowAlgorithmKind::Sha3, now), 30.0);
+
+        // Peers stop counting once their announcements expire, the local hashrate does not
+        let later = now + HASHRATE_ANNOUNCEMENT_TTL;
+        assert_eq!(estimate.total(PowAlgorithmKind::Sha256, later), 100.0);
+        estimate.remove_expired(later);
+        assert_eq!(estimate.num_peers(now), 0);
+    }
+}
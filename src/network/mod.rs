@@ -1,3 +1,4 @@
 pub mod event;
 pub mod event_handling;
 pub mod behaviour;
+pub mod hashrate;
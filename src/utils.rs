@@ -1,52 +1,78 @@
-use openssl::sha::sha256;
+use libp2p::identity;
+use rug::Integer;
+use std::io::Write;
+
+use crate::blockchain::{pow, pow_algorithm::PowAlgorithm, target::Target};
 
 // Function determining the number of hashes which a machine can compute in a second.
 // This will be used to determine the difficulty of the proof of work.
-pub fn find_my_hashrate() -> usize {
-    let mut nonce: i32 = 0;
-    let mut count = 0;
-    let mut total_time = std::time::Duration::new(0, 0);
-    loop {
-        let start = std::time::Instant::now();
-        _ = sha256(&nonce.to_be_bytes());
-        let elapsed = start.elapsed();
-        
-        total_time += elapsed;
-        
-        nonce += 1;
-        count += 1;
-        if total_time.as_secs() >= 1 {
-            break;
-        }
-    }
-    count
+pub fn find_my_hashrate(num_threads: usize, algorithm: &dyn PowAlgorithm) -> f64 {
+    pow::benchmark_hashrate(num_threads, pow::HASHRATE_BENCHMARK_WINDOW, algorithm)
 }
 
-pub fn difficulty_from_secs(difficulty_in_secs: f64, hashrate: f64) -> Vec<u8> {
-    let difficulty = (2.0f64.powi(256) - 1.0) / (difficulty_in_secs * hashrate);
-    let difficulty = rug::Float::with_val(256, difficulty);
-    let difficulty = difficulty.trunc().to_integer().unwrap();
-    println!("Difficulty: {:?}", difficulty);
-    
-    let mut difficulty = difficulty.to_digits::<u8>(rug::integer::Order::MsfBe);
-    while difficulty.len() < 32 {
-        // Pad the difficulty with zeros if it is shorter that the length of the ouput
-        // of the hash function (which in this case is 256 bits since we use sha256)
-        difficulty.insert(0, 0);
-    }
+// Target at which a block takes difficulty_in_secs seconds to mine at the given hashrate.
+// Only the expected number of hashes is a float, the target itself is computed exactly.
+pub fn difficulty_from_secs(difficulty_in_secs: f64, hashrate: f64) -> Target {
+    let expected_hashes = Integer::from_f64((difficulty_in_secs * hashrate).round())
+        .unwrap_or_else(|| Integer::from(1));
+    let difficulty = Target::from_expected_hashes(&expected_hashes);
+    println!("Difficulty: {}", difficulty);
 
     difficulty
 }
 
+// Keys are stored in the protobuf encoding used by libp2p, so that the same file can hold
+// the node's identity as well as any other key of the user. Anybody who can read the key can
+// sign in the name of its owner, so only the owner may read the file, and an existing key is
+// never overwritten.
+pub fn save_keypair_to_file(keypair: &identity::Keypair, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(file_name)?.write_all(&keypair.to_protobuf_encoding()?)?;
+    Ok(())
+}
+
+pub fn load_keypair_from_file(file_name: &str) -> Result<identity::Keypair, Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(file_name)?;
+    Ok(identity::Keypair::from_protobuf_encoding(&bytes)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_find_my_hashrate() {
-        let hashrate = find_my_hashrate();
+        use crate::blockchain::pow_algorithm::Sha256Pow;
+
+        let hashrate = find_my_hashrate(2, &Sha256Pow);
         println!("My hashrate: {} hashes/s", hashrate);
-        assert!(hashrate > 0);
+        assert!(hashrate > 0.0);
+    }
+
+    #[test]
+    fn test_keypair_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("author.key");
+        let key_file = key_file.to_str().unwrap();
+        let keypair = identity::Keypair::generate_ed25519();
+        save_keypair_to_file(&keypair, key_file).unwrap();
+        assert_eq!(load_keypair_from_file(key_file).unwrap().public(), keypair.public());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(key_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // The existing key stays
+        assert!(save_keypair_to_file(&identity::Keypair::generate_ed25519(), key_file).is_err());
+        assert_eq!(load_keypair_from_file(key_file).unwrap().public(), keypair.public());
     }
 
     #[test]
@@ -68,32 +94,44 @@ mod tests {
 
     #[test]
     fn test_block_comparison() {
-        use crate::blockchain::block::Block;
-        
-        let block1 = Block::genesis();
-        let block2 = Block::genesis();
+        use crate::blockchain::{block::Block, difficulty::ChainParams};
+
+        let params = ChainParams {
+            initial_difficulty: Target::zero(),
+            target_block_time: 30,
+            retarget_interval: 10,
+            pow_algorithm: Default::default(),
+            initial_hashrate: None,
+        };
+        let block1 = Block::genesis(params.clone());
+        let block2 = Block::genesis(params);
 
         assert_eq!(block1, block2);
     }
 
     #[test]
     fn test_hash_value() {
-        use crate::blockchain::pow;
+        use crate::blockchain::{pow, pow_algorithm::Sha256Pow};
 
-        let block = crate::blockchain::block::Block::new(
+        let mut block = crate::blockchain::block::Block::new(
             1,
-            "mDgKLzrjHxk/fpBrKby9puNvbVMVunf44ns3uj3d9UY=".to_string(),
+            "98380a2f3ae31f193f7e906b29bcbda6e36f6d5315ba77f8e27b37ba3dddf546".parse().unwrap(),
             0,
             Vec::new(),
-            "6339200808718768504".to_string(),
+            "".to_string(),
             Vec::new(),
-            vec![0, 0, 0, 48, 80, 236, 231, 14, 175, 100, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            Target::from_bytes(&[0, 48, 80, 236, 231, 14, 175, 100, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
         );
+        // The token depends on the timestamp of the block, so find a matching nonce first
+        let nonce = (0..u64::MAX)
+            .find(|&nonce| block.difficulty.is_met_by(&pow::get_new_token(&block, nonce, &Sha256Pow)))
+            .unwrap();
+        block.pow = nonce.to_string();
 
-        let hash = pow::get_token_from_block(&block);
+        let hash = pow::get_token_from_block(&block, &Sha256Pow).unwrap();
 
         // Assert that hash of the block above is smaller than difficulty in it and print values of the hash and of difficulty
         println!("hash: {:?}\ndifficulty: {:?}", hash, block.difficulty);
-        assert!(hash.as_slice() < block.difficulty.as_slice());
+        assert!(block.difficulty.is_met_by(&hash));
     }
 }
\ No newline at end of file